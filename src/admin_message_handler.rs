@@ -1,31 +1,94 @@
 use crate::db;
 use crate::format;
+use crate::locale::{self, Template};
 use crate::message_handler;
 use crate::message_handler::CallbackQuery;
+use crate::payments;
 use crate::reply::*;
-use crate::types::{Configuration, Context, Event, MessageType, User};
+use crate::ticket;
+use crate::util;
+use crate::types::{
+    Configuration, Context, Event, EventSeries, EventType, MessageType, PaymentRecord,
+    PaymentStatus, PriceTier, PromoCode, RepeatRule, ReservationState, User,
+};
+use std::collections::HashSet;
 use anyhow::anyhow;
-use chrono::DateTime;
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
 use r2d2::PooledConnection;
 use r2d2_sqlite::SqliteConnectionManager;
 use teloxide::{
     types::{InlineKeyboardButton, ParseMode},
-    utils::markdown,
+    utils::{html, markdown},
 };
 
+/// Event description sent by admins.
+#[derive(Deserialize, Serialize, Clone, Default, Debug)]
+pub struct NewEvent {
+    pub id: Option<u64>,
+    pub name: String,
+    pub link: String,
+    pub start: String,
+    /// Empty if only `reminders` are used.
+    #[serde(default)]
+    pub remind: String,
+    pub max_adults: u64,
+    pub max_children: u64,
+    pub max_adults_per_reservation: u64,
+    pub max_children_per_reservation: u64,
+    pub adult_ticket_price: Option<u64>,
+    pub child_ticket_price: Option<u64>,
+    pub series: Option<u64>,
+    pub repeat: Option<Repeat>,
+    pub update_future: Option<bool>,
+    pub tz: Option<String>,
+    pub currency: Option<String>,
+    pub calendar_invite: Option<bool>,
+    /// Offsets before the start, e.g. "1d, 2h". Defaults to the configured reminders.
+    pub reminders: Option<String>,
+    /// Hours to accept a seat from the waiting list, 0 to prompt the waiting list instead.
+    pub auto_promote_hours: Option<u64>,
+    /// Prices besides the regular ones, e.g. early-bird or concession.
+    pub price_tiers: Option<Vec<NewPriceTier>>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug)]
+pub struct NewPriceTier {
+    pub name: String,
+    pub adult_ticket_price: Option<u64>,
+    pub child_ticket_price: Option<u64>,
+    /// Last moment to buy at this price, e.g. "2022-05-01 00:00".
+    pub until: Option<String>,
+    /// Seats sold at this price, no limit if omitted.
+    pub quota: Option<u64>,
+}
+
+/// Promo code sent by admins.
+#[derive(Deserialize, Serialize, Clone, Default, Debug)]
+pub struct NewPromoCode {
+    pub code: String,
+    pub percent: Option<u64>,
+    /// Amount off in minor units of the event currency.
+    pub amount: Option<u64>,
+    pub max_uses: Option<u64>,
+    /// Validity window, e.g. "2022-05-01 00:00" in the configured time zone.
+    pub from: Option<String>,
+    pub until: Option<String>,
+    /// Valid for all paid events if omitted.
+    pub event: Option<u64>,
+}
+
 #[derive(Deserialize, Serialize, Clone, Default, Debug)]
-struct NewEvent {
-    id: Option<u64>,
-    name: String,
-    link: String,
-    start: String,
-    remind: String,
-    max_adults: u64,
-    max_children: u64,
-    max_adults_per_reservation: u64,
-    max_children_per_reservation: u64,
-    adult_ticket_price: Option<u64>,
-    child_ticket_price: Option<u64>,
+pub struct Repeat {
+    rule: String,
+    until: Option<String>,
+    count: Option<u64>,
+    skip: Option<Vec<String>>,
+}
+
+pub enum SavedEvent {
+    Event(u64),
+    /// Series id and the events generated for it.
+    Series(u64, Vec<u64>),
 }
 
 /// Command line processor.
@@ -53,14 +116,17 @@ pub fn handle_message(
                 if let Ok(event_id) = pars[2].parse::<u64>() {
                     match db::get_event(conn, event_id, user.id.0) {
                         Ok(s) => {
-                            let text = format!(
-                                    "<a href=\"tg://user?id={}\">{}</a>:\nСообщение по мероприятию {} (Начало: {})\n{}",
-                                    user.id.0,
-                                    user.user_name1,
-                                    format::event_title(&s.event),
-                                    format::ts(s.event.ts),
-                                    pars[3].to_string()
-                                );
+                            let text = Template::new(
+                                "event_message",
+                                &[
+                                    ("user", user.id.0.to_string()),
+                                    ("name", user.user_name1.clone()),
+                                    ("title", format::event_title(&s.event)),
+                                    ("text", pars[3].to_string()),
+                                ],
+                            )
+                            .time("start", s.event.ts, &s.event.tz)
+                            .to_text();
 
                             if db::enqueue_message(
                                 conn,
@@ -75,7 +141,7 @@ pub fn handle_message(
                             {
                                 return Ok(ReplyMessage::new(format!(
                                     "The following message has been scheduled for sending:\n{}",
-                                    text
+                                    locale::render(&text, &user.lang)
                                 ))
                                 .into());
                             } else {
@@ -101,7 +167,7 @@ pub fn handle_message(
                 {
                     error!("Failed to add user {} to black list", user_id);
                 }
-                return show_black_list(conn, &ctx.config, 0);
+                return show_black_list(conn, &ctx.config, 0, &user.lang);
             }
         }
         "/remove_from_black_list" if pars.len() == 2 => {
@@ -109,7 +175,7 @@ pub fn handle_message(
                 if db::remove_from_black_list(conn, user_id).is_ok() == false {
                     error!("Failed to remove user {} from black list", user_id);
                 }
-                return show_black_list(conn, &ctx.config, 0);
+                return show_black_list(conn, &ctx.config, 0, &user.lang);
             }
         }
         "/delete_event" if pars.len() == 2 => {
@@ -126,6 +192,21 @@ pub fn handle_message(
                 }
             }
         }
+        "/series" => {
+            return show_series_list(conn, &user.lang);
+        }
+        "/delete_series" if pars.len() == 2 => {
+            if let Ok(series_id) = pars[1].parse::<u64>() {
+                match db::delete_series(conn, series_id, crate::util::get_unix_time()) {
+                    Ok(_) => {
+                        return Ok(ReplyMessage::new("Deleted").into());
+                    }
+                    Err(e) => {
+                        return Err(anyhow!("Failed to delete series: {}.", e));
+                    }
+                }
+            }
+        }
         "/delete_link" if pars.len() == 2 => {
             match db::delete_link(conn, pars[1]) {
                 Ok(_) => {
@@ -148,6 +229,55 @@ pub fn handle_message(
                 };
             }
         }
+        "/refund" if pars.len() >= 3 => {
+            if let (Ok(event_id), Ok(user_id)) = (pars[1].parse::<u64>(), pars[2].parse::<u64>()) {
+                let reason = pars.get(3).unwrap_or(&"");
+                match db::request_refund(conn, event_id, user_id, reason, crate::util::get_unix_time()) {
+                    Ok(0) => {
+                        return Ok(ReplyMessage::new("No paid reservations found.").into());
+                    }
+                    Ok(_) => {
+                        return show_refunds(conn);
+                    }
+                    Err(e) => {
+                        return Err(anyhow!("Failed to request refund: {}.", e));
+                    }
+                };
+            }
+        }
+        "/refunds" => {
+            return show_refunds(conn);
+        }
+        "/donations" => {
+            return show_donations(conn);
+        }
+        "/export" if pars.len() == 2 => {
+            if let Ok(event_id) = pars[1].parse::<u64>() {
+                return export_participants(conn, event_id);
+            }
+        }
+        "/report" if pars.len() == 2 => {
+            if let Ok(event_id) = pars[1].parse::<u64>() {
+                return show_payment_report(conn, event_id);
+            }
+        }
+        "/report_csv" if pars.len() == 2 => {
+            if let Ok(event_id) = pars[1].parse::<u64>() {
+                return export_payments(conn, event_id);
+            }
+        }
+        "/refund_done" if pars.len() == 2 => {
+            if let Ok(refund_id) = pars[1].parse::<u64>() {
+                match db::complete_refund(conn, refund_id, crate::util::get_unix_time()) {
+                    Ok(_) => {
+                        return show_refunds(conn);
+                    }
+                    Err(e) => {
+                        return Err(anyhow!("Failed to complete refund: {}.", e));
+                    }
+                };
+            }
+        }
         "/set_group_leader" if pars.len() == 3 => {
             if let (Ok(event_id), Ok(user_id)) = (pars[1].parse::<u64>(), pars[2].parse::<u64>()) {
                 match db::set_group_leader(conn, event_id, user_id) {
@@ -161,7 +291,23 @@ pub fn handle_message(
             }
         }
         "/show_black_list" => {
-            return show_black_list(conn, &ctx.config, 0);
+            return show_black_list(conn, &ctx.config, 0, &user.lang);
+        }
+        "/add_promo" if pars.len() >= 2 => {
+            return add_promo_code(conn, data["/add_promo".len()..].trim(), &ctx.config, &user.lang);
+        }
+        "/promo_codes" => {
+            return show_promo_codes(conn, &ctx.config, &user.lang);
+        }
+        "/delete_promo" if pars.len() == 2 => {
+            match db::delete_promo_code(conn, &pars[1].to_uppercase()) {
+                Ok(_) => {
+                    return Ok(ReplyMessage::new("Deleted").into());
+                }
+                Err(e) => {
+                    return Err(anyhow!("Failed to delete promo code: {}.", e));
+                }
+            }
         }
         "/set_event_limits" if pars.len() == 4 => {
             if let (Ok(event_id), Ok(max_adults), Ok(max_children)) = (
@@ -179,30 +325,26 @@ pub fn handle_message(
                 };
             }
         }
+        "/start" if pars.len() == 2 && pars[1].starts_with(ticket::CHECKIN_PREFIX) => {
+            return ticket::check_in(conn, user, pars[1]);
+        }
+        "/checkin" => {
+            let on = pars.get(1) != Some(&"off");
+            db::set_checkin_mode(conn, user.id.0, on)?;
+            return Ok(ReplyMessage::new(t!(&user.lang, if on { "checkin_on" } else { "checkin_off" })).into());
+        }
         "/help" => {
-            return Ok(ReplyMessage::new(markdown::escape(
-                        "Добавить мероприятие: \
-                        \n { \"name\":\"тест\", \"link\":\"https://t.me/storiesvienna/21\", \"start\":\"2022-05-29 15:00 +02:00\", \"remind\":\"2022-05-28 15:00 +02:00\", \"max_adults\":15, \"max_children\":15, \"max_adults_per_reservation\":15, \"max_children_per_reservation\":15 }\
-                        \n\n Отредактировать: добавьте \"id\":<event> в команду выше \
-                        \n\n Цены билетов: добавьте \"adult_ticket_price\":200, \"child_ticket_price\":100 в евроцентах в команду выше \
-                        \n \nПослать сообщение: \
-                        \n /send confirmed <event> текст \
-                        \n /send waiting <event> текст \
-                        \n \nЧёрный список: \
-                        \n /ban <user> \
-                        \n /show_black_list \
-                        \n \
-                        \n /delete_event <event> \
-                        \n /delete_link <url> \
-                        \n /delete_reservation <event> <user> \
-                        \n /set_group_leader <event> <user> \
-                        \n /set_event_limits <event> <max_adults> <max_children> \
-                        ")).parse_mode(ParseMode::MarkdownV2).into());
+            return Ok(ReplyMessage::new(markdown::escape(&t!(&user.lang, "admin_help")))
+                .parse_mode(ParseMode::MarkdownV2)
+                .into());
         }
         _ => {
+            if !data.starts_with('/') && ticket::is_ticket(data) && db::is_checkin_mode(conn, user.id.0)? {
+                return ticket::check_in(conn, user, data);
+            }
             if let Some(ch) = data.chars().next() {
                 if ch == '{' {
-                    return add_event(conn, data);
+                    return add_event(conn, data, ctx);
                 }
             }
             return crate::message_handler::handle_message(conn, user, data, ctx);
@@ -228,26 +370,34 @@ pub fn handle_callback(
                         Err(e) => Err(anyhow!("Failed to close event: {}.", e)),
                     }
                 }
-                ShowBlackList { offset } => show_black_list(conn, &ctx.config, offset),
+                ExportParticipants { event_id } => export_participants(conn, event_id),
+                ShowBlackList { offset } => show_black_list(conn, &ctx.config, offset, &user.lang),
                 RemoveFromBlackList { user_id } => {
                     if db::remove_from_black_list(conn, user_id).is_ok() == false {
                         error!("Failed to remove user {} from black list", user_id);
                     }
-                    show_black_list(conn, &ctx.config, 0)
+                    show_black_list(conn, &ctx.config, 0, &user.lang)
                 }
                 ConfirmRemoveFromBlackList { user_id } => {
                     if let Ok(reason) = db::get_ban_reason(conn, user_id) {
                         let keyboard: Vec<Vec<InlineKeyboardButton>> = vec![vec![
                             InlineKeyboardButton::callback(
-                                "да",
+                                t!(&user.lang, "yes"),
                                 serde_json::to_string(&RemoveFromBlackList { user_id })?,
                             ),
                             InlineKeyboardButton::callback(
-                                "нет",
+                                t!(&user.lang, "no"),
                                 serde_json::to_string(&ShowBlackList { offset: 0 })?,
                             ),
                         ]];
-                        Ok(ReplyMessage::new(format!("Причина бана: {reason}\nУдалить пользавателя <a href=\"tg://user?id={0}\">{0}</a> из чёрного списка?", user_id)).keyboard(keyboard).into())
+                        Ok(ReplyMessage::new(t!(
+                            &user.lang,
+                            "unban_prompt",
+                            reason = reason,
+                            user = user_id
+                        ))
+                        .keyboard(keyboard)
+                        .into())
                     } else {
                         Err(anyhow!("Failed to find ban reason"))
                     }
@@ -265,70 +415,506 @@ pub fn handle_callback(
 fn add_event(
     conn: &PooledConnection<SqliteConnectionManager>,
     data: &str,
+    ctx: &Context,
 ) -> anyhow::Result<Reply> {
-    match serde_json::from_str::<NewEvent>(&data) {
-        Ok(v) => {
-            match (
-                DateTime::parse_from_str(&v.start, "%Y-%m-%d %H:%M  %z"),
-                DateTime::parse_from_str(&v.remind, "%Y-%m-%d %H:%M  %z"),
-            ) {
-                (Ok(ts), Ok(remind)) => {
-                    let event = Event {
-                        id: v.id.unwrap_or(0),
-                        name: v.name,
-                        link: v.link,
-                        max_adults: v.max_adults,
-                        max_children: v.max_children,
-                        max_adults_per_reservation: v.max_adults_per_reservation,
-                        max_children_per_reservation: v.max_children_per_reservation,
-                        ts: ts.timestamp() as u64,
-                        remind: remind.timestamp() as u64,
-                        adult_ticket_price: v.adult_ticket_price.unwrap_or(0u64),
-                        child_ticket_price: v.child_ticket_price.unwrap_or(0u64),
-                    };
+    let v = serde_json::from_str::<NewEvent>(data)
+        .map_err(|e| anyhow!("Failed to parse json: {}", e))?;
+    match save_event(conn, v, &ctx.config)? {
+        SavedEvent::Event(id) => Ok(ReplyMessage::new(if id > 0 {
+            format!("Direct event link: https://t.me/{}?start={}", ctx.config.bot_name, id)
+        } else {
+            "Failed to add event.".to_string()
+        })
+        .into()),
+        SavedEvent::Series(series_id, events) => Ok(ReplyMessage::new(format!(
+            "Series {} saved. New events:{}",
+            series_id,
+            events
+                .iter()
+                .map(|id| format!("\nhttps://t.me/{}?start={}", ctx.config.bot_name, id))
+                .collect::<String>()
+        ))
+        .into()),
+    }
+}
+
+/// Validates and stores an event or a series, as sent by admins in chat or through the API.
+pub fn save_event(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    v: NewEvent,
+    config: &Configuration,
+) -> anyhow::Result<SavedEvent> {
+    let tz = v.tz.clone().unwrap_or_else(|| config.time_zone.clone());
+    if util::parse_time_zone(&tz).is_none() {
+        return Err(anyhow!("Unknown time zone {}", tz));
+    }
+    let remind = if v.remind.trim().is_empty() {
+        Some(0)
+    } else {
+        parse_time(&v.remind, &tz)
+    };
+    let (ts, remind) = match (parse_time(&v.start, &tz), remind) {
+        (Some(ts), Some(remind)) => (ts, remind),
+        _ => return Err(anyhow!("Failed to parse date")),
+    };
+    let reminders = v.reminders.as_deref().unwrap_or(&config.reminders);
+    let reminders = util::parse_reminders(reminders)
+        .ok_or_else(|| anyhow!("Wrong reminders {}", reminders))?;
+    let mut price_tiers = Vec::new();
+    for tier in v.price_tiers.unwrap_or_default() {
+        let until = match tier.until {
+            Some(until) => parse_time(&until, &tz).ok_or_else(|| anyhow!("Failed to parse date {}", until))?,
+            None => 0,
+        };
+        if tier.name.trim().is_empty() {
+            return Err(anyhow!("Price tier without a name"));
+        }
+        price_tiers.push(PriceTier {
+            name: tier.name,
+            adult_ticket_price: tier.adult_ticket_price.unwrap_or(0),
+            child_ticket_price: tier.child_ticket_price.unwrap_or(0),
+            until,
+            quota: tier.quota.unwrap_or(0),
+        });
+    }
+    let event = Event {
+        id: v.id.unwrap_or(0),
+        name: v.name,
+        link: v.link,
+        max_adults: v.max_adults,
+        max_children: v.max_children,
+        max_adults_per_reservation: v.max_adults_per_reservation,
+        max_children_per_reservation: v.max_children_per_reservation,
+        ts,
+        remind,
+        adult_ticket_price: v.adult_ticket_price.unwrap_or(0u64),
+        child_ticket_price: v.child_ticket_price.unwrap_or(0u64),
+        tz,
+        currency: v.currency.unwrap_or_else(|| config.currency.clone()),
+        calendar_invite: v.calendar_invite.unwrap_or(false),
+        reminders,
+        auto_promote_hours: v.auto_promote_hours.unwrap_or(
+            if v.adult_ticket_price.unwrap_or(0) != 0 || v.child_ticket_price.unwrap_or(0) != 0 {
+                config.paid_auto_promote_hours
+            } else {
+                config.auto_promote_hours
+            },
+        ),
+        price_tiers,
+    };
+
+    if event.adult_ticket_price != 0 && event.max_adults == 0
+        || event.child_ticket_price != 0 && event.max_children == 0
+    {
+        return Err(anyhow!("Wrong event format"));
+    }
+    if payments::currency_exponent(&event.currency).is_none() {
+        return Err(anyhow!("Unsupported currency {}", event.currency));
+    }
+    if !event.price_tiers.is_empty() && event.get_type() != EventType::Paid {
+        return Err(anyhow!("Price tiers need regular ticket prices"));
+    }
+    // Telegram takes invoice amounts as 32 bit integers.
+    for tier in 0..=event.price_tiers.len() as u64 {
+        let (adult_ticket_price, child_ticket_price) = event.prices(tier).unwrap_or((0, 0));
+        if adult_ticket_price * event.max_adults_per_reservation
+            + child_ticket_price * event.max_children_per_reservation
+            > i32::MAX as u64
+        {
+            return Err(anyhow!("Ticket prices are too high"));
+        }
+    }
+    if let Some(repeat) = v.repeat {
+        return add_series(
+            conn,
+            EventSeries {
+                id: v.series.unwrap_or(0),
+                event,
+                rule: RepeatRule::Weekly,
+                until: 0,
+                count: repeat.count.unwrap_or(0),
+                skip: Vec::new(),
+            },
+            &repeat,
+            v.update_future.unwrap_or(false),
+            config,
+        );
+    }
+    match db::add_event(conn, event) {
+        Ok(id) => Ok(SavedEvent::Event(id)),
+        Err(e) => Err(anyhow!("Failed to add event: {}.", e)),
+    }
+}
+
+/// Parses "2022-05-29 15:00" in the event time zone. An explicit offset, e.g.
+/// "2022-05-29 15:00 +02:00", takes precedence.
+fn parse_time(time: &str, tz: &str) -> Option<u64> {
+    match DateTime::parse_from_str(time, "%Y-%m-%d %H:%M  %z") {
+        Ok(t) => Some(t.timestamp() as u64),
+        Err(_) => NaiveDateTime::parse_from_str(time.trim(), "%Y-%m-%d %H:%M")
+            .ok()
+            .and_then(|t| util::to_unix_time(&t, tz)),
+    }
+}
+
+fn add_series(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    mut series: EventSeries,
+    repeat: &Repeat,
+    update_future: bool,
+    config: &Configuration,
+) -> anyhow::Result<SavedEvent> {
+    series.rule = match repeat.rule.as_str() {
+        "weekly" => RepeatRule::Weekly,
+        "biweekly" => RepeatRule::Biweekly,
+        "monthly" => RepeatRule::Monthly,
+        _ => return Err(anyhow!("Unknown repeat rule: {}", repeat.rule)),
+    };
+    if let Some(until) = &repeat.until {
+        match NaiveDate::parse_from_str(until, "%Y-%m-%d")
+            .ok()
+            .and_then(|d| d.and_hms_opt(23, 59, 59))
+            .and_then(|d| util::to_unix_time(&d, &series.event.tz))
+        {
+            Some(until) => series.until = until,
+            None => return Err(anyhow!("Failed to parse date")),
+        }
+    }
+    if let Some(skip) = &repeat.skip {
+        for d in skip {
+            match NaiveDate::parse_from_str(d, "%Y-%m-%d") {
+                Ok(d) => series.skip.push(d),
+                Err(_) => return Err(anyhow!("Failed to parse date")),
+            }
+        }
+    }
+
+    let ts = crate::util::get_unix_time();
+    match db::add_series(conn, &series, update_future, ts) {
+        Ok(series_id) => {
+            let events = db::generate_series_events(
+                conn,
+                ts,
+                ts + config.series_horizon_days * 24 * 60 * 60,
+            )?;
+            Ok(SavedEvent::Series(series_id, events))
+        }
+        Err(e) => Err(anyhow!("Failed to add series: {}.", e)),
+    }
+}
 
-                    if event.adult_ticket_price != 0 && event.max_adults == 0
-                        || event.child_ticket_price != 0 && event.max_children == 0
-                    {
-                        return Err(anyhow!("Wrong event format"));
+fn show_series_list(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    lang: &str,
+) -> anyhow::Result<Reply> {
+    match db::get_series_list(conn) {
+        Ok(list) => Ok(ReplyMessage::new(if list.is_empty() {
+            "No series.".to_string()
+        } else {
+            list.iter()
+                .map(|(s, _)| {
+                    format!(
+                        "\n{} {} {:?} {}",
+                        s.id,
+                        format::ts(s.event.ts, &s.event.tz, lang),
+                        s.rule,
+                        format::event_title(&s.event)
+                    )
+                })
+                .collect::<String>()
+        })
+        .into()),
+        Err(e) => Err(anyhow!("Failed to get series: {}", e)),
+    }
+}
+
+fn add_promo_code(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    data: &str,
+    config: &Configuration,
+    lang: &str,
+) -> anyhow::Result<Reply> {
+    let v = serde_json::from_str::<NewPromoCode>(data)
+        .map_err(|e| anyhow!("Failed to parse json: {}", e))?;
+    let code = v.code.trim().to_uppercase();
+    // The code travels in the invoice payload, which is limited to 128 bytes.
+    if code.is_empty()
+        || code.len() > 32
+        || !code.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(anyhow!("Promo codes are up to 32 letters, digits, - and _"));
+    }
+    let percent = v.percent.unwrap_or(0);
+    let amount = v.amount.unwrap_or(0);
+    if percent + amount == 0 {
+        return Err(anyhow!("Wrong discount"));
+    }
+    let parse = |time: Option<String>| match time {
+        Some(time) => parse_time(&time, &config.time_zone).ok_or_else(|| anyhow!("Failed to parse date {}", time)),
+        None => Ok(0),
+    };
+    let promo_code = PromoCode {
+        code,
+        percent,
+        amount,
+        max_uses: v.max_uses.unwrap_or(0),
+        valid_from: parse(v.from)?,
+        valid_until: parse(v.until)?,
+        event_id: v.event.unwrap_or(0),
+    };
+    if promo_code.event_id != 0 && db::get_event(conn, promo_code.event_id, 0)?.event.get_type() != EventType::Paid {
+        return Err(anyhow!("Event {} is not paid", promo_code.event_id));
+    }
+    db::add_promo_code(conn, &promo_code)?;
+    show_promo_codes(conn, config, lang)
+}
+
+fn show_promo_codes(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    config: &Configuration,
+    lang: &str,
+) -> anyhow::Result<Reply> {
+    let codes = db::get_promo_codes(conn)?;
+    Ok(ReplyMessage::new(if codes.is_empty() {
+        "No promo codes.".to_string()
+    } else {
+        "Promo codes, remove with /delete_promo <code>:".to_string()
+            + &codes
+                .iter()
+                .map(|(p, uses)| {
+                    let mut line = format!("\n{}", p.code);
+                    if p.percent > 0 {
+                        line.push_str(&format!(" -{}%", p.percent));
                     }
-                    match crate::db::add_event(conn, event) {
-                        Ok(id) => {
-                            return Ok(ReplyMessage::new(if id > 0 {
-                                format!("Direct event link: https://t.me/sign_up_for_event_bot?start={}", id)
-                            } else {
-                                format!("Failed to add event.")
-                            }).into());
-                        }
-                        Err(e) => {
-                            return Err(anyhow!("Failed to add event: {}.", e));
-                        }
+                    if p.amount > 0 {
+                        line.push_str(&format!(" -{}", p.amount));
                     }
-                }
-                _ => {
-                    return Err(anyhow!("Failed to parse date"));
-                }
-            }
+                    if p.max_uses > 0 {
+                        line.push_str(&format!(", used {} of {}", uses, p.max_uses));
+                    } else {
+                        line.push_str(&format!(", used {}", uses));
+                    }
+                    if p.valid_from > 0 {
+                        line.push_str(&format!(", from {}", format::ts(p.valid_from, &config.time_zone, lang)));
+                    }
+                    if p.valid_until > 0 {
+                        line.push_str(&format!(", until {}", format::ts(p.valid_until, &config.time_zone, lang)));
+                    }
+                    if p.event_id > 0 {
+                        line.push_str(&format!(", event {}", p.event_id));
+                    }
+                    line
+                })
+                .collect::<String>()
+    })
+    .into())
+}
+
+fn show_refunds(conn: &PooledConnection<SqliteConnectionManager>) -> anyhow::Result<Reply> {
+    match db::get_outstanding_refunds(conn, 0, 100) {
+        Ok(refunds) => Ok(ReplyMessage::new(if refunds.is_empty() {
+            "No outstanding refunds.".to_string()
+        } else {
+            "Outstanding refunds, mark processed ones with /refund_done <refund>:".to_string()
+                + &refunds
+                    .iter()
+                    .map(|r| {
+                        format!(
+                            "\n\n{}. {} (event {})\n<a href=\"tg://user?id={}\">{} {}</a> {}, {} adults, {} children\n{} paid by {}, charge {}\n{}",
+                            r.id,
+                            r.event_name,
+                            r.event_id,
+                            r.user_id,
+                            r.user_name1,
+                            r.user_name2,
+                            r.user_id,
+                            r.adults,
+                            r.children,
+                            format::amount(r.order.amount, &r.order.currency),
+                            html::escape(&r.order.name),
+                            r.order.id,
+                            html::escape(&r.reason),
+                        )
+                    })
+                    .collect::<String>()
+        })
+        .into()),
+        Err(e) => Err(anyhow!("Failed to get refunds: {}", e)),
+    }
+}
+
+/// Participant list for the door: confirmed and waiting participants with presence marks.
+fn export_participants(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    event_id: u64,
+) -> anyhow::Result<Reply> {
+    let s = db::get_event(conn, event_id, 0)?;
+    // The waiting list isn't paid for.
+    let state = |waiting_list| match s.event.get_type() {
+        EventType::Paid if waiting_list == 0 => ReservationState::PaymentCompleted,
+        _ => ReservationState::Free,
+    };
+    // Presence list holds the confirmed participants not marked present yet.
+    let absent: HashSet<u64> = db::get_presence_list(conn, event_id, 0, i64::MAX as u64)?
+        .iter()
+        .map(|p| p.user_id)
+        .collect();
+    let mut rows = vec![[
+        "name", "username", "user_id", "adults", "children", "attachment", "waiting_list",
+        "present", "confirmed",
+    ]
+    .iter()
+    .map(|h| h.to_string())
+    .collect::<Vec<String>>()];
+    for waiting_list in [0, 1] {
+        for p in db::get_participants(conn, event_id, waiting_list, 0, 0, state(waiting_list))? {
+            rows.push(vec![
+                p.user_name1,
+                p.user_name2,
+                p.user_id.to_string(),
+                p.adults.to_string(),
+                p.children.to_string(),
+                p.attachment.unwrap_or_default(),
+                waiting_list.to_string(),
+                if waiting_list == 0 {
+                    (!absent.contains(&p.user_id) as u64).to_string()
+                } else {
+                    String::new()
+                },
+                if p.reminded {
+                    (p.confirmed as u64).to_string()
+                } else {
+                    String::new()
+                },
+            ]);
+        }
+    }
+    Ok(ReplyDocument::new(
+        format!("participants-{}.csv", event_id),
+        format::csv(&rows).into_bytes(),
+    )
+    .caption(format!("{} {}", format::ts(s.event.ts, &s.event.tz, locale::DEFAULT_LANGUAGE), s.event.name))
+    .into())
+}
+
+fn show_payment_report(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    event_id: u64,
+) -> anyhow::Result<Reply> {
+    let s = db::get_event(conn, event_id, 0)?;
+    let payments = db::get_payments(conn, event_id)?;
+    let currency = &s.event.currency;
+    let mut text = format!("{}\n", format::event_title(&s.event));
+    for status in [
+        PaymentStatus::Completed,
+        PaymentStatus::Pending,
+        PaymentStatus::RefundRequested,
+        PaymentStatus::Refunded,
+    ] {
+        let records: Vec<&PaymentRecord> =
+            payments.iter().filter(|p| p.status == status).collect();
+        if records.is_empty() {
+            continue;
         }
-        Err(e) => {
-            return Err(anyhow!("Failed to parse json: {}", e));
+        text.push_str(&format!(
+            "\n<b>{}</b>: {} reservations, {} adults, {} children, {}",
+            status.as_str(),
+            records.len(),
+            records.iter().map(|p| p.adults).sum::<u64>(),
+            records.iter().map(|p| p.children).sum::<u64>(),
+            format::amount(
+                records.iter().filter_map(|p| p.order.as_ref()).map(|o| o.amount).sum(),
+                currency
+            )
+        ));
+        for o in records.iter().filter_map(|p| p.order.as_ref()) {
+            text.push_str(&format!("\n{}", o.id));
         }
     }
+    if payments.is_empty() {
+        text.push_str("\nNo payments.");
+    } else {
+        text.push_str(&format!("\n\n/report_csv {}", event_id));
+    }
+    Ok(ReplyMessage::new(text).into())
+}
+
+fn export_payments(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    event_id: u64,
+) -> anyhow::Result<Reply> {
+    let s = db::get_event(conn, event_id, 0)?;
+    let mut rows = vec![[
+        "status", "charge_id", "name", "user_id", "user_name", "username", "adults", "children",
+        "amount_minor_units", "currency", "promo_code", "discount_minor_units",
+    ]
+    .iter()
+    .map(|h| h.to_string())
+    .collect::<Vec<String>>()];
+    for p in db::get_payments(conn, event_id)? {
+        let (charge_id, name, amount, currency, promo_code, discount) = match p.order {
+            Some(o) => (o.id, o.name, o.amount.to_string(), o.currency, o.promo_code, o.discount.to_string()),
+            None => (String::new(), String::new(), String::new(), String::new(), String::new(), String::new()),
+        };
+        rows.push(vec![
+            p.status.as_str().to_string(),
+            charge_id,
+            name,
+            p.user_id.to_string(),
+            p.user_name1,
+            p.user_name2,
+            p.adults.to_string(),
+            p.children.to_string(),
+            amount,
+            if currency.is_empty() {
+                s.event.currency.clone()
+            } else {
+                currency
+            },
+            promo_code,
+            discount,
+        ]);
+    }
+    Ok(ReplyDocument::new(
+        format!("payments-{}.csv", event_id),
+        format::csv(&rows).into_bytes(),
+    )
+    .caption(s.event.name)
+    .into())
+}
+
+fn show_donations(conn: &PooledConnection<SqliteConnectionManager>) -> anyhow::Result<Reply> {
+    match db::get_donation_totals(conn) {
+        Ok(totals) => Ok(ReplyMessage::new(if totals.is_empty() {
+            "No donations.".to_string()
+        } else {
+            "Donations per month:".to_string()
+                + &totals
+                    .iter()
+                    .map(|(month, currency, count, total)| {
+                        format!("\n{} {} ({})", month, format::amount(*total, currency), count)
+                    })
+                    .collect::<String>()
+        })
+        .into()),
+        Err(e) => Err(anyhow!("Failed to get donations: {}", e)),
+    }
 }
 
 fn show_black_list(
     conn: &PooledConnection<SqliteConnectionManager>,
     config: &Configuration,
     offset: u64,
+    lang: &str,
 ) -> anyhow::Result<Reply> {
     match db::get_black_list(conn, offset, config.presence_page_size) {
         Ok(participants) => {
             Ok(
                 // header
                 ReplyMessage::new(if participants.len() != 0 || offset > 0 {
-                    "Чёрный список. Нажмите кнопку чтобы удалить из списка."
+                    t!(lang, "black_list_header")
                 } else {
-                    "Чёрный список пуст."
+                    t!(lang, "black_list_empty")
                 })
                 // list
                 .keyboard(
@@ -0,0 +1,493 @@
+//! REST/JSON admin API for dashboards.
+//!
+//! Enabled by `api_address`. Every request needs `Authorization: Bearer <token>` with one of the
+//! comma separated `api_tokens`. Errors are returned as `{"error": "..."}`.
+use crate::admin_message_handler::{self, NewEvent, SavedEvent};
+use crate::db;
+use crate::format;
+use crate::locale::Template;
+use crate::types::{Context, EventState, EventType, MessageType, Participant, ReservationState};
+use crate::util;
+use axum::{
+    async_trait,
+    extract::{extractor_middleware, Extension, FromRequest, Path, Query, RequestParts},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, put},
+    Json, Router,
+};
+use r2d2::PooledConnection;
+use r2d2_sqlite::SqliteConnectionManager;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+pub async fn serve(ctx: Arc<Context>) -> anyhow::Result<()> {
+    let address: SocketAddr = ctx.config.api_address.parse()?;
+    info!("Admin API listening on {}", address);
+    axum::Server::bind(&address)
+        .serve(router(ctx).into_make_service())
+        .await?;
+    Ok(())
+}
+
+fn router(ctx: Arc<Context>) -> Router {
+    Router::new()
+        .route("/api/events", get(list_events).post(create_event))
+        .route(
+            "/api/events/:id",
+            get(get_event).put(update_event).delete(delete_event),
+        )
+        .route("/api/events/:id/limits", put(set_limits))
+        .route("/api/events/:id/state", put(set_state))
+        .route("/api/events/:id/participants", get(get_participants))
+        .route("/api/events/:id/messages", axum::routing::post(send_message))
+        .route("/api/black_list", get(get_black_list).post(ban))
+        .route("/api/black_list/:id", axum::routing::delete(unban))
+        .layer(extractor_middleware::<Authorized>())
+        .layer(Extension(ctx))
+}
+
+struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(serde_json::json!({ "error": self.1 }))).into_response()
+    }
+}
+
+impl From<db::Error> for ApiError {
+    fn from(e: db::Error) -> Self {
+        let status = match e {
+            db::Error::NotFound(_) => StatusCode::NOT_FOUND,
+            db::Error::Invalid(_) => StatusCode::BAD_REQUEST,
+            db::Error::Sqlite(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        ApiError(status, e.to_string())
+    }
+}
+
+impl From<r2d2::Error> for ApiError {
+    fn from(e: r2d2::Error) -> Self {
+        ApiError(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    }
+}
+
+/// Rejected event descriptions and other request errors.
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast::<db::Error>() {
+            Ok(e) => e.into(),
+            Err(e) => ApiError(StatusCode::BAD_REQUEST, e.to_string()),
+        }
+    }
+}
+
+type ApiResult<T> = Result<Json<T>, ApiError>;
+
+/// Checks the bearer token.
+struct Authorized;
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for Authorized {
+    type Rejection = ApiError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let token = req
+            .headers()
+            .and_then(|h| h.get(header::AUTHORIZATION))
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .unwrap_or_default();
+        let ctx = req.extensions().and_then(|e| e.get::<Arc<Context>>());
+        match ctx {
+            Some(ctx)
+                if !token.is_empty()
+                    && ctx.config.api_tokens.split(',').any(|t| t.trim() == token) =>
+            {
+                Ok(Authorized)
+            }
+            _ => Err(ApiError(StatusCode::UNAUTHORIZED, "Unauthorized".to_string())),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct EventInfo {
+    id: u64,
+    name: String,
+    link: String,
+    start: u64,
+    tz: String,
+    currency: String,
+    max_adults: u64,
+    max_children: u64,
+    max_adults_per_reservation: u64,
+    max_children_per_reservation: u64,
+    adult_ticket_price: u64,
+    child_ticket_price: u64,
+    adults: u64,
+    children: u64,
+    open: bool,
+    calendar_invite: bool,
+}
+
+impl From<db::EventStats> for EventInfo {
+    fn from(s: db::EventStats) -> Self {
+        EventInfo {
+            id: s.event.id,
+            name: s.event.name,
+            link: s.event.link,
+            start: s.event.ts,
+            tz: s.event.tz,
+            currency: s.event.currency,
+            max_adults: s.event.max_adults,
+            max_children: s.event.max_children,
+            max_adults_per_reservation: s.event.max_adults_per_reservation,
+            max_children_per_reservation: s.event.max_children_per_reservation,
+            adult_ticket_price: s.event.adult_ticket_price,
+            child_ticket_price: s.event.child_ticket_price,
+            adults: s.adults.reserved,
+            children: s.children.reserved,
+            open: s.state == EventState::Open,
+            calendar_invite: s.event.calendar_invite,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Page {
+    offset: Option<u64>,
+    limit: Option<u64>,
+}
+
+fn conn(ctx: &Context) -> Result<PooledConnection<SqliteConnectionManager>, ApiError> {
+    Ok(ctx.pool.get()?)
+}
+
+async fn list_events(
+    Extension(ctx): Extension<Arc<Context>>,
+    Query(page): Query<Page>,
+) -> ApiResult<Vec<EventInfo>> {
+    let limit = page.limit.unwrap_or(ctx.config.event_list_page_size);
+    let events = db::get_events(&conn(&ctx)?, 0, page.offset.unwrap_or(0), limit)?;
+    Ok(Json(events.into_iter().map(EventInfo::from).collect()))
+}
+
+async fn get_event(
+    Extension(ctx): Extension<Arc<Context>>,
+    Path(id): Path<u64>,
+) -> ApiResult<EventInfo> {
+    Ok(Json(db::get_event(&conn(&ctx)?, id, 0)?.into()))
+}
+
+fn saved(res: SavedEvent) -> serde_json::Value {
+    match res {
+        SavedEvent::Event(id) => serde_json::json!({ "id": id }),
+        SavedEvent::Series(series, events) => {
+            serde_json::json!({ "series": series, "events": events })
+        }
+    }
+}
+
+async fn create_event(
+    Extension(ctx): Extension<Arc<Context>>,
+    Json(mut event): Json<NewEvent>,
+) -> Result<(StatusCode, Json<serde_json::Value>), ApiError> {
+    event.id = None;
+    let res = admin_message_handler::save_event(&conn(&ctx)?, event, &ctx.config)?;
+    Ok((StatusCode::CREATED, Json(saved(res))))
+}
+
+async fn update_event(
+    Extension(ctx): Extension<Arc<Context>>,
+    Path(id): Path<u64>,
+    Json(mut event): Json<NewEvent>,
+) -> ApiResult<serde_json::Value> {
+    let conn = conn(&ctx)?;
+    db::get_event(&conn, id, 0)?;
+    event.id = Some(id);
+    Ok(Json(saved(admin_message_handler::save_event(
+        &conn,
+        event,
+        &ctx.config,
+    )?)))
+}
+
+async fn delete_event(
+    Extension(ctx): Extension<Arc<Context>>,
+    Path(id): Path<u64>,
+) -> Result<StatusCode, ApiError> {
+    db::delete_event(
+        &conn(&ctx)?,
+        id,
+        ctx.config.automatic_blacklisting,
+        ctx.config.cancel_future_reservations_on_ban,
+        &ctx.admins,
+    )?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+struct Limits {
+    max_adults: u64,
+    max_children: u64,
+}
+
+async fn set_limits(
+    Extension(ctx): Extension<Arc<Context>>,
+    Path(id): Path<u64>,
+    Json(limits): Json<Limits>,
+) -> ApiResult<EventInfo> {
+    let conn = conn(&ctx)?;
+    db::get_event(&conn, id, 0)?;
+    db::set_event_limits(&conn, id, limits.max_adults, limits.max_children)?;
+    Ok(Json(db::get_event(&conn, id, 0)?.into()))
+}
+
+#[derive(Deserialize)]
+struct State {
+    open: bool,
+}
+
+async fn set_state(
+    Extension(ctx): Extension<Arc<Context>>,
+    Path(id): Path<u64>,
+    Json(state): Json<State>,
+) -> ApiResult<EventInfo> {
+    let conn = conn(&ctx)?;
+    db::get_event(&conn, id, 0)?;
+    db::change_event_state(&conn, id, if state.open { 0 } else { 1 })?;
+    Ok(Json(db::get_event(&conn, id, 0)?.into()))
+}
+
+#[derive(Serialize)]
+struct Participants {
+    confirmed: Vec<Participant>,
+    waiting: Vec<Participant>,
+}
+
+async fn get_participants(
+    Extension(ctx): Extension<Arc<Context>>,
+    Path(id): Path<u64>,
+) -> ApiResult<Participants> {
+    let conn = conn(&ctx)?;
+    let event_type = db::get_event(&conn, id, 0)?.event.get_type();
+    let state = || match event_type {
+        EventType::Paid => ReservationState::PaymentCompleted,
+        _ => ReservationState::Free,
+    };
+    Ok(Json(Participants {
+        confirmed: db::get_participants(&conn, id, 0, 0, 0, state())?,
+        // The waiting list isn't paid for.
+        waiting: db::get_participants(&conn, id, 1, 0, 0, ReservationState::Free)?,
+    }))
+}
+
+#[derive(Deserialize)]
+struct NewMessage {
+    /// Telegram id of the admin the recipients can reply to.
+    sender_id: u64,
+    sender_name: String,
+    waiting_list: bool,
+    text: String,
+}
+
+async fn send_message(
+    Extension(ctx): Extension<Arc<Context>>,
+    Path(id): Path<u64>,
+    Json(msg): Json<NewMessage>,
+) -> Result<StatusCode, ApiError> {
+    if !ctx.admins.contains(&msg.sender_id) {
+        return Err(ApiError(
+            StatusCode::BAD_REQUEST,
+            format!("User {} is not an admin", msg.sender_id),
+        ));
+    }
+    let conn = conn(&ctx)?;
+    let s = db::get_event(&conn, id, 0)?;
+    let text = Template::new(
+        "event_message",
+        &[
+            ("user", msg.sender_id.to_string()),
+            ("name", msg.sender_name.clone()),
+            ("title", format::event_title(&s.event)),
+            ("text", msg.text),
+        ],
+    )
+    .time("start", s.event.ts, &s.event.tz)
+    .to_text();
+    db::enqueue_message(
+        &conn,
+        id,
+        &msg.sender_name,
+        msg.waiting_list as u64,
+        MessageType::Direct,
+        &text,
+        util::get_unix_time(),
+    )?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+#[derive(Serialize)]
+struct BannedUser {
+    user_id: u64,
+    user_name1: String,
+    user_name2: String,
+}
+
+async fn get_black_list(
+    Extension(ctx): Extension<Arc<Context>>,
+    Query(page): Query<Page>,
+) -> ApiResult<Vec<BannedUser>> {
+    let limit = page.limit.unwrap_or(ctx.config.presence_page_size);
+    let list = db::get_black_list(&conn(&ctx)?, page.offset.unwrap_or(0), limit)?;
+    Ok(Json(
+        list.into_iter()
+            .map(|u| BannedUser {
+                user_id: u.id.0,
+                user_name1: u.user_name1,
+                user_name2: u.user_name2,
+            })
+            .collect(),
+    ))
+}
+
+#[derive(Deserialize)]
+struct Ban {
+    user_id: u64,
+}
+
+async fn ban(
+    Extension(ctx): Extension<Arc<Context>>,
+    Json(ban): Json<Ban>,
+) -> Result<StatusCode, ApiError> {
+    db::add_to_black_list(
+        &conn(&ctx)?,
+        ban.user_id,
+        ctx.config.cancel_future_reservations_on_ban,
+    )?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn unban(
+    Extension(ctx): Extension<Arc<Context>>,
+    Path(id): Path<u64>,
+) -> Result<StatusCode, ApiError> {
+    db::remove_from_black_list(&conn(&ctx)?, id)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Configuration;
+    use axum::{body::Body, http::Request};
+    use tower::ServiceExt;
+
+    async fn call(
+        app: &Router,
+        method: &str,
+        uri: &str,
+        token: &str,
+        body: Option<serde_json::Value>,
+    ) -> (StatusCode, serde_json::Value) {
+        let req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::AUTHORIZATION, format!("Bearer {}", token))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(match body {
+                Some(body) => Body::from(body.to_string()),
+                None => Body::empty(),
+            })
+            .unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        let status = res.status();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn test_api() {
+        let db_file = "./test7.db3";
+        let _ = std::fs::remove_file(db_file);
+        let pool = r2d2::Pool::new(SqliteConnectionManager::file(db_file)).unwrap();
+        db::create(&pool.get().unwrap()).unwrap();
+        let app = router(Arc::new(Context {
+            config: Configuration {
+                api_tokens: "token1, token2".to_string(),
+                time_zone: "Europe/Vienna".to_string(),
+                currency: "EUR".to_string(),
+                event_list_page_size: 20,
+                presence_page_size: 20,
+                ..Default::default()
+            },
+            pool,
+            admins: [1].into_iter().collect(),
+        }));
+
+        assert_eq!(call(&app, "GET", "/api/events", "", None).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(call(&app, "GET", "/api/events", "wrong", None).await.0, StatusCode::UNAUTHORIZED);
+
+        let event = serde_json::json!({
+            "name": "test", "link": "https://example.com/1", "start": "2099-05-29 15:00",
+            "remind": "2099-05-28 15:00", "max_adults": 10, "max_children": 5,
+            "max_adults_per_reservation": 2, "max_children_per_reservation": 2
+        });
+        let (status, res) = call(&app, "POST", "/api/events", "token2", Some(event.clone())).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let id = res["id"].as_u64().unwrap();
+        let (status, res) = call(&app, "GET", "/api/events", "token1", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(res[0]["name"], "test");
+        assert_eq!(res[0]["tz"], "Europe/Vienna");
+
+        // validation errors
+        let mut wrong = event.clone();
+        wrong["start"] = "tomorrow".into();
+        let (status, res) = call(&app, "POST", "/api/events", "token1", Some(wrong)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(res["error"], "Failed to parse date");
+        let uri = "/api/events/99/limits";
+        let limits = serde_json::json!({"max_adults": 1, "max_children": 1});
+        assert_eq!(call(&app, "PUT", uri, "token1", Some(limits)).await.0, StatusCode::NOT_FOUND);
+
+        let mut update = event.clone();
+        update["name"] = "renamed".into();
+        let uri = format!("/api/events/{}", id);
+        assert_eq!(call(&app, "PUT", &uri, "token1", Some(update)).await.0, StatusCode::OK);
+        let limits = serde_json::json!({"max_adults": 3, "max_children": 0});
+        let (_, res) = call(&app, "PUT", &format!("{}/limits", uri), "token1", Some(limits)).await;
+        assert_eq!((res["name"].as_str(), res["max_adults"].as_u64()), (Some("renamed"), Some(3)));
+        let state = serde_json::json!({"open": false});
+        let (_, res) = call(&app, "PUT", &format!("{}/state", uri), "token1", Some(state)).await;
+        assert_eq!(res["open"], false);
+
+        let (status, res) = call(&app, "GET", &format!("{}/participants", uri), "token1", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(res["confirmed"].as_array().map(|v| v.len()), Some(0));
+
+        let mut msg = serde_json::json!({
+            "sender_id": 2, "sender_name": "admin", "waiting_list": false, "text": "hello"
+        });
+        let messages = format!("{}/messages", uri);
+        let (status, _) = call(&app, "POST", &messages, "token1", Some(msg.clone())).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        msg["sender_id"] = 1.into();
+        assert_eq!(call(&app, "POST", &messages, "token1", Some(msg)).await.0, StatusCode::ACCEPTED);
+
+        // black list
+        let ban = serde_json::json!({"user_id": 5});
+        let status = call(&app, "POST", "/api/black_list", "token1", Some(ban)).await.0;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (_, res) = call(&app, "GET", "/api/black_list", "token1", None).await;
+        assert_eq!(res[0]["user_id"], 5);
+        let status = call(&app, "DELETE", "/api/black_list/5", "token1", None).await.0;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (_, res) = call(&app, "GET", "/api/black_list", "token1", None).await;
+        assert_eq!(res.as_array().map(|v| v.len()), Some(0));
+
+        assert_eq!(call(&app, "DELETE", &uri, "token1", None).await.0, StatusCode::NO_CONTENT);
+        assert_eq!(call(&app, "GET", &uri, "token1", None).await.0, StatusCode::NOT_FOUND);
+    }
+}
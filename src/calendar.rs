@@ -0,0 +1,196 @@
+//! iCalendar feeds of all events and of the events a user is booked on.
+//!
+//! Served on `calendar_address` as `<calendar_url>/events.ics` and `<calendar_url>/<token>.ics`.
+//! The token is a secret created on the first /calendar command of the user.
+use crate::db;
+use crate::format;
+use crate::locale;
+use crate::types::{Configuration, Context};
+use crate::util;
+use axum::{
+    extract::{Extension, Path},
+    http::{header, StatusCode},
+    response::{Headers, IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use r2d2::PooledConnection;
+use r2d2_sqlite::SqliteConnectionManager;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+const MAX_EVENTS: u64 = 1000;
+
+pub fn public_feed(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    ts: u64,
+) -> Result<String, db::Error> {
+    let events = db::get_events(conn, 0, 0, MAX_EVENTS)?;
+    Ok(format::ics(&events, locale::DEFAULT_LANGUAGE, ts))
+}
+
+/// Events with confirmed reservations of the user.
+pub fn user_feed(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    user: u64,
+    lang: &str,
+    ts: u64,
+) -> Result<String, db::Error> {
+    let events: Vec<db::EventStats> = db::get_events(conn, user, 0, MAX_EVENTS)?
+        .into_iter()
+        .filter(|s| s.adults.my_reservation + s.children.my_reservation > 0)
+        .collect();
+    Ok(format::ics(&events, lang, ts))
+}
+
+/// Personal and public feed urls.
+pub fn urls(config: &Configuration, token: &str) -> (String, String) {
+    let base = config.calendar_url.trim_end_matches('/');
+    (format!("{}/{}.ics", base, token), format!("{}/events.ics", base))
+}
+
+pub async fn serve(ctx: Arc<Context>) -> anyhow::Result<()> {
+    let address: SocketAddr = ctx.config.calendar_address.parse()?;
+    info!("Calendar feed listening on {}", address);
+    axum::Server::bind(&address)
+        .serve(router(ctx).into_make_service())
+        .await?;
+    Ok(())
+}
+
+fn router(ctx: Arc<Context>) -> Router {
+    Router::new()
+        .route("/:file", get(feed))
+        .layer(Extension(ctx))
+}
+
+async fn feed(Extension(ctx): Extension<Arc<Context>>, Path(file): Path<String>) -> Response {
+    match load_feed(&ctx, &file) {
+        Ok(Some(ics)) => (
+            Headers(vec![(header::CONTENT_TYPE, "text/calendar; charset=utf-8")]),
+            ics,
+        )
+            .into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            error!("Failed to build calendar {}: {}", file, e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+fn load_feed(ctx: &Context, file: &str) -> anyhow::Result<Option<String>> {
+    let name = match file.strip_suffix(".ics") {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+    let conn = ctx.pool.get()?;
+    let ts = util::get_unix_time();
+    if name == "events" {
+        return Ok(Some(public_feed(&conn, ts)?));
+    }
+    match db::get_calendar_user(&conn, name)? {
+        Some(user) => {
+            let lang = db::init_language(&conn, user, locale::DEFAULT_LANGUAGE)?;
+            Ok(Some(user_feed(&conn, user, &lang, ts)?))
+        }
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Event, User};
+    use axum::{body::Body, http::Request};
+    use teloxide::types::UserId;
+    use tower::ServiceExt;
+
+    async fn get(app: &Router, uri: &str) -> (StatusCode, String) {
+        let req = Request::builder().uri(uri).body(Body::empty()).unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        let status = res.status();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_calendar() {
+        let db_file = "./test8.db3";
+        let _ = std::fs::remove_file(db_file);
+        let pool = r2d2::Pool::new(SqliteConnectionManager::file(db_file)).unwrap();
+        let conn = pool.get().unwrap();
+        db::create(&conn).unwrap();
+
+        let ts = 4000000000;
+        for name in ["first, event", "second event"] {
+            db::add_event(
+                &conn,
+                Event {
+                    id: 0,
+                    name: name.to_string(),
+                    link: "https://example.com/1".to_string(),
+                    max_adults: 10,
+                    max_children: 0,
+                    max_adults_per_reservation: 2,
+                    max_children_per_reservation: 0,
+                    ts,
+                    remind: ts - 10,
+                    adult_ticket_price: 0,
+                    child_ticket_price: 0,
+                    tz: "Europe/Vienna".to_string(),
+                    currency: "EUR".to_string(),
+                    calendar_invite: false,
+                    reminders: vec![],
+                    auto_promote_hours: 0,
+                    price_tiers: vec![],
+                },
+            )
+            .unwrap();
+        }
+        let user = User {
+            id: UserId(10),
+            user_name1: "".to_string(),
+            user_name2: "".to_string(),
+            is_admin: false,
+            lang: "en".to_string(),
+        };
+        db::sign_up(&conn, 2, &user, 2, 0, 0, ts - 20, 0, 0, "").unwrap();
+        db::init_language(&conn, 10, "en").unwrap();
+        let token = db::get_calendar_token(&conn, 10, "token").unwrap();
+        assert_eq!(db::get_calendar_token(&conn, 10, "other").unwrap(), token);
+
+        let config = Configuration {
+            calendar_url: "https://example.com/calendar/".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            urls(&config, &token),
+            (
+                "https://example.com/calendar/token.ics".to_string(),
+                "https://example.com/calendar/events.ics".to_string()
+            )
+        );
+
+        let app = router(Arc::new(Context {
+            config,
+            pool: pool.clone(),
+            admins: Default::default(),
+        }));
+        let (status, ics) = get(&app, "/events.ics").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(ics.starts_with("BEGIN:VCALENDAR\r\n"));
+        assert_eq!(ics.matches("BEGIN:VEVENT").count(), 2);
+        assert!(ics.contains("SUMMARY:first\\, event\r\n"));
+        assert!(ics.contains("DTSTART:20961002T070640Z\r\n"));
+
+        let (status, ics) = get(&app, "/token.ics").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(ics.matches("BEGIN:VEVENT").count(), 1);
+        assert!(ics.contains("UID:event-2@event-manager-telegram-bot\r\n"));
+        assert!(ics.contains("DESCRIPTION:Free seats: 8 of 10\r\n"));
+
+        assert_eq!(get(&app, "/unknown.ics").await.0, StatusCode::NOT_FOUND);
+        assert_eq!(get(&app, "/token").await.0, StatusCode::NOT_FOUND);
+    }
+}
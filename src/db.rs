@@ -1,7 +1,8 @@
-use crate::types::{Event, EventState, EventType, MessageBatch, MessageType, Participant, Presence, User, OrderInfo, ReservationState, Booking};
+use crate::types::{Event, EventSeries, EventState, EventType, MessageBatch, MessageType, Participant, PaymentRecord, PriceTier, PromoCode, PaymentStatus, Presence, Recipient, Refund, RefundState, RepeatRule, User, OrderInfo, ReservationState, Booking};
+use crate::locale::{self, Template};
+use chrono::NaiveDate;
 use crate::util::{self, get_unix_time};
-use fallible_streaming_iterator::FallibleStreamingIterator;
-use rusqlite::{params, Result, Row};
+use rusqlite::{params, Result, Row, Transaction, TransactionBehavior};
 use std::collections::HashSet;
 use url::Url;
 
@@ -10,6 +11,11 @@ use r2d2_sqlite::SqliteConnectionManager;
 use anyhow::anyhow;
 use crate::format;
 
+mod error;
+mod migrations;
+pub use error::Error;
+use migrations::MIGRATIONS;
+
 #[cfg(test)]
 mod tests;
 
@@ -62,9 +68,15 @@ impl EventStats {
                 max_adults_per_reservation: row.get("max_adults_per_reservation")?,
                 max_children_per_reservation: row.get("max_children_per_reservation")?,
                 ts: row.get("ts")?,
-                remind: 0,
+                remind: row.get("remind")?,
                 adult_ticket_price: row.get::<&str, u64>("adult_ticket_price")?,
                 child_ticket_price: row.get::<&str, u64>("child_ticket_price")?,
+                tz: row.get("tz")?,
+                currency: row.get("currency")?,
+                calendar_invite: row.get("calendar_invite")?,
+                reminders: split_reminders(&row.get::<&str, String>("reminders")?),
+                auto_promote_hours: row.get("auto_promote_hours")?,
+                price_tiers: split_price_tiers(&row.get::<&str, String>("price_tiers")?),
             },
             adults: Counter::new(
                 row.get("adults"),
@@ -84,6 +96,36 @@ impl EventStats {
     }
 }
 
+/// Nestable transaction. Rolls back everything done since it was opened unless committed, so
+/// multi-statement operations don't leave orphaned rows behind when one of the statements fails.
+struct Savepoint<'a> {
+    conn: &'a rusqlite::Connection,
+    done: bool,
+}
+
+impl<'a> Savepoint<'a> {
+    fn new(conn: &'a rusqlite::Connection) -> Result<Savepoint<'a>, Error> {
+        conn.execute_batch("SAVEPOINT sp")?;
+        Ok(Savepoint { conn, done: false })
+    }
+
+    fn commit(mut self) -> Result<(), Error> {
+        self.done = true;
+        self.conn.execute_batch("RELEASE sp")?;
+        Ok(())
+    }
+}
+
+impl Drop for Savepoint<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            if let Err(e) = self.conn.execute_batch("ROLLBACK TO sp; RELEASE sp") {
+                error!("Failed to roll back: {}", e);
+            }
+        }
+    }
+}
+
 pub struct GroupMessage {
     pub sender: String,
     pub text: String,
@@ -92,21 +134,19 @@ pub struct GroupMessage {
 }
 
 
-pub fn add_event(conn: &PooledConnection<SqliteConnectionManager>, e: Event) -> Result<u64, rusqlite::Error> {
+pub fn add_event(conn: &PooledConnection<SqliteConnectionManager>, e: Event) -> Result<u64, Error> {
     let event_type = e.get_type();
     if event_type == EventType::Announcement {
         if let Err(err) = Url::parse(&e.link) {
-            // todo: fix error
-            return Err(rusqlite::Error::InvalidParameterName(
-                format!("Failed to parse url: {}. {}", e.link, err),
-            ));
+            return Err(Error::Invalid(format!("Failed to parse url: {}. {}", e.link, err)));
         }
     }
+    let sp = Savepoint::new(conn)?;
     let mut event_id = e.id;
     if e.id == 0 {
         let res = conn.execute(
-            "INSERT INTO events (name, link, max_adults, max_children, max_adults_per_reservation, max_children_per_reservation, ts, remind, adult_ticket_price, child_ticket_price) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
-            params![e.name, e.link, e.max_adults, e.max_children, e.max_adults_per_reservation, e.max_children_per_reservation, e.ts, e.remind, e.adult_ticket_price, e.child_ticket_price],
+            "INSERT INTO events (name, link, max_adults, max_children, max_adults_per_reservation, max_children_per_reservation, ts, remind, adult_ticket_price, child_ticket_price, tz, currency, calendar_invite, reminders, auto_promote_hours, price_tiers) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)",
+            params![e.name, e.link, e.max_adults, e.max_children, e.max_adults_per_reservation, e.max_children_per_reservation, e.ts, e.remind, e.adult_ticket_price, e.child_ticket_price, e.tz, e.currency, e.calendar_invite, join_reminders(&e.reminders), e.auto_promote_hours, join_price_tiers(&e.price_tiers)],
         )?;
         if res > 0 {
             let mut stmt = conn
@@ -117,30 +157,281 @@ pub fn add_event(conn: &PooledConnection<SqliteConnectionManager>, e: Event) ->
             }
         }
     } else {
+        let old = get_event(conn, e.id, 0)?.event;
+        check_price_change(conn, &old, &e)?;
         conn.execute(
-            "UPDATE events SET name = ?1, link = ?2, max_adults = ?3, max_children = ?4, max_adults_per_reservation = ?5, max_children_per_reservation = ?6, ts = ?7, remind = ?8 \
-                WHERE id = ?9",
-            params![e.name, e.link, e.max_adults, e.max_children, e.max_adults_per_reservation, e.max_children_per_reservation, e.ts, e.remind, e.id],
+            "UPDATE events SET name = ?1, link = ?2, max_adults = ?3, max_children = ?4, max_adults_per_reservation = ?5, max_children_per_reservation = ?6, ts = ?7, remind = ?8, tz = ?9, calendar_invite = ?10, reminders = ?11, auto_promote_hours = ?12, \
+                adult_ticket_price = ?13, child_ticket_price = ?14, currency = ?15, price_tiers = ?16 WHERE id = ?17",
+            params![e.name, e.link, e.max_adults, e.max_children, e.max_adults_per_reservation, e.max_children_per_reservation, e.ts, e.remind, e.tz, e.calendar_invite, join_reminders(&e.reminders), e.auto_promote_hours, e.adult_ticket_price, e.child_ticket_price, e.currency, join_price_tiers(&e.price_tiers), e.id],
         )?;
         delete_enqueued_messages(conn, e.id, MessageType::Reminder)?;
+        notify_event_change(conn, &old, &e)?;
+        reprice_holds(conn, &old, &e, get_unix_time())?;
     }
 
     if event_id != 0 && event_type != EventType::Announcement {
-        let text = format!("\nЗдравствуйте!\nНе забудьте, пожалуйста, что вы записались на\n<a href=\"{}\">{}</a>\
-            \nНачало: {}\nПожалуйста, вовремя откажитесь от мест, если ваши планы изменились.\n",
-            e.link, e.name, format::ts(e.ts), );
-        enqueue_message(conn, 
-            event_id,
-            "Bot",
-            0,
-            MessageType::Reminder,
-            &text,
-            e.remind,
-        )?;
+        let text = Template::new("reminder", &[("link", e.link.clone()), ("name", e.name.clone())])
+            .time("start", e.ts, &e.tz)
+            .to_text();
+        // Past reminders are skipped, unless none is left for a new event. Then the last one goes
+        // out right away. An edited event has had its reminders already.
+        let times = e.reminder_times();
+        let now = get_unix_time();
+        let mut due: Vec<u64> = times.iter().copied().filter(|t| *t > now).collect();
+        if due.is_empty() && e.id == 0 {
+            due.extend(times.last());
+        }
+        for remind in due {
+            enqueue_message(conn, 
+                event_id,
+                "Bot",
+                0,
+                MessageType::Reminder,
+                &text,
+                remind,
+            )?;
+        }
     }
+    sp.commit()?;
     Ok(event_id)
 }
 
+/// Free events can't become paid ones or the other way round once somebody has signed up, and
+/// the currency stays once something has been paid.
+fn check_price_change(conn: &PooledConnection<SqliteConnectionManager>, old: &Event, e: &Event) -> Result<(), Error> {
+    let is_paid = |e: &Event| e.get_type() == EventType::Paid;
+    if is_paid(old) != is_paid(e) {
+        let reservations: u64 =
+            conn.query_row("SELECT count(*) FROM reservations WHERE event = ?1", [e.id], |row| row.get(0))?;
+        if reservations > 0 {
+            return Err(Error::Invalid("Ticket prices can't be added or removed once there are reservations".to_string()));
+        }
+    }
+    if old.currency != e.currency && !get_payments(conn, e.id)?.is_empty() {
+        return Err(Error::Invalid("The currency can't be changed once there are payments".to_string()));
+    }
+    Ok(())
+}
+
+/// Releases the unpaid seats whose price has changed, since their invoices can't be paid any
+/// more, and tells their users. Seats offered from the waiting list stay offered at the new price.
+fn reprice_holds(conn: &PooledConnection<SqliteConnectionManager>, old: &Event, e: &Event, ts: u64) -> Result<(), Error> {
+    let mut stmt = conn.prepare("SELECT id, user, tier, accept_until FROM reservations WHERE event = ?1 AND state = ?2")?;
+    let holds = stmt
+        .query_map(params![e.id, ReservationState::PaymentPending as u64], |row| {
+            Ok((row.get("id")?, row.get("user")?, row.get("tier")?, row.get("accept_until")?))
+        })?
+        .collect::<Result<Vec<(u64, u64, u64, u64)>, rusqlite::Error>>()?;
+    let state_changed = !have_vacancies(conn, e.id)?;
+    let mut released = Vec::new();
+    for (id, user, tier, accept_until) in holds {
+        if old.prices(tier) == e.prices(tier) {
+            continue;
+        }
+        if accept_until != 0 {
+            offer_seats(conn, e, id, user, ts)?;
+        } else {
+            conn.execute("DELETE FROM reservations WHERE id = ?1", [id])?;
+            if !released.contains(&user) {
+                released.push(user);
+            }
+        }
+    }
+    if released.is_empty() {
+        return Ok(());
+    }
+    if state_changed {
+        prompt_waiting_list(conn, e.id)?;
+    }
+    let text = Template::new("hold_repriced", &[("name", e.name.clone())])
+        .time("start", e.ts, &e.tz)
+        .to_text();
+    for user in released {
+        enqueue_notice(conn, e.id, user, MessageType::Release, &text, "", ts)?;
+    }
+    Ok(())
+}
+
+fn join_reminders(reminders: &[u64]) -> String {
+    reminders.iter().map(|r| r.to_string()).collect::<Vec<String>>().join(",")
+}
+
+fn split_reminders(reminders: &str) -> Vec<u64> {
+    reminders.split(',').filter_map(|r| r.parse().ok()).collect()
+}
+
+fn join_price_tiers(price_tiers: &[PriceTier]) -> String {
+    if price_tiers.is_empty() {
+        String::new()
+    } else {
+        serde_json::to_string(price_tiers).unwrap_or_default()
+    }
+}
+
+fn split_price_tiers(price_tiers: &str) -> Vec<PriceTier> {
+    serde_json::from_str(price_tiers).unwrap_or_default()
+}
+
+pub fn add_series(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    series: &EventSeries,
+    update_future: bool,
+    ts: u64,
+) -> Result<u64, Error> {
+    let e = &series.event;
+    let skip = series
+        .skip
+        .iter()
+        .map(|d| d.format("%Y-%m-%d").to_string())
+        .collect::<Vec<String>>()
+        .join(",");
+    let sp = Savepoint::new(conn)?;
+    let series_id = if series.id == 0 {
+        conn.execute(
+            "INSERT INTO event_series (name, link, max_adults, max_children, max_adults_per_reservation, max_children_per_reservation, ts, remind, adult_ticket_price, child_ticket_price, rule, until, count, skip, tz, currency, calendar_invite, reminders, auto_promote_hours, price_tiers) \
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20)",
+            params![e.name, e.link, e.max_adults, e.max_children, e.max_adults_per_reservation, e.max_children_per_reservation, e.ts, e.remind, e.adult_ticket_price, e.child_ticket_price, series.rule as u64, series.until, series.count, skip, e.tz, e.currency, e.calendar_invite, join_reminders(&e.reminders), e.auto_promote_hours, join_price_tiers(&e.price_tiers)],
+        )?;
+        conn.last_insert_rowid() as u64
+    } else {
+        let res = conn.execute(
+            "UPDATE event_series SET name = ?1, link = ?2, max_adults = ?3, max_children = ?4, max_adults_per_reservation = ?5, max_children_per_reservation = ?6, ts = ?7, remind = ?8, \
+                adult_ticket_price = ?9, child_ticket_price = ?10, rule = ?11, until = ?12, count = ?13, skip = ?14, tz = ?15, currency = ?16, calendar_invite = ?17, reminders = ?18, auto_promote_hours = ?19, price_tiers = ?20 WHERE id = ?21",
+            params![e.name, e.link, e.max_adults, e.max_children, e.max_adults_per_reservation, e.max_children_per_reservation, e.ts, e.remind, e.adult_ticket_price, e.child_ticket_price, series.rule as u64, series.until, series.count, skip, e.tz, e.currency, e.calendar_invite, join_reminders(&e.reminders), e.auto_promote_hours, join_price_tiers(&e.price_tiers), series.id],
+        )?;
+        if res == 0 {
+            return Err(Error::NotFound(format!("series {}", series.id)));
+        }
+        if update_future {
+            // Future occurrences without reservations are regenerated from the new template.
+            delete_unbooked_occurrences(conn, series.id, ts)?;
+            conn.execute(
+                "UPDATE event_series SET generated_until = ?1 WHERE id = ?2",
+                params![ts, series.id],
+            )?;
+        }
+        series.id
+    };
+    sp.commit()?;
+    Ok(series_id)
+}
+
+pub fn get_series_list(conn: &PooledConnection<SqliteConnectionManager>) -> Result<Vec<(EventSeries, u64)>, Error> {
+    let mut stmt = conn.prepare("SELECT * FROM event_series ORDER BY id")?;
+    let mut rows = stmt.query([])?;
+    let mut res = Vec::new();
+    while let Some(row) = rows.next()? {
+        let rule: u64 = row.get("rule")?;
+        let skip: String = row.get("skip")?;
+        res.push((
+            EventSeries {
+                id: row.get("id")?,
+                event: Event {
+                    id: 0,
+                    name: row.get("name")?,
+                    link: row.get("link")?,
+                    max_adults: row.get("max_adults")?,
+                    max_children: row.get("max_children")?,
+                    max_adults_per_reservation: row.get("max_adults_per_reservation")?,
+                    max_children_per_reservation: row.get("max_children_per_reservation")?,
+                    ts: row.get("ts")?,
+                    remind: row.get("remind")?,
+                    adult_ticket_price: row.get("adult_ticket_price")?,
+                    child_ticket_price: row.get("child_ticket_price")?,
+                    tz: row.get("tz")?,
+                    currency: row.get("currency")?,
+                    calendar_invite: row.get("calendar_invite")?,
+                    reminders: split_reminders(&row.get::<&str, String>("reminders")?),
+                    auto_promote_hours: row.get("auto_promote_hours")?,
+                    price_tiers: split_price_tiers(&row.get::<&str, String>("price_tiers")?),
+                },
+                rule: match rule {
+                    1 => RepeatRule::Biweekly,
+                    2 => RepeatRule::Monthly,
+                    _ => RepeatRule::Weekly,
+                },
+                until: row.get("until")?,
+                count: row.get("count")?,
+                skip: skip
+                    .split(',')
+                    .filter_map(|d| NaiveDate::parse_from_str(d, "%Y-%m-%d").ok())
+                    .collect(),
+            },
+            row.get("generated_until")?,
+        ));
+    }
+    Ok(res)
+}
+
+/// Materialises occurrences of all series up to the horizon. Returns ids of the new events.
+pub fn generate_series_events(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    ts: u64,
+    horizon: u64,
+) -> Result<Vec<u64>, Error> {
+    let sp = Savepoint::new(conn)?;
+    let mut res = Vec::new();
+    for (series, generated_until) in get_series_list(conn)? {
+        if generated_until >= horizon {
+            continue;
+        }
+        for start in series.occurrences(std::cmp::max(ts, generated_until), horizon) {
+            let mut stmt = conn.prepare("SELECT id FROM events WHERE series = ?1 AND ts = ?2")?;
+            let mut rows = stmt.query(params![series.id, start])?;
+            if rows.next()?.is_some() {
+                continue; // kept from before the series was edited
+            }
+            let mut event = series.event.clone();
+            event.ts = start;
+            if series.event.remind != 0 {
+                event.remind = start.saturating_sub(series.event.ts.saturating_sub(series.event.remind));
+            }
+            for tier in event.price_tiers.iter_mut().filter(|t| t.until != 0) {
+                tier.until = start.saturating_sub(series.event.ts.saturating_sub(tier.until));
+            }
+            let event_id = add_event(conn, event)?;
+            if event_id != 0 {
+                conn.execute(
+                    "UPDATE events SET series = ?1 WHERE id = ?2",
+                    params![series.id, event_id],
+                )?;
+                res.push(event_id);
+            }
+        }
+        conn.execute(
+            "UPDATE event_series SET generated_until = ?1 WHERE id = ?2",
+            params![horizon, series.id],
+        )?;
+    }
+    sp.commit()?;
+    Ok(res)
+}
+
+/// Stops a series. Booked occurrences are kept.
+pub fn delete_series(conn: &PooledConnection<SqliteConnectionManager>, series_id: u64, ts: u64) -> Result<(), Error> {
+    let sp = Savepoint::new(conn)?;
+    delete_unbooked_occurrences(conn, series_id, ts)?;
+    conn.execute("DELETE FROM event_series WHERE id = ?1", params![series_id])?;
+    sp.commit()
+}
+
+fn delete_unbooked_occurrences(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    series_id: u64,
+    ts: u64,
+) -> Result<(), Error> {
+    let mut stmt = conn.prepare(
+        "SELECT id FROM events WHERE series = ?1 AND ts > ?2 AND NOT EXISTS (SELECT 1 FROM reservations WHERE event = events.id)",
+    )?;
+    let events = stmt
+        .query_map(params![series_id, ts], |row| row.get::<usize, u64>(0))?
+        .collect::<Result<Vec<u64>, rusqlite::Error>>()?;
+    for event_id in events {
+        delete_event(conn, event_id, false, false, &HashSet::new())?;
+    }
+    Ok(())
+}
+
 pub fn enqueue_message(
     conn: &PooledConnection<SqliteConnectionManager>,
     event_id: u64,
@@ -149,20 +440,72 @@ pub fn enqueue_message(
     message_type: MessageType,
     text: &str,
     send_at: u64,
-) -> Result<(), rusqlite::Error> {
+) -> Result<(), Error> {
     debug!("enqueue message {} {}", util::get_unix_time(), send_at);
+    let sp = Savepoint::new(conn)?;
     conn.execute(
         "INSERT INTO messages (event, type, sender, waiting_list, text, ts) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
         params![event_id, message_type as u64, sender, waiting_list, text, util::get_unix_time()],
     )?;
-    let mut stmt = conn.prepare("SELECT last_insert_rowid()")?;
-    let mut rows = stmt.query([])?;
-    if let Some(row) = rows.next()? {
-        let message_id: u64 = row.get(0)?;
-        conn.execute(
-            "INSERT INTO message_outbox (message, send_at) VALUES (?1, ?2)",
-            params![message_id, send_at],
-        )?;
+    conn.execute(
+        "INSERT INTO message_outbox (message, send_at) VALUES (?1, ?2)",
+        params![conn.last_insert_rowid(), send_at],
+    )?;
+    sp.commit()
+}
+
+/// Enqueues a bot notice for confirmed participants or, if `recipient` is set, for that user only.
+/// A non-empty `document` is sent as an .ics file with the text as caption.
+pub fn enqueue_notice(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    event_id: u64,
+    recipient: u64,
+    message_type: MessageType,
+    text: &str,
+    document: &str,
+    send_at: u64,
+) -> Result<(), Error> {
+    let sp = Savepoint::new(conn)?;
+    conn.execute(
+        "INSERT INTO messages (event, type, sender, waiting_list, text, ts, recipient, document) VALUES (?1, ?2, ?3, 0, ?4, ?5, ?6, ?7)",
+        params![event_id, message_type as u64, "Bot", text, util::get_unix_time(), recipient, document],
+    )?;
+    conn.execute(
+        "INSERT INTO message_outbox (message, send_at) VALUES (?1, ?2)",
+        params![conn.last_insert_rowid(), send_at],
+    )?;
+    sp.commit()
+}
+
+/// Tells participants of both lists about a new time, name or link. Those with a calendar invite
+/// get the new version of it.
+fn notify_event_change(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    old: &Event,
+    e: &Event,
+) -> Result<(), Error> {
+    let template = if old.ts != e.ts {
+        Template::new("event_moved", &[("link", e.link.clone()), ("name", e.name.clone())])
+            .time("old_start", old.ts, &old.tz)
+    } else if old.name != e.name || old.link != e.link {
+        Template::new(
+            "event_changed",
+            &[("link", e.link.clone()), ("name", e.name.clone()), ("old_name", old.name.clone())],
+        )
+    } else if e.calendar_invite && old.reminder_times() != e.reminder_times() {
+        Template::new("calendar_invite", &[])
+    } else {
+        return Ok(());
+    };
+    let text = template.time("start", e.ts, &e.tz).to_text();
+    let now = get_unix_time();
+    if e.calendar_invite {
+        enqueue_notice(conn, e.id, 0, MessageType::EventChange, &text, &format::ics_invite(e, false, now), now)?;
+    } else {
+        enqueue_message(conn, e.id, "Bot", 0, MessageType::EventChange, &text, now)?;
+    }
+    if old.ts != e.ts || old.name != e.name || old.link != e.link {
+        enqueue_message(conn, e.id, "Bot", 1, MessageType::EventChange, &text, now)?;
     }
     Ok(())
 }
@@ -171,29 +514,35 @@ pub fn delete_enqueued_messages(
     conn: &PooledConnection<SqliteConnectionManager>,
     event_id: u64,
     message_type: MessageType,
-) -> Result<(), rusqlite::Error> {
-    let mut stmt = conn.prepare("SELECT id FROM messages WHERE event = ?1 AND type = ?2")?;
-    let mut rows = stmt.query([event_id, message_type as u64])?;
-    while let Some(row) = rows.next()? {
-        let message_id: u64 = row.get(0)?;
-        conn.execute(
-            "DELETE FROM message_outbox WHERE message = ?1",
-            params![message_id],
-        )?;
-        conn.execute(
-            "DELETE FROM messages WHERE id = ?1",
-            params![message_id],
-        )?;
-    }
-    Ok(())
+) -> Result<(), Error> {
+    let message_type = message_type as u64;
+    let sp = Savepoint::new(conn)?;
+    conn.execute(
+        "DELETE FROM message_outbox WHERE message IN (SELECT id FROM messages WHERE event = ?1 AND type = ?2)",
+        params![event_id, message_type],
+    )?;
+    conn.execute(
+        "DELETE FROM messages WHERE event = ?1 AND type = ?2",
+        params![event_id, message_type],
+    )?;
+    sp.commit()
 }
 
-pub fn prompt_waiting_list(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64) -> Result<(), rusqlite::Error> {
+pub fn prompt_waiting_list(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64) -> Result<(), Error> {
     if have_vacancies(conn, event_id)? == false {
         debug!("prompt_waiting_list - no tickets, event {}", event_id);
         return Ok(());
     }
 
+    let mut stmt = conn.prepare("SELECT auto_promote_hours FROM events WHERE id = ?1")?;
+    let mut rows = stmt.query([event_id])?;
+    if let Some(row) = rows.next()? {
+        let hours: u64 = row.get("auto_promote_hours")?;
+        if hours > 0 {
+            return promote_waiting_list(conn, event_id, hours, get_unix_time());
+        }
+    }
+
     let send_at = get_unix_time() + 10; // give some time to finish multiple cancellations
     let mut stmt = conn
         .prepare("SELECT id FROM messages WHERE event = ?1 AND type = ?2")?;
@@ -205,13 +554,15 @@ pub fn prompt_waiting_list(conn: &PooledConnection<SqliteConnectionManager>, eve
             params![message_id, send_at],
         )?;
     } else {
-        if let Ok(event_name) = get_event_name(conn, event_id) {
+        if let Ok((name, ts, tz)) = get_event_name(conn, event_id) {
             enqueue_message(conn, 
                 event_id,
                 "Bot",
                 1,
                 MessageType::WaitingListPrompt,
-                &format!("Кто-то отменил бронирование на мероприятие: \"{}\".\nВы можете попробовать записаться.", event_name),
+                &Template::new("waiting_list_prompt", &[("event", name)])
+                    .time("start", ts, &tz)
+                    .to_text(),
                 send_at
             )?;
         }
@@ -219,12 +570,136 @@ pub fn prompt_waiting_list(conn: &PooledConnection<SqliteConnectionManager>, eve
     Ok(())
 }
 
+/// Moves the earliest waiting reservations that fit into the free seats and asks their holders
+/// to accept them within the given hours.
+fn promote_waiting_list(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64, hours: u64, ts: u64) -> Result<(), Error> {
+    let event = get_event(conn, event_id, 0)?.event;
+    let (name, start, tz) = (event.name.clone(), event.ts, event.tz.clone());
+    let is_paid = event.get_type() == EventType::Paid;
+    let accept_until = std::cmp::min(ts + hours * 60 * 60, start);
+    let sp = Savepoint::new(conn)?;
+    let mut promoted: Vec<u64> = Vec::new();
+    loop {
+        let (vacant_adults, vacant_children) = get_vacancies(conn, event_id)?;
+        let mut stmt = conn.prepare(
+            "SELECT r.id, r.user FROM reservations as r JOIN events as e ON r.event = e.id \
+            WHERE r.event = ?1 AND r.waiting_list = 1 AND r.adults <= ?2 AND r.children <= ?3 \
+            AND r.adults + (SELECT coalesce(sum(adults), 0) FROM reservations WHERE event = ?1 AND user = r.user AND waiting_list = 0) <= e.max_adults_per_reservation \
+            AND r.children + (SELECT coalesce(sum(children), 0) FROM reservations WHERE event = ?1 AND user = r.user AND waiting_list = 0) <= e.max_children_per_reservation \
+            ORDER BY r.ts, r.id LIMIT 1",
+        )?;
+        let mut rows = stmt.query(params![event_id, vacant_adults, vacant_children])?;
+        let (id, user): (u64, u64) = match rows.next()? {
+            Some(row) => (row.get("id")?, row.get("user")?),
+            None => break,
+        };
+        conn.execute(
+            "UPDATE reservations SET waiting_list = 0, accept_until = ?1, confirmed = 0 WHERE id = ?2",
+            params![accept_until, id],
+        )?;
+        if is_paid {
+            offer_seats(conn, &event, id, user, ts)?;
+        }
+        if !promoted.contains(&user) {
+            promoted.push(user);
+        }
+    }
+    for user in promoted {
+        let key = if is_paid { "seat_offered" } else { "seat_promoted" };
+        let text = Template::new(key, &[("name", name.clone())])
+            .time("start", start, &tz)
+            .time("until", accept_until, &tz)
+            .to_text();
+        enqueue_notice(conn, event_id, user, MessageType::Promotion, &text, "", ts)?;
+    }
+    sp.commit()?;
+    Ok(())
+}
+
+/// Turns the promoted waiting list reservation of a paid event into a hold to be paid for. The
+/// price tier is kept while it's still on sale and the user's promo code is applied, unless it
+/// would leave nothing to pay.
+fn offer_seats(conn: &PooledConnection<SqliteConnectionManager>, event: &Event, id: u64, user: u64, ts: u64) -> Result<(), Error> {
+    let (adults, children, tier): (u64, u64, u64) =
+        conn.query_row("SELECT adults, children, tier FROM reservations WHERE id = ?1", [id], |row| {
+            Ok((row.get("adults")?, row.get("children")?, row.get("tier")?))
+        })?;
+    // The offered seats already count as sold.
+    let tier = if event.prices(tier).is_some() && is_tier_available(conn, event, tier, 0, ts)? { tier } else { 0 };
+    let (adult_ticket_price, child_ticket_price) = event.prices(tier).unwrap_or_default();
+    let price = adult_ticket_price * adults + child_ticket_price * children;
+    let (promo_code, discount) = match get_applied_promo_code(conn, event.id, user, ts)? {
+        Some(p) if p.discount(price) < price => (p.code.clone(), p.discount(price)),
+        _ => (String::new(), 0),
+    };
+    conn.execute(
+        "UPDATE reservations SET state = ?1, ts = ?2, tier = ?3, promo_code = ?4, discount = ?5 WHERE id = ?6",
+        params![ReservationState::PaymentPending as u64, ts, tier, promo_code, discount, id],
+    )?;
+    Ok(())
+}
+
+/// Seats of a paid event offered to the user from the waiting list, with the discount and the
+/// time they have to be paid by.
+pub fn get_offer(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64, user: u64) -> Result<Option<(Booking, u64, u64)>, Error> {
+    let mut stmt = conn.prepare(
+        "SELECT adults, children, tier, promo_code, discount, accept_until FROM reservations \
+        WHERE event = ?1 AND user = ?2 AND state = ?3 AND waiting_list = 0 AND accept_until != 0 LIMIT 1",
+    )?;
+    let mut rows = stmt.query(params![event_id, user, ReservationState::PaymentPending as u64])?;
+    match rows.next()? {
+        Some(row) => Ok(Some((
+            Booking {
+                event_id,
+                adults: row.get("adults")?,
+                children: row.get("children")?,
+                user_id: user,
+                tier: row.get("tier")?,
+                promo_code: row.get("promo_code")?,
+            },
+            row.get("discount")?,
+            row.get("accept_until")?,
+        ))),
+        None => Ok(None),
+    }
+}
+
+/// Returns promoted seats that weren't accepted or paid for in time and promotes the next in the
+/// waiting list.
+pub fn expire_promotions(conn: &PooledConnection<SqliteConnectionManager>, ts: u64) -> Result<usize, Error> {
+    let sp = Savepoint::new(conn)?;
+    let mut stmt = conn.prepare(
+        "SELECT r.event, r.user, max(r.state) as state, e.name, e.ts, e.tz FROM reservations as r JOIN events as e ON r.event = e.id \
+        WHERE r.waiting_list = 0 AND r.accept_until != 0 AND r.accept_until < ?1 AND r.confirmed = 0 GROUP BY r.event, r.user",
+    )?;
+    let expired = stmt
+        .query_map(params![ts], |row| {
+            Ok((row.get("event")?, row.get("user")?, row.get("state")?, row.get("name")?, row.get("ts")?, row.get("tz")?))
+        })?
+        .collect::<Result<Vec<(u64, u64, u64, String, u64, String)>, rusqlite::Error>>()?;
+    let mut released = 0;
+    for (event_id, user, state, name, start, tz) in expired {
+        released += conn.execute(
+            "DELETE FROM reservations WHERE event = ?1 AND user = ?2 AND waiting_list = 0 AND accept_until != 0 AND confirmed = 0",
+            params![event_id, user],
+        )?;
+        let key = if state == ReservationState::PaymentPending as u64 { "offer_expired" } else { "promotion_expired" };
+        let text = Template::new(key, &[("name", name)])
+            .time("start", start, &tz)
+            .to_text();
+        enqueue_notice(conn, event_id, user, MessageType::Release, &text, "", ts)?;
+        prompt_waiting_list(conn, event_id)?;
+    }
+    sp.commit()?;
+    Ok(released)
+}
+
 pub fn blacklist_absent_participants(
     conn: &PooledConnection<SqliteConnectionManager>,
     event_id: u64,
     admins: &HashSet<u64>,
     cancel_future_reservations: bool,
-) -> Result<(), rusqlite::Error> {
+) -> Result<(), Error> {
     let mut stmt = conn.prepare(
         "select r.*, p.user from (select event, user, user_name1, user_name2, count(user) as count from reservations where event = ?1 and waiting_list = 0 group by user) as r 
         left join presence as p on r.event = p.event and r.user = p.user"
@@ -248,7 +723,8 @@ pub fn blacklist_absent_participants(
     }
     if presence_checked && list.len() > 0 {
         // Check at least one present.
-        if let Ok(reason) = get_event_name(conn, event_id) {
+        if let Ok((name, ts, tz)) = get_event_name(conn, event_id) {
+            let reason = format!("{} {}", format::ts(ts, &tz, locale::DEFAULT_LANGUAGE), name);
             list.iter()
                 .filter(|p| !admins.contains(&p.user_id))
                 .try_for_each(|p| {
@@ -267,7 +743,7 @@ pub fn blacklist_absent_participants(
     Ok(())
 }
 
-pub fn get_ban_reason(conn: &PooledConnection<SqliteConnectionManager>, user_id: u64) -> Result<String, rusqlite::Error> {
+pub fn get_ban_reason(conn: &PooledConnection<SqliteConnectionManager>, user_id: u64) -> Result<String, Error> {
     let mut stmt = conn
         .prepare("SELECT reason FROM black_list WHERE user = ?1")?;
     let mut rows = stmt.query([user_id])?;
@@ -285,60 +761,54 @@ pub fn delete_event(
     automatic_blacklisting: bool,
     cancel_future_reservations_on_ban: bool,
     admins: &HashSet<u64>
-) -> Result<(), rusqlite::Error> {
+) -> Result<(), Error> {
     let s = get_event(conn, event_id, 0)?;
+    let sp = Savepoint::new(conn)?;
+    // (user, is on the waiting list only)
+    let mut stmt = conn.prepare("SELECT user, min(waiting_list) FROM reservations WHERE event = ?1 GROUP BY user ORDER BY min(ts)")?;
+    let participants = stmt
+        .query_map([event_id], |row| Ok((row.get::<usize, u64>(0)?, row.get::<usize, bool>(1)?)))?
+        .collect::<Result<Vec<(u64, bool)>, rusqlite::Error>>()?;
     if automatic_blacklisting && s.event.adult_ticket_price == 0 && s.event.child_ticket_price == 0 {
-        if let Err(e) = blacklist_absent_participants(
-            conn,
-            event_id,
-            admins,
-            cancel_future_reservations_on_ban,
-        ) {
-            // todo: fix error
-            return Err(rusqlite::Error::InvalidParameterName(
-                format!("Failed to blacklist absent participants: {}.", e),
-            ));
-        }
+        blacklist_absent_participants(conn, event_id, admins, cancel_future_reservations_on_ban)?;
     }
 
-    if let Err(e) = conn
-        .execute("DELETE FROM reservations WHERE event=?1", params![event_id])
-    {
-        error!("{}", e);
-    }
-    if let Err(e) = conn
-        .execute("DELETE FROM events WHERE id=?1", params![event_id])
-    {
-        error!("{}", e);
-    }
-    if let Err(e) = conn
-        .execute("DELETE FROM attachments WHERE event=?1", params![event_id])
-    {
-        error!("{}", e);
-    }
-    if let Err(e) = conn
-        .execute("DELETE FROM presence WHERE event=?1", params![event_id])
-    {
-        error!("{}", e);
+    let now = get_unix_time();
+    if s.event.ts > now {
+        // Cancelled before the start, the payments are kept for refund.
+        for (user, _) in &participants {
+            move_to_refunds(conn, event_id, *user, "event deleted", now)?;
+        }
     }
-    if let Err(e) = conn.execute(
-        "DELETE FROM group_leaders WHERE event=?1",
+    conn.execute("DELETE FROM reservations WHERE event=?1", params![event_id])?;
+    conn.execute("DELETE FROM events WHERE id=?1", params![event_id])?;
+    conn.execute("DELETE FROM attachments WHERE event=?1", params![event_id])?;
+    conn.execute("DELETE FROM presence WHERE event=?1", params![event_id])?;
+    conn.execute("DELETE FROM group_leaders WHERE event=?1", params![event_id])?;
+    conn.execute("DELETE FROM applied_promo_codes WHERE event=?1", params![event_id])?;
+    conn.execute(
+        "DELETE FROM message_outbox WHERE message IN (SELECT id FROM messages WHERE event=?1)",
         params![event_id],
-    ) {
-        error!("{}", e);
-    }
-    if let Err(e) = conn
-        .execute("DELETE FROM messages WHERE event=?1", params![event_id])
-    {
-        error!("{}", e);
+    )?;
+    conn.execute("DELETE FROM messages WHERE event=?1", params![event_id])?;
+
+    if s.event.ts > now {
+        // The reservations are gone, so the notices are addressed to each participant.
+        let text = Template::new("event_cancelled", &[("link", s.event.link.clone()), ("name", s.event.name.clone())])
+            .time("start", s.event.ts, &s.event.tz)
+            .to_text();
+        let document = if s.event.calendar_invite { format::ics_invite(&s.event, true, now) } else { String::new() };
+        for (user, waiting) in participants {
+            enqueue_notice(conn, event_id, user, MessageType::EventChange, &text, if waiting { "" } else { &document }, now)?;
+        }
     }
-    Ok(())
+    sp.commit()
 }
 
 pub fn delete_link(
     conn: &PooledConnection<SqliteConnectionManager>,
     link: &str,
-) -> Result<(), rusqlite::Error> {
+) -> Result<(), Error> {
     let mut stmt = conn.prepare("select id from events where link = ?1")?;
     let mut rows = stmt.query(params![link])?;
     if let Some(row) = rows.next()? {
@@ -358,22 +828,29 @@ pub fn sign_up(
     wait: u64,
     ts: u64,
     amount: u64,
+    tier: u64,
+    promo_code: &str,
 ) -> anyhow::Result<(usize, bool)> {
+    // BEGIN IMMEDIATE takes the database write lock before the limits are checked, so parallel
+    // sign-ups can't both see the last free seat. Other connections wait up to the busy timeout.
+    // Early returns drop the transaction, which rolls it back.
+    let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
     let user_id = user.id.0;
     let s = get_event(conn, event_id, user_id)?;
     let event_type = s.event.get_type();
 
     if ts > s.event.ts || (s.state != EventState::Open && user.is_admin == false) {
-        return Err(anyhow!("Запись остановлена."));
+        return Err(anyhow!(t!(&user.lang, "sign_up_closed")));
     }
 
     // Check event limits
-    if (wait == 0 || event_type == EventType::Paid) &&
+    if wait == 0 &&
         (adults as i64 > s.event.max_adults as i64 - s.adults.reserved as i64 || 
         children as i64 > s.event.max_children as i64 - s.children.reserved as i64) {
-        return Err(anyhow!("К сожалению, свободные места закончились."));
+        return Err(anyhow!(t!(&user.lang, "no_seats")));
     }
 
+    let mut discount = 0;
     let state = match event_type {
         EventType::Free => { 
             if let Ok(black_listed) = is_in_black_list(conn, user_id) {
@@ -387,15 +864,35 @@ pub fn sign_up(
                 .prepare("select events.id from events join reservations as r on events.id = r.event where events.ts = ?1 and r.user = ?2 and events.id != ?3")?;
             let mut rows = stmt.query(params![s.event.ts, user_id, s.event.id])?;
             if let Some(_) = rows.next()? {
-                return Err(anyhow!(
-                    "Вы уже записаны на другое мероприятие в это время."
-                ));
+                return Err(anyhow!(t!(&user.lang, "time_conflict")));
+            }
+            ReservationState::Free
+        }
+        EventType::Paid if wait != 0 => {
+            // The waiting list is paid for when the seats are offered.
+            if s.event.prices(tier).is_none() {
+                return Err(anyhow!(t!(&user.lang, "price_tier_unavailable")));
             }
             ReservationState::Free
         }
         EventType::Paid => {
+            let (adult_ticket_price, child_ticket_price) = match s.event.prices(tier) {
+                Some(prices) if is_tier_available(conn, &s.event, tier, adults + children, ts)? => prices,
+                _ => return Err(anyhow!(t!(&user.lang, "price_tier_unavailable"))),
+            };
+            let price = adult_ticket_price * adults + child_ticket_price * children;
+            if !promo_code.is_empty() {
+                match check_promo_code(conn, promo_code, event_id, ts)? {
+                    Some(p) => discount = p.discount(price),
+                    None => return Err(anyhow!(t!(&user.lang, "promo_code_invalid"))),
+                }
+                // Telegram doesn't take invoices without an amount.
+                if discount >= price {
+                    return Err(anyhow!(t!(&user.lang, "promo_code_invalid")));
+                }
+            }
             // pre checkout?
-            if s.event.adult_ticket_price * adults + s.event.child_ticket_price * children != amount {
+            if price - discount != amount {
                 return Err(anyhow!("Wrong tranaction amount"));
             }
             ReservationState::PaymentPending
@@ -406,64 +903,236 @@ pub fn sign_up(
     };
 
     // Check user limits
-    if s.adults.my_reservation + s.adults.my_waiting + adults
+    if event_type == EventType::Paid {
+        // Paid seats never move from the waiting list without a payment.
+        let (waiting_adults, waiting_children) = if wait == 0 { (0, 0) } else { (s.adults.my_waiting, s.children.my_waiting) };
+        if s.adults.my_reservation + waiting_adults + adults > s.event.max_adults_per_reservation
+            || s.children.my_reservation + waiting_children + children > s.event.max_children_per_reservation
+        {
+            return Ok((0, false));
+        }
+    } else if s.adults.my_reservation + s.adults.my_waiting + adults
         > s.event.max_adults_per_reservation
     {
         if s.adults.my_reservation + adults > s.event.max_adults_per_reservation {
             return Ok((0, false));
         } else {
             move_from_waiting_list(conn, event_id, user_id, 1, 0)?;
+            tx.commit()?;
             return Ok((1, false));
         }
     }
-    if s.children.my_reservation + s.children.my_waiting + children
+    if event_type != EventType::Paid && s.children.my_reservation + s.children.my_waiting + children
         > s.event.max_children_per_reservation
     {
         if s.children.my_reservation + children > s.event.max_children_per_reservation {
             return Ok((0, false));
         } else {
             move_from_waiting_list(conn, event_id, user_id, 0, 1)?;
+            tx.commit()?;
             return Ok((1, false));
         }
     }
 
-    Ok((conn.execute(
-        "INSERT INTO reservations (event, user, user_name1, user_name2, adults, children, waiting_list, ts, state) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
-        params![event_id, user_id, user.user_name1, user.user_name2, adults, children, wait, ts, state as u64],
-    )?, false))    
+    let res = conn.execute(
+        "INSERT INTO reservations (event, user, user_name1, user_name2, adults, children, waiting_list, ts, state, tier, promo_code, discount) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+        params![event_id, user_id, user.user_name1, user.user_name2, adults, children, wait, ts, state as u64, tier, promo_code, discount],
+    )?;
+    tx.commit()?;
+    Ok((res, false))
 }
 
 pub fn checkout(
     conn: &PooledConnection<SqliteConnectionManager>,
     booking: &Booking,
-    order_info: OrderInfo,
+    mut order_info: OrderInfo,
 ) -> anyhow::Result<()> {
+    let sp = Savepoint::new(conn)?;
     let s = get_event(conn, booking.event_id, booking.user_id)?;
-    if s.event.adult_ticket_price * booking.adults + s.event.child_ticket_price * booking.children != order_info.amount {
-        return Err(anyhow!("Wrong tranaction amount"));
+    let (adult_ticket_price, child_ticket_price) = s
+        .event
+        .prices(booking.tier)
+        .ok_or_else(|| anyhow!("Unknown price tier {}", booking.tier))?;
+    if s.event.currency != order_info.currency {
+        return Err(anyhow!("Wrong tranaction currency {}", order_info.currency));
     }
 
     let mut stmt = conn
-        .prepare("select id from reservations where event = ?1 and user = ?2 and state = ?3 and adults = ?4 and children = ?5 limit 1")?;
-    let mut rows = stmt.query(params![booking.event_id, booking.user_id, ReservationState::PaymentPending as u64, booking.adults, booking.children])?;
+        .prepare("select id, discount from reservations where event = ?1 and user = ?2 and state = ?3 and adults = ?4 and children = ?5 and tier = ?6 and promo_code = ?7 limit 1")?;
+    let mut rows = stmt.query(params![booking.event_id, booking.user_id, ReservationState::PaymentPending as u64, booking.adults, booking.children, booking.tier, booking.promo_code])?;
     if let Some(row) = rows.next()? {
         let id: u64 = row.get("id")?;
-        conn.execute("UPDATE reservations SET state = ?1, payment = ?2, user_name1 = ?3 WHERE id = ?4",
+        // The discount was granted when the seats were reserved.
+        let discount: u64 = row.get("discount")?;
+        if adult_ticket_price * booking.adults + child_ticket_price * booking.children - discount != order_info.amount {
+            return Err(anyhow!("Wrong tranaction amount"));
+        }
+        if !booking.promo_code.is_empty() {
+            conn.execute("UPDATE promo_codes SET uses = uses + 1 WHERE code = ?1", params![booking.promo_code])?;
+        }
+        order_info.promo_code = booking.promo_code.clone();
+        order_info.discount = discount;
+        conn.execute("UPDATE reservations SET state = ?1, payment = ?2, user_name1 = ?3, accept_until = 0 WHERE id = ?4",
             params![ReservationState::PaymentCompleted as u64, serde_json::to_string(&order_info)?, order_info.name, id],
         )?;
+        // The user got the tickets they waited for.
+        conn.execute("DELETE FROM reservations WHERE event = ?1 AND user = ?2 AND waiting_list = 1",
+            params![booking.event_id, booking.user_id],
+        )?;
+        sp.commit()?;
         Ok(())
     } else {
         Err(anyhow!("Failed to find reservation for event {}, user {}.", booking.event_id, booking.user_id))
     }
 }
 
+fn promo_code_from_row(row: &Row) -> Result<PromoCode, rusqlite::Error> {
+    Ok(PromoCode {
+        code: row.get("code")?,
+        percent: row.get("percent")?,
+        amount: row.get("amount")?,
+        max_uses: row.get("max_uses")?,
+        valid_from: row.get("valid_from")?,
+        valid_until: row.get("valid_until")?,
+        event_id: row.get("event")?,
+    })
+}
+
+/// Adds the promo code or replaces the one with the same code. The discount has to leave something
+/// to pay for the cheapest ticket. Fixed amounts are in the currency of the event, so they need one.
+pub fn add_promo_code(conn: &PooledConnection<SqliteConnectionManager>, p: &PromoCode) -> Result<(), Error> {
+    if p.percent >= 100 {
+        return Err(Error::Invalid("The discount has to be less than 100%".to_string()));
+    }
+    if p.amount > 0 {
+        if p.event_id == 0 {
+            return Err(Error::Invalid("Promo codes with an amount have to be for an event".to_string()));
+        }
+        let event = get_event(conn, p.event_id, 0)?.event;
+        let cheapest = (0..=event.price_tiers.len() as u64)
+            .filter_map(|tier| event.prices(tier))
+            .flat_map(|(adult, child)| [adult, child])
+            .filter(|price| *price > 0)
+            .min()
+            .unwrap_or(0);
+        if p.discount(cheapest) >= cheapest {
+            return Err(Error::Invalid(format!("The discount has to be less than the cheapest ticket, {}", cheapest)));
+        }
+    }
+    conn.execute(
+        "INSERT INTO promo_codes (code, percent, amount, max_uses, valid_from, valid_until, event) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7) \
+        ON CONFLICT (code) DO UPDATE SET percent = excluded.percent, amount = excluded.amount, max_uses = excluded.max_uses, \
+        valid_from = excluded.valid_from, valid_until = excluded.valid_until, event = excluded.event",
+        params![p.code, p.percent, p.amount, p.max_uses, p.valid_from, p.valid_until, p.event_id],
+    )?;
+    Ok(())
+}
+
+pub fn delete_promo_code(conn: &PooledConnection<SqliteConnectionManager>, code: &str) -> Result<(), Error> {
+    if conn.execute("DELETE FROM promo_codes WHERE code = ?1", params![code])? == 0 {
+        return Err(Error::NotFound(format!("promo code {}", code)));
+    }
+    Ok(())
+}
+
+/// Promo codes with the number of paid orders that used them.
+pub fn get_promo_codes(conn: &PooledConnection<SqliteConnectionManager>) -> Result<Vec<(PromoCode, u64)>, Error> {
+    let mut stmt = conn.prepare("SELECT * FROM promo_codes ORDER BY code")?;
+    let res = stmt
+        .query_map([], |row| Ok((promo_code_from_row(row)?, row.get("uses")?)))?
+        .collect::<Result<Vec<(PromoCode, u64)>, rusqlite::Error>>()?;
+    Ok(res)
+}
+
+/// The promo code if it can be used for the event at the given time. Orders waiting for payment
+/// count as uses.
+pub fn check_promo_code(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    code: &str,
+    event_id: u64,
+    ts: u64,
+) -> Result<Option<PromoCode>, Error> {
+    let mut stmt = conn.prepare(
+        "SELECT p.* FROM promo_codes as p WHERE p.code = ?1 AND (p.event = 0 OR p.event = ?2) \
+        AND p.valid_from <= ?3 AND (p.valid_until = 0 OR p.valid_until > ?3) \
+        AND (p.max_uses = 0 OR p.max_uses > p.uses + (SELECT count(*) FROM reservations WHERE promo_code = p.code AND state = ?4))",
+    )?;
+    let mut rows = stmt.query(params![code, event_id, ts, ReservationState::PaymentPending as u64])?;
+    match rows.next()? {
+        Some(row) => Ok(Some(promo_code_from_row(row)?)),
+        None => Ok(None),
+    }
+}
+
+/// Whether any promo code can be used for the event at the given time.
+pub fn have_promo_codes(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64, ts: u64) -> Result<bool, Error> {
+    let count: u64 = conn.query_row(
+        "SELECT count(*) FROM promo_codes WHERE (event = 0 OR event = ?1) AND valid_from <= ?2 AND (valid_until = 0 OR valid_until > ?2)",
+        params![event_id, ts],
+        |row| row.get(0),
+    )?;
+    Ok(count > 0)
+}
+
+/// Remembers the promo code the user entered for the event.
+pub fn apply_promo_code(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64, user: u64, code: &str) -> Result<(), Error> {
+    conn.execute(
+        "INSERT INTO applied_promo_codes (user, event, code) VALUES (?1, ?2, ?3) ON CONFLICT (user, event) DO UPDATE SET code = excluded.code",
+        params![user, event_id, code],
+    )?;
+    Ok(())
+}
+
+/// The promo code the user entered for the event, if it can still be used.
+pub fn get_applied_promo_code(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    event_id: u64,
+    user: u64,
+    ts: u64,
+) -> Result<Option<PromoCode>, Error> {
+    let mut stmt = conn.prepare("SELECT code FROM applied_promo_codes WHERE user = ?1 AND event = ?2")?;
+    let mut rows = stmt.query(params![user, event_id])?;
+    match rows.next()? {
+        Some(row) => check_promo_code(conn, &row.get::<usize, String>(0)?, event_id, ts),
+        None => Ok(None),
+    }
+}
+
+/// Seats sold or being paid for at the price tier.
+pub fn get_tier_sales(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64, tier: u64) -> Result<u64, Error> {
+    Ok(conn.query_row(
+        "SELECT coalesce(sum(adults + children), 0) FROM reservations WHERE event = ?1 AND tier = ?2 AND waiting_list = 0",
+        params![event_id, tier],
+        |row| row.get(0),
+    )?)
+}
+
+/// Whether the seats can still be sold at the price tier at the given time. The regular price is
+/// only limited by the event.
+pub fn is_tier_available(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    event: &Event,
+    tier: u64,
+    seats: u64,
+    ts: u64,
+) -> Result<bool, Error> {
+    if tier == 0 {
+        return Ok(true);
+    }
+    match event.price_tiers.get(tier as usize - 1) {
+        Some(t) => Ok((t.until == 0 || ts < t.until)
+            && (t.quota == 0 || get_tier_sales(conn, event.id, tier)? + seats <= t.quota)),
+        None => Ok(false),
+    }
+}
+
 fn move_from_waiting_list(
     conn: &PooledConnection<SqliteConnectionManager>,
     event_id: u64,
     user_id: u64,
     adults: u64,
     children: u64,
-) -> Result<(), rusqlite::Error> {
+) -> Result<(), Error> {
     conn.execute("UPDATE reservations SET waiting_list = 0  WHERE id in \
         (SELECT id FROM reservations where event = ?1 and user = ?2 and waiting_list = 1 and adults = ?3 and children = ?4 order by ts limit 1)",
         params![event_id, user_id, adults, children],
@@ -476,7 +1145,7 @@ pub fn add_attachment(
     event_id: u64,
     user: u64,
     attachment: &str,
-) -> Result<usize, rusqlite::Error> {
+) -> Result<usize, Error> {
     let msg = if attachment.len() < 256 {
         format!("{}...", attachment.chars().take(256).collect::<String>())
     } else {
@@ -489,17 +1158,17 @@ pub fn add_attachment(
         || s.children.my_reservation > 0
         || s.children.my_waiting > 0
     {
-        conn.execute(
+        Ok(conn.execute(
             "INSERT INTO attachments (event, user, attachment) VALUES (?1, ?2, ?3) ON CONFLICT (event, user) DO \
             UPDATE SET attachment=excluded.attachment",
             params![event_id, user, msg],
-        )
+        )?)
     } else {
         Ok(0)
     }
 }
 
-pub fn cancel(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64, user: u64, adults: u64) -> Result<(), rusqlite::Error> {
+pub fn cancel(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64, user: u64, adults: u64) -> Result<(), Error> {
     let state_changed = have_vacancies(conn, event_id)? == false;
     conn.execute(
         "DELETE FROM reservations WHERE id IN (SELECT id FROM reservations WHERE event=?1 AND user=?2 AND adults = ?3 ORDER BY waiting_list DESC LIMIT 1)",
@@ -512,7 +1181,15 @@ pub fn cancel(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64, u
     }
 }
 
-pub fn wontgo(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64, user: u64) -> Result<(), rusqlite::Error> {
+/// Removes the user from the waiting list of the event.
+pub fn leave_waiting_list(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64, user: u64) -> Result<usize, Error> {
+    Ok(conn.execute(
+        "DELETE FROM reservations WHERE event = ?1 AND user = ?2 AND waiting_list = 1",
+        params![event_id, user],
+    )?)
+}
+
+pub fn wontgo(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64, user: u64) -> Result<(), Error> {
     let state_changed = have_vacancies(conn, event_id)? == false;
     conn.execute(
         "DELETE FROM reservations WHERE event=?1 AND user=?2",
@@ -525,7 +1202,62 @@ pub fn wontgo(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64, u
     }
 }
 
-fn have_vacancies(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64) -> Result<bool, rusqlite::Error> {
+/// Records that the reminder asked the user whether they are still coming.
+pub fn set_reminded(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64, user: u64, ts: u64) -> Result<(), Error> {
+    conn.execute(
+        "UPDATE reservations SET reminded = ?1 WHERE event = ?2 AND user = ?3 AND reminded = 0",
+        params![ts, event_id, user],
+    )?;
+    Ok(())
+}
+
+pub fn confirm_attendance(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64, user: u64) -> Result<(), Error> {
+    let res = conn.execute(
+        "UPDATE reservations SET confirmed = 1 WHERE event = ?1 AND user = ?2",
+        params![event_id, user],
+    )?;
+    if res == 0 {
+        return Err(Error::NotFound(format!("reservation {} {}", event_id, user)));
+    }
+    Ok(())
+}
+
+/// Releases the seats of free events starting within `hours` held by participants who were asked
+/// before that and didn't confirm. The waiting list gets prompted, the participants notified.
+/// Returns the number of released reservations.
+pub fn release_unconfirmed(conn: &PooledConnection<SqliteConnectionManager>, ts: u64, hours: u64) -> Result<usize, Error> {
+    let deadline = hours * 60 * 60;
+    let sp = Savepoint::new(conn)?;
+    let mut stmt = conn.prepare(
+        "SELECT r.event, r.user, e.name, e.ts, e.tz FROM reservations as r JOIN events as e ON r.event = e.id \
+        WHERE r.waiting_list = 0 AND r.reminded != 0 AND r.reminded < e.ts - ?2 AND e.ts > ?1 AND e.ts <= ?1 + ?2 \
+        AND e.adult_ticket_price = 0 AND e.child_ticket_price = 0 GROUP BY r.event, r.user HAVING max(r.confirmed) = 0",
+    )?;
+    let unconfirmed = stmt
+        .query_map(params![ts, deadline], |row| {
+            Ok((row.get("event")?, row.get("user")?, row.get("name")?, row.get("ts")?, row.get("tz")?))
+        })?
+        .collect::<Result<Vec<(u64, u64, String, u64, String)>, rusqlite::Error>>()?;
+    let mut released = 0;
+    for (event_id, user, name, start, tz) in unconfirmed {
+        let state_changed = !have_vacancies(conn, event_id)?;
+        released += conn.execute(
+            "DELETE FROM reservations WHERE event = ?1 AND user = ?2 AND waiting_list = 0",
+            params![event_id, user],
+        )?;
+        if state_changed {
+            prompt_waiting_list(conn, event_id)?;
+        }
+        let text = Template::new("seats_released", &[("name", name)])
+            .time("start", start, &tz)
+            .to_text();
+        enqueue_notice(conn, event_id, user, MessageType::Release, &text, "", ts)?;
+    }
+    sp.commit()?;
+    Ok(released)
+}
+
+fn have_vacancies(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64) -> Result<bool, Error> {
     let (vacant_adults, vacant_children) = get_vacancies(conn, event_id)?;
     if vacant_adults + vacant_children > 0 {
         Ok(true)
@@ -534,7 +1266,7 @@ fn have_vacancies(conn: &PooledConnection<SqliteConnectionManager>, event_id: u6
     }
 }
 
-fn get_vacancies(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64) -> Result<(u64, u64), rusqlite::Error> {
+fn get_vacancies(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64) -> Result<(u64, u64), Error> {
     let mut vacant_adults: u64 = 0;
     let mut vacant_children: u64 = 0;
     let mut stmt = conn.prepare(
@@ -564,7 +1296,7 @@ pub fn get_attachment(
     conn: &PooledConnection<SqliteConnectionManager>,
     event_id: u64,
     user: u64,
-) -> Result<Option<String>, rusqlite::Error> {
+) -> Result<Option<String>, Error> {
     let mut stmt = conn
         .prepare("SELECT attachment FROM attachments WHERE event = ?1 AND user = ?2")?;
     let mut rows = stmt.query(params![event_id, user])?;
@@ -581,10 +1313,10 @@ pub fn get_events(
     user: u64,
     offset: u64,
     limit: u64,
-) -> Result<Vec<EventStats>, rusqlite::Error> {
+) -> Result<Vec<EventStats>, Error> {
     let mut stmt = conn.prepare(
         "select a.*, b.my_adults, b.my_children, c.my_wait_adults, c.my_wait_children FROM \
-        (SELECT events.id, events.name, events.link, events.max_adults, events.max_children, events.max_adults_per_reservation, events.max_children_per_reservation, events.ts, r.adults, r.children, events.state, events.adult_ticket_price, events.child_ticket_price FROM events \
+        (SELECT events.id, events.name, events.link, events.max_adults, events.max_children, events.max_adults_per_reservation, events.max_children_per_reservation, events.ts, r.adults, r.children, events.state, events.adult_ticket_price, events.child_ticket_price, events.tz, events.currency, events.remind, events.calendar_invite, events.reminders, events.auto_promote_hours, events.price_tiers FROM events \
         LEFT JOIN (SELECT sum(adults) as adults, sum(children) as children, event FROM reservations WHERE waiting_list = 0 GROUP BY event) as r ON events.id = r.event ORDER BY ts LIMIT ?2 OFFSET ?3) as a \
         LEFT JOIN (SELECT sum(adults) as my_adults, sum(children) as my_children, event FROM reservations WHERE waiting_list = 0 AND user = ?1 GROUP BY event) as b ON a.id = b.event \
         LEFT JOIN (SELECT sum(adults) as my_wait_adults, sum(children) as my_wait_children, event FROM reservations WHERE waiting_list = 1 AND user = ?1 GROUP BY event) as c ON a.id = c.event"
@@ -597,10 +1329,10 @@ pub fn get_events(
     Ok(res)
 }
 
-pub fn get_event(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64, user: u64) -> Result<EventStats, rusqlite::Error> {
+pub fn get_event(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64, user: u64) -> Result<EventStats, Error> {
     let mut stmt = conn.prepare(
         "select a.*, b.my_adults, b.my_children, c.my_wait_adults, c.my_wait_children FROM \
-        (SELECT events.id, events.name, events.link, events.max_adults, events.max_children, events.max_adults_per_reservation, events.max_children_per_reservation, events.ts, r.adults, r.children, events.state, events.adult_ticket_price, events.child_ticket_price FROM events \
+        (SELECT events.id, events.name, events.link, events.max_adults, events.max_children, events.max_adults_per_reservation, events.max_children_per_reservation, events.ts, r.adults, r.children, events.state, events.adult_ticket_price, events.child_ticket_price, events.tz, events.currency, events.remind, events.calendar_invite, events.reminders, events.auto_promote_hours, events.price_tiers FROM events \
         LEFT JOIN (SELECT sum(adults) as adults, sum(children) as children, event FROM reservations WHERE waiting_list = 0 GROUP BY event) as r ON events.id = r.event) as a \
         LEFT JOIN (SELECT sum(adults) as my_adults, sum(children) as my_children, event FROM reservations WHERE waiting_list = 0 AND user = ?1 GROUP BY event) as b ON a.id = b.event \
         LEFT JOIN (SELECT sum(adults) as my_wait_adults, sum(children) as my_wait_children, event FROM reservations WHERE waiting_list = 1 AND user = ?1 GROUP BY event) as c ON a.id = c.event WHERE a.id = ?2"
@@ -611,24 +1343,19 @@ pub fn get_event(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64
         set_current_event(conn, user, event_id)?;
         Ok(EventStats::new(row)?)
     } else {
-        Err(rusqlite::Error::InvalidParameterName(
-            format!("Failed to find event {}", event_id),
-        ))
+        Err(Error::NotFound(format!("event {}", event_id)))
     }
 }
 
-pub fn get_event_name(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64) -> Result<String, rusqlite::Error> {
+/// Name, start and time zone of the event.
+pub fn get_event_name(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64) -> Result<(String, u64, String), Error> {
     let mut stmt = conn
-        .prepare("SELECT events.name, events.ts FROM events WHERE id = ?1")?;
+        .prepare("SELECT events.name, events.ts, events.tz FROM events WHERE id = ?1")?;
     let mut rows = stmt.query([event_id])?;
     if let Some(row) = rows.next()? {
-        let name: String = row.get("name")?;
-        let ts: u64 = row.get("ts")?;
-        Ok(format!("{} {}", format::ts(ts), name))
+        Ok((row.get("name")?, row.get("ts")?, row.get("tz")?))
     } else {
-        Err(rusqlite::Error::InvalidParameterName(
-            "Failed to find event".to_string(),
-        ))
+        Err(Error::NotFound(format!("event {}", event_id)))
     }
 }
 
@@ -639,17 +1366,17 @@ pub fn get_participants(
     offset: u64,
     limit: u64,
     state: ReservationState,
-) -> Result<Vec<Participant>, rusqlite::Error> {
+) -> Result<Vec<Participant>, Error> {
     let mut stmt;
     let mut rows = if limit == 0 {
         stmt = conn.prepare(
-        "SELECT a.*, b.attachment FROM (SELECT sum(adults) as adults, sum(children) as children, user, user_name1, user_name2, event, ts FROM reservations WHERE waiting_list = ?1 AND event = ?2 AND state = ?3 group by event, user ORDER BY ts) as a \
+        "SELECT a.*, b.attachment FROM (SELECT sum(adults) as adults, sum(children) as children, user, user_name1, user_name2, event, ts, max(reminded) as reminded, max(confirmed) as confirmed FROM reservations WHERE waiting_list = ?1 AND event = ?2 AND state = ?3 group by event, user ORDER BY ts) as a \
         LEFT JOIN attachments as b ON a.event = b.event and a.user = b.user"
         )?;
         stmt.query([waiting_list, event_id, state as u64])?
     } else {
         stmt = conn.prepare(
-            "SELECT a.*, b.attachment FROM (SELECT sum(adults) as adults, sum(children) as children, user, user_name1, user_name2, event, ts FROM reservations WHERE waiting_list = ?1 AND event = ?2 AND state = ?3 group by event, user ORDER BY ts LIMIT ?4 OFFSET ?5) as a \
+            "SELECT a.*, b.attachment FROM (SELECT sum(adults) as adults, sum(children) as children, user, user_name1, user_name2, event, ts, max(reminded) as reminded, max(confirmed) as confirmed FROM reservations WHERE waiting_list = ?1 AND event = ?2 AND state = ?3 group by event, user ORDER BY ts LIMIT ?4 OFFSET ?5) as a \
             LEFT JOIN attachments as b ON a.event = b.event and a.user = b.user"
             )?;
         stmt.query([waiting_list, event_id, state as u64, limit, offset * limit])?
@@ -662,10 +1389,9 @@ pub fn get_participants(
             user_id: row.get(2)?,
             user_name1: row.get(3)?,
             user_name2: row.get(4)?,
-            attachment: match row.get(7) {
-                Ok(v) => Some(v),
-                Err(_) => None,
-            },
+            attachment: row.get("attachment").ok(),
+            reminded: row.get::<&str, u64>("reminded")? != 0,
+            confirmed: row.get("confirmed")?,
         });
     }
     Ok(res)
@@ -676,7 +1402,7 @@ pub fn get_presence_list(
     event_id: u64,
     offset: u64,
     limit: u64,
-) -> Result<Vec<Presence>, rusqlite::Error> {
+) -> Result<Vec<Presence>, Error> {
     let mut stmt = conn.prepare(
             "select r.*, p.user, a.attachment from (select event, user, user_name1, user_name2, count(user) from reservations where event = ?1 and waiting_list = 0 group by user) as r \
             left join presence as p on r.event = p.event and r.user = p.user \
@@ -700,7 +1426,7 @@ pub fn get_presence_list(
     Ok(res)
 }
 
-pub fn confirm_presence(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64, user_id: u64) -> Result<(), rusqlite::Error> {
+pub fn confirm_presence(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64, user_id: u64) -> Result<(), Error> {
     conn.execute(
         "insert into presence (event, user) values (?1, ?2)",
         params![event_id, user_id],
@@ -708,7 +1434,30 @@ pub fn confirm_presence(conn: &PooledConnection<SqliteConnectionManager>, event_
     Ok(())
 }
 
-pub fn is_group_leader(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64, user_id: u64) -> Result<bool, rusqlite::Error> {
+/// Marks the ticket holder present. Returns the name on the reservation, its seats and whether it
+/// was checked in before, None if the user has no paid or free seats.
+pub fn check_in(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64, user_id: u64) -> Result<Option<(String, u64, bool)>, Error> {
+    let mut stmt = conn.prepare(
+        "SELECT max(user_name1) as name, sum(adults + children) as seats FROM reservations \
+        WHERE event = ?1 AND user = ?2 AND waiting_list = 0 AND state != ?3 GROUP BY user",
+    )?;
+    let mut rows = stmt.query(params![event_id, user_id, ReservationState::PaymentPending as u64])?;
+    let (name, seats): (String, u64) = match rows.next()? {
+        Some(row) => (row.get("name")?, row.get("seats")?),
+        None => return Ok(None),
+    };
+    let present: u64 = conn.query_row(
+        "SELECT count(*) FROM presence WHERE event = ?1 AND user = ?2",
+        params![event_id, user_id],
+        |row| row.get(0),
+    )?;
+    if present == 0 {
+        confirm_presence(conn, event_id, user_id)?;
+    }
+    Ok(Some((name, seats, present > 0)))
+}
+
+pub fn is_group_leader(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64, user_id: u64) -> Result<bool, Error> {
     let mut stmt = conn
         .prepare("SELECT event FROM group_leaders WHERE event = ?1 AND user = ?2")?;
     let mut rows = stmt.query(params![event_id, user_id])?;
@@ -719,7 +1468,7 @@ pub fn is_group_leader(conn: &PooledConnection<SqliteConnectionManager>, event_i
     }
 }
 
-pub fn set_group_leader(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64, user_id: u64) -> Result<(), rusqlite::Error> {
+pub fn set_group_leader(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64, user_id: u64) -> Result<(), Error> {
     conn.execute(
         "insert into group_leaders (event, user) values (?1, ?2)",
         params![event_id, user_id],
@@ -727,8 +1476,10 @@ pub fn set_group_leader(conn: &PooledConnection<SqliteConnectionManager>, event_
     Ok(())
 }
 
-pub fn delete_reservation(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64, user_id: u64) -> Result<(), rusqlite::Error> {
+pub fn delete_reservation(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64, user_id: u64) -> Result<(), Error> {
     let state_changed = have_vacancies(conn, event_id)? == false;
+    // Keep the payment records.
+    move_to_refunds(conn, event_id, user_id, "reservation deleted", get_unix_time())?;
     conn.execute(
         "delete from reservations where event = ?1 and user = ?2",
         params![event_id, user_id],
@@ -740,16 +1491,172 @@ pub fn delete_reservation(conn: &PooledConnection<SqliteConnectionManager>, even
     }
 }
 
+/// Pending and completed payments and refunds of the event.
+pub fn get_payments(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    event_id: u64,
+) -> Result<Vec<PaymentRecord>, Error> {
+    let mut stmt = conn.prepare(
+        "SELECT user, user_name1, user_name2, adults, children, state, payment, 0 as refund FROM reservations WHERE event = ?1 AND state IN (?2, ?3) \
+            UNION ALL SELECT user, user_name1, user_name2, adults, children, state, payment, 1 as refund FROM refunds WHERE event = ?1 \
+            ORDER BY refund",
+    )?;
+    let mut rows = stmt.query(params![
+        event_id,
+        ReservationState::PaymentPending as u64,
+        ReservationState::PaymentCompleted as u64
+    ])?;
+    let mut res = Vec::new();
+    while let Some(row) = rows.next()? {
+        let state: u64 = row.get("state")?;
+        let refund: u64 = row.get("refund")?;
+        let status = match (refund, state) {
+            (0, s) if s == ReservationState::PaymentPending as u64 => PaymentStatus::Pending,
+            (0, _) => PaymentStatus::Completed,
+            (_, s) if s == RefundState::Requested as u64 => PaymentStatus::RefundRequested,
+            _ => PaymentStatus::Refunded,
+        };
+        let payment: Option<String> = row.get("payment")?;
+        res.push(PaymentRecord {
+            user_id: row.get("user")?,
+            user_name1: row.get("user_name1")?,
+            user_name2: row.get("user_name2")?,
+            adults: row.get("adults")?,
+            children: row.get("children")?,
+            status,
+            order: payment.and_then(|p| serde_json::from_str(&p).ok()),
+        });
+    }
+    Ok(res)
+}
+
+pub fn add_donation(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    user_id: u64,
+    order_info: &OrderInfo,
+    ts: u64,
+) -> Result<(), Error> {
+    conn.execute(
+        "INSERT INTO donations (charge_id, user, amount, currency, ts) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![order_info.id, user_id, order_info.amount, order_info.currency, ts],
+    )?;
+    Ok(())
+}
+
+/// Donations per month (UTC) and currency, latest first: (month, currency, count, total).
+pub fn get_donation_totals(
+    conn: &PooledConnection<SqliteConnectionManager>,
+) -> Result<Vec<(String, String, u64, u64)>, Error> {
+    let mut stmt = conn.prepare(
+        "SELECT strftime('%Y-%m', ts, 'unixepoch') as month, currency, count(*), sum(amount) FROM donations \
+            GROUP BY month, currency ORDER BY month DESC, currency",
+    )?;
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))?;
+    Ok(rows.collect::<Result<Vec<_>, rusqlite::Error>>()?)
+}
+
+/// Cancels paid reservations of the user and queues them for refund. Returns the number of
+/// reservations cancelled.
+pub fn request_refund(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    event_id: u64,
+    user_id: u64,
+    reason: &str,
+    ts: u64,
+) -> Result<usize, Error> {
+    let state_changed = !have_vacancies(conn, event_id)?;
+    let res = move_to_refunds(conn, event_id, user_id, reason, ts)?;
+    if res > 0 && state_changed {
+        prompt_waiting_list(conn, event_id)?;
+    }
+    Ok(res)
+}
+
+fn move_to_refunds(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    event_id: u64,
+    user_id: u64,
+    reason: &str,
+    ts: u64,
+) -> Result<usize, Error> {
+    let sp = Savepoint::new(conn)?;
+    conn.execute(
+        "INSERT INTO refunds (event, user, user_name1, user_name2, adults, children, payment, reason, state, ts) \
+            SELECT event, user, user_name1, user_name2, adults, children, payment, ?1, ?2, ?3 FROM reservations \
+            WHERE event = ?4 AND user = ?5 AND state = ?6 AND payment IS NOT NULL",
+        params![reason, RefundState::Requested as u64, ts, event_id, user_id, ReservationState::PaymentCompleted as u64],
+    )?;
+    let res = conn.execute(
+        "DELETE FROM reservations WHERE event = ?1 AND user = ?2 AND state = ?3",
+        params![event_id, user_id, ReservationState::PaymentCompleted as u64],
+    )?;
+    sp.commit()?;
+    Ok(res)
+}
+
+/// Refunds that have not been processed with the payment provider yet.
+pub fn get_outstanding_refunds(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    offset: u64,
+    limit: u64,
+) -> Result<Vec<Refund>, Error> {
+    let mut stmt = conn.prepare(
+        "SELECT r.*, e.name, e.currency FROM refunds as r LEFT JOIN events as e ON r.event = e.id \
+            WHERE r.state = ?1 ORDER BY r.ts LIMIT ?2 OFFSET ?3",
+    )?;
+    let mut rows = stmt.query(params![RefundState::Requested as u64, limit, offset * limit])?;
+    let mut res = Vec::new();
+    while let Some(row) = rows.next()? {
+        let payment: String = row.get("payment")?;
+        let mut order: OrderInfo = match serde_json::from_str(&payment) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Failed to parse payment {}: {}", payment, e);
+                continue;
+            }
+        };
+        if order.currency.is_empty() {
+            // paid before currencies were recorded
+            order.currency = row.get::<&str, Option<String>>("currency")?.unwrap_or_default();
+        }
+        res.push(Refund {
+            id: row.get("id")?,
+            event_id: row.get("event")?,
+            event_name: row.get::<&str, Option<String>>("name")?.unwrap_or_default(),
+            user_id: row.get("user")?,
+            user_name1: row.get("user_name1")?,
+            user_name2: row.get("user_name2")?,
+            adults: row.get("adults")?,
+            children: row.get("children")?,
+            order,
+            reason: row.get("reason")?,
+        });
+    }
+    Ok(res)
+}
+
+pub fn complete_refund(conn: &PooledConnection<SqliteConnectionManager>, refund_id: u64, ts: u64) -> Result<(), Error> {
+    let res = conn.execute(
+        "UPDATE refunds SET state = ?1, refunded_ts = ?2 WHERE id = ?3 AND state = ?4",
+        params![RefundState::Completed as u64, ts, refund_id, RefundState::Requested as u64],
+    )?;
+    if res == 0 {
+        return Err(Error::NotFound(format!("outstanding refund {}", refund_id)));
+    }
+    Ok(())
+}
+
 pub fn get_pending_messages(
     conn: &PooledConnection<SqliteConnectionManager>,
     ts: u64,
     mut max_messages: u64,
-) -> Result<Vec<MessageBatch>, rusqlite::Error> {
+) -> Result<Vec<MessageBatch>, Error> {
     //debug!("get_pending_messages {}", ts);
+    let sp = Savepoint::new(conn)?;
     let mut stmt = conn.prepare(
-        "SELECT m.*, o.send_at, e.adult_ticket_price, e.child_ticket_price FROM message_outbox as o \
+        "SELECT m.*, o.send_at, e.id as current_event, coalesce(e.adult_ticket_price, 0) as adult_ticket_price, coalesce(e.child_ticket_price, 0) as child_ticket_price FROM message_outbox as o \
         JOIN messages as m ON o.message = m.id \
-        JOIN events as e ON m.event = e.id \
+        LEFT JOIN events as e ON m.event = e.id \
         WHERE o.send_at < ?1",
     )?;
     let mut rows = stmt.query([ts])?;
@@ -758,11 +1665,12 @@ pub fn get_pending_messages(
         let message_type: u64 = row.get("type")?;
         let batch = MessageBatch {
             message_id: row.get("id")?,
-            event_id: row.get("event")?,
+            event_id: row.get::<&str, Option<u64>>("current_event")?.unwrap_or(0),
             sender: row.get("sender")?,
             message_type: num::FromPrimitive::from_u64(message_type).unwrap(),
             waiting_list: row.get("waiting_list")?,
             text: row.get("text")?,
+            document: row.get("document")?,
             is_paid: row.get::<&str, u64>("adult_ticket_price")? != 0 || row.get::<&str, u64>("child_ticket_price")? != 0,
             recipients: Vec::new(),
         };
@@ -777,11 +1685,14 @@ pub fn get_pending_messages(
         }
 
         if collect_users {
+            // Messages with a recipient go to that user only, even if the event is gone.
             let mut stmt = conn.prepare(
-                "SELECT r.user, s.message as sent FROM \
-                        (select user, ts from reservations WHERE event = ?1 AND waiting_list = ?2 GROUP BY user) as r 
+                "SELECT r.user, s.message as sent, l.language FROM \
+                        (select user, ts from reservations WHERE ?5 = 0 AND event = ?1 AND waiting_list = ?2 GROUP BY user
+                        UNION ALL SELECT ?5, 0 WHERE ?5 != 0) as r 
                         LEFT JOIN (select user, message from message_sent where message = ?3) as s 
                         ON r.user = s.user
+                        LEFT JOIN user_settings as l ON r.user = l.user
                         WHERE sent is null ORDER BY r.ts LIMIT ?4"
             )?;
             let mut rows = stmt.query([
@@ -789,13 +1700,19 @@ pub fn get_pending_messages(
                 batch.waiting_list,
                 batch.message_id,
                 max_messages,
+                row.get("recipient")?,
             ])?;
 
             while let Some(row) = rows.next()? {
-                let recipient: u64 = row.get("user")?;
-                batch.recipients.push(recipient);
+                batch.recipients.push(Recipient {
+                    user_id: row.get("user")?,
+                    lang: row
+                        .get::<&str, Option<String>>("language")?
+                        .unwrap_or_else(|| locale::DEFAULT_LANGUAGE.to_string()),
+                });
                 max_messages -= 1;
                 if max_messages == 0 {
+                    sp.commit()?;
                     return Ok(res);
                 }
 
@@ -817,11 +1734,12 @@ pub fn get_pending_messages(
             )?;
         }
     }
+    sp.commit()?;
     Ok(res)
 }
 
 
-fn set_current_event(conn: &PooledConnection<SqliteConnectionManager>, user_id: u64, event_id: u64) -> Result<(), rusqlite::Error> {
+fn set_current_event(conn: &PooledConnection<SqliteConnectionManager>, user_id: u64, event_id: u64) -> Result<(), Error> {
     conn.execute(
         "insert or replace into current_events (user, event) values (?1, ?2)",
         params![user_id, event_id],
@@ -829,7 +1747,7 @@ fn set_current_event(conn: &PooledConnection<SqliteConnectionManager>, user_id:
     Ok(())
 }
 
-pub fn get_current_event(conn: &PooledConnection<SqliteConnectionManager>, user_id: u64) -> Result<u64, rusqlite::Error> {
+pub fn get_current_event(conn: &PooledConnection<SqliteConnectionManager>, user_id: u64) -> Result<u64, Error> {
     let mut stmt = conn
         .prepare("SELECT event FROM current_events WHERE user=?1")?;
     let mut rows = stmt.query([user_id])?;
@@ -847,7 +1765,7 @@ pub fn clear_old_events(
     automatic_blacklisting: bool,
     cancel_future_reservations: bool,
     admins: &HashSet<u64>,
-) -> Result<(), rusqlite::Error> {
+) -> Result<(), Error> {
     let mut stmt = conn.prepare("SELECT id FROM events WHERE ts < ?1")?;
     let mut rows = stmt.query([ts - util::get_seconds_before_midnight(ts)])?;
     while let Some(row) = rows.next()? {
@@ -857,148 +1775,48 @@ pub fn clear_old_events(
     Ok(())
 }
 
-pub fn create(conn: &PooledConnection<SqliteConnectionManager>) -> Result<(), rusqlite::Error> {
-
-    let mut stmt =
-        conn.prepare("SELECT name FROM sqlite_master WHERE type='table' AND name='events'")?;
-    match stmt.query([]) {
-        Ok(rows) => match rows.count() {
-            Ok(count) => {
-                if count == 0 {
-                    conn.execute(
-                        "CREATE TABLE events (
-                            id              INTEGER PRIMARY KEY AUTOINCREMENT,
-                            name            TEXT NOT NULL,
-                            link            TEXT NOT NULL,
-                            max_adults      INTEGER NOT NULL,
-                            max_children    INTEGER NOT NULL,
-                            max_adults_per_reservation   INTEGER NOT NULL,
-                            max_children_per_reservation INTEGER NOT NULL,
-                            ts              INTEGER NOT NULL,
-                            remind          INTEGER NOT NULL,
-                            state           INTEGER default 0,
-                            adult_ticket_price INTEGER default 0,
-                            child_ticket_price INTEGER default 0
-                            )",
-                        [],
-                    )?;
-                    conn.execute(
-                        "CREATE TABLE reservations (
-                            id              INTEGER PRIMARY KEY,
-                            event           INTEGER NOT NULL,
-                            user            INTEGER NOT NULL,
-                            user_name1      TEXT NOT NULL,
-                            user_name2      TEXT NOT NULL,
-                            adults          INTEGER NOT NULL,
-                            children        INTEGER NOT NULL,
-                            waiting_list    INTEGER DEFAULT 0 NOT NULL,
-                            ts              INTEGER NOT NULL,
-                            payment         TEXT DEFAULT NULL,
-                            state           INTEGER default 0
-                            )",
-                        [],
-                    )?;
-                    conn.execute(
-                        "CREATE INDEX reservations_event_index ON reservations (event)",
-                        [],
-                    )?;
-                    conn.execute(
-                        "CREATE INDEX reservations_user_index ON reservations (user)",
-                        [],
-                    )?;
-
-                    conn.execute(
-                        "CREATE TABLE attachments (
-                            event           INTEGER NOT NULL,
-                            user            INTEGER NOT NULL,
-                            attachment      TEXT NOT NULL
-                            )",
-                        [],
-                    )?;
-                    conn.execute(
-                        "CREATE INDEX attachments_event_index ON attachments (event)",
-                        [],
-                    )?;
-                    conn.execute("CREATE UNIQUE INDEX attachments_unique_event_user_idx ON attachments (event, user)", [])?;
-
-                    conn.execute(
-                        "CREATE TABLE black_list (
-                            user            INTEGER PRIMARY KEY,
-                            user_name1      TEXT NOT NULL,
-                            user_name2      TEXT NOT NULL,
-                            ts              INTEGER NOT NULL,
-                            reason          TEXT default ''
-                            )",
-                        [],
-                    )?;
-
-                    conn.execute(
-                        "CREATE TABLE presence (
-                            event           INTEGER NOT NULL,
-                            user            INTEGER NOT NULL
-                            )",
-                        [],
-                    )?;
-                    conn.execute("CREATE INDEX presence_event_index ON presence (event)", [])?;
-                    conn.execute("CREATE UNIQUE INDEX presence_event_user_unique_idx ON presence (event, user)", [])?;
-
-                    conn.execute(
-                        "CREATE TABLE group_leaders (
-                            event           INTEGER NOT NULL,
-                            user            INTEGER NOT NULL
-                            )",
-                        [],
-                    )?;
-                    conn.execute("CREATE UNIQUE INDEX group_leaders_event_user_unique_idx ON presence (event, user)", [])?;
-
-                    conn.execute(
-                        "CREATE TABLE messages (
-                            id              INTEGER PRIMARY KEY AUTOINCREMENT,
-                            event           INTEGER NOT NULL,
-                            type            INTEGER NOT NULL,
-                            sender          text NOT NULL,
-                            waiting_list    INTEGER NOT NULL,
-                            text            text NOT NULL,
-                            ts              INTEGER NOT NULL
-                            )",
-                        [],
-                    )?;
-                    conn.execute("CREATE INDEX messages_event_index ON messages (event)", [])?;
-
-                    conn.execute(
-                        "CREATE TABLE message_outbox (
-                            message         INTEGER NOT NULL,
-                            send_at         INTEGER NOT NULL
-                            )",
-                        [],
-                    )?;
-                    conn.execute(
-                        "CREATE TABLE message_sent (
-                            message         INTEGER NOT NULL,
-                            user            INTEGER NOT NULL,
-                            ts              INTEGER NOT NULL
-                            )",
-                        [],
-                    )?;
-                    conn.execute(
-                        "CREATE TABLE current_events (
-                            user            INTEGER NOT NULL PRIMARY KEY,
-                            event           INTEGER NOT NULL
-                            )",
-                        [],
-                    )?;
-                }
-            }
-            _ => panic!("DB is corrupt."),
-        },
-        _ => {
-            error!("Failed to query db.");
+/// Brings the database schema up to date, refusing to work with databases created by a newer version.
+pub fn create(conn: &PooledConnection<SqliteConnectionManager>) -> anyhow::Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version         INTEGER PRIMARY KEY,
+            ts              INTEGER NOT NULL
+            )",
+        [],
+    )?;
+
+    let tx = conn.unchecked_transaction()?;
+    let mut version: usize = tx.query_row("SELECT ifnull(max(version), 0) FROM schema_version", [], |row| row.get(0))?;
+    if version == 0 {
+        let legacy: u64 = tx.query_row("SELECT count(*) FROM sqlite_master WHERE type='table' AND name='events'", [], |row| row.get(0))?;
+        if legacy > 0 {
+            // Created before versioning was introduced.
+            tx.execute("INSERT INTO schema_version (version, ts) VALUES (1, ?1)", params![get_unix_time()])?;
+            version = 1;
         }
     }
+
+    if version > MIGRATIONS.len() {
+        return Err(anyhow!(
+            "Database schema version {} is newer than supported version {}.",
+            version,
+            MIGRATIONS.len()
+        ));
+    }
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        info!("Applying db migration {}", i + 1);
+        tx.execute_batch(migration)?;
+        tx.execute(
+            "INSERT INTO schema_version (version, ts) VALUES (?1, ?2)",
+            params![i + 1, get_unix_time()],
+        )?;
+    }
+    tx.commit()?;
     Ok(())
 }
 
-pub fn save_receipt(conn: &PooledConnection<SqliteConnectionManager>, message_id: u64, user: u64) -> Result<(), rusqlite::Error> {
+pub fn save_receipt(conn: &PooledConnection<SqliteConnectionManager>, message_id: u64, user: u64) -> Result<(), Error> {
     conn.execute(
         "INSERT INTO message_sent (message, user, ts) VALUES (?1, ?2, ?3)",
         params![message_id, user, util::get_unix_time()],
@@ -1010,7 +1828,7 @@ pub fn add_to_black_list(
     conn: &PooledConnection<SqliteConnectionManager>,
     user: u64,
     cancel_future_reservations: bool,
-) -> Result<(), rusqlite::Error> {
+) -> Result<(), Error> {
     let mut user_name1 = user.to_string();
     let mut user_name2 = "".to_string();
 
@@ -1038,28 +1856,25 @@ pub fn ban_user(
     user_name2: &str,
     reason: &str,
     cancel_future_reservations: bool,
-) -> Result<(), rusqlite::Error> {
+) -> Result<(), Error> {
+    let sp = Savepoint::new(conn)?;
     conn.execute(
         "INSERT INTO black_list (user, user_name1, user_name2, ts, reason) VALUES (?1, ?2, ?3, ?4, ?5)",
         params![user, user_name1, user_name2, util::get_unix_time(), reason],
     )?;
 
     if cancel_future_reservations {
-        if let Err(e) = conn
-            .execute("DELETE FROM reservations where user = ?1", params![user])
-        {
-            warn!("{}", e);
-        }
+        conn.execute("DELETE FROM reservations where user = ?1", params![user])?;
     }
-    Ok(())
+    sp.commit()
 }
 
-pub fn remove_from_black_list(conn: &PooledConnection<SqliteConnectionManager>, user: u64) -> Result<(), rusqlite::Error> {
+pub fn remove_from_black_list(conn: &PooledConnection<SqliteConnectionManager>, user: u64) -> Result<(), Error> {
     conn
         .execute("DELETE FROM black_list WHERE user=?1", params![user])?;
     Ok(())
 }
-pub fn get_black_list(conn: &PooledConnection<SqliteConnectionManager>, offset: u64, limit: u64) -> Result<Vec<User>, rusqlite::Error> {
+pub fn get_black_list(conn: &PooledConnection<SqliteConnectionManager>, offset: u64, limit: u64) -> Result<Vec<User>, Error> {
     let mut stmt = conn
         .prepare("SELECT * FROM black_list order by user_name1 LIMIT ?1 OFFSET ?2")?;
     let mut rows = stmt.query([limit, offset * limit])?;
@@ -1071,12 +1886,13 @@ pub fn get_black_list(conn: &PooledConnection<SqliteConnectionManager>, offset:
             user_name1: row.get(1)?,
             user_name2: row.get(2)?,
             is_admin: false,
+            lang: locale::DEFAULT_LANGUAGE.to_string(),
         });
     }
     Ok(res)
 }
 
-pub fn is_in_black_list(conn: &PooledConnection<SqliteConnectionManager>, user: u64) -> Result<bool, rusqlite::Error> {
+pub fn is_in_black_list(conn: &PooledConnection<SqliteConnectionManager>, user: u64) -> Result<bool, Error> {
     let mut stmt = conn
         .prepare("SELECT * FROM black_list WHERE user = ?1")?;
     let mut rows = stmt.query([user])?;
@@ -1087,19 +1903,72 @@ pub fn is_in_black_list(conn: &PooledConnection<SqliteConnectionManager>, user:
     }
 }
 
-pub fn clear_black_list(conn: &PooledConnection<SqliteConnectionManager>, ts: u64) -> Result<(), rusqlite::Error> {
+pub fn clear_black_list(conn: &PooledConnection<SqliteConnectionManager>, ts: u64) -> Result<(), Error> {
     conn
         .execute("DELETE FROM black_list WHERE ts < ?1", params![ts])?;
     Ok(())
 }
 
-pub fn clear_failed_payments(conn: &PooledConnection<SqliteConnectionManager>, ts: u64) -> Result<(), rusqlite::Error> {
-    conn
-        .execute("DELETE FROM reservations WHERE state = ?1 AND ts < ?2", params![ReservationState::PaymentPending as u64, ts])?;
-    Ok(())
+/// Releases seats that weren't paid within `hold` seconds, tells their users and prompts the
+/// waiting list.
+pub fn clear_failed_payments(conn: &PooledConnection<SqliteConnectionManager>, ts: u64, hold: u64) -> Result<usize, Error> {
+    let sp = Savepoint::new(conn)?;
+    let mut stmt = conn.prepare(
+        "SELECT r.event, r.user, e.name, e.ts, e.tz FROM reservations as r JOIN events as e ON r.event = e.id \
+        WHERE r.state = ?1 AND r.ts < ?2 AND r.accept_until = 0 GROUP BY r.event, r.user",
+    )?;
+    let expired = stmt
+        .query_map(params![ReservationState::PaymentPending as u64, ts - hold], |row| {
+            Ok((row.get("event")?, row.get("user")?, row.get("name")?, row.get("ts")?, row.get("tz")?))
+        })?
+        .collect::<Result<Vec<(u64, u64, String, u64, String)>, rusqlite::Error>>()?;
+    let mut released = 0;
+    for (event_id, user, name, start, tz) in expired {
+        let state_changed = !have_vacancies(conn, event_id)?;
+        released += conn.execute(
+            "DELETE FROM reservations WHERE event = ?1 AND user = ?2 AND state = ?3 AND ts < ?4 AND accept_until = 0",
+            params![event_id, user, ReservationState::PaymentPending as u64, ts - hold],
+        )?;
+        if state_changed {
+            prompt_waiting_list(conn, event_id)?;
+        }
+        let text = Template::new("hold_expired", &[("name", name)])
+            .time("start", start, &tz)
+            .to_text();
+        enqueue_notice(conn, event_id, user, MessageType::Release, &text, "", ts)?;
+    }
+    sp.commit()?;
+    Ok(released)
+}
+
+/// Releases the user's unpaid seats of the event, e.g. when a new invoice replaces the old one.
+/// Seats offered from the waiting list stay until their deadline.
+pub fn release_hold(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64, user: u64) -> Result<usize, Error> {
+    Ok(conn.execute(
+        "DELETE FROM reservations WHERE event = ?1 AND user = ?2 AND state = ?3 AND accept_until = 0",
+        params![event_id, user, ReservationState::PaymentPending as u64],
+    )?)
 }
 
-pub fn change_event_state(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64, state: u64) -> Result<(), rusqlite::Error> {
+/// Restarts the hold of the booked seats when the user confirms the payment, so it can't expire
+/// while the payment is processed. Returns the discount of the hold, None if it has expired.
+pub fn renew_hold(conn: &PooledConnection<SqliteConnectionManager>, booking: &Booking, ts: u64) -> Result<Option<u64>, Error> {
+    let mut stmt = conn.prepare(
+        "SELECT id, discount FROM reservations WHERE event = ?1 AND user = ?2 AND state = ?3 \
+        AND adults = ?4 AND children = ?5 AND tier = ?6 AND promo_code = ?7 LIMIT 1",
+    )?;
+    let mut rows = stmt.query(params![booking.event_id, booking.user_id, ReservationState::PaymentPending as u64, booking.adults, booking.children, booking.tier, booking.promo_code])?;
+    match rows.next()? {
+        Some(row) => {
+            let id: u64 = row.get("id")?;
+            conn.execute("UPDATE reservations SET ts = ?1, accept_until = 0 WHERE id = ?2", params![ts, id])?;
+            Ok(Some(row.get("discount")?))
+        }
+        None => Ok(None),
+    }
+}
+
+pub fn change_event_state(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64, state: u64) -> Result<(), Error> {
     conn.execute(
         "UPDATE events SET state = ?1 WHERE id = ?2",
         params![state, event_id],
@@ -1112,7 +1981,7 @@ pub fn set_event_limits(
     event_id: u64,
     max_adults: u64,
     max_children: u64,
-) -> Result<(), rusqlite::Error> {
+) -> Result<(), Error> {
     let state_changed = have_vacancies(conn, event_id)? == false;
     conn.execute(
         "UPDATE events SET max_adults = ?1, max_children = ?2 WHERE id = ?3",
@@ -1129,7 +1998,7 @@ pub fn get_group_messages(
     conn: &PooledConnection<SqliteConnectionManager>,
     event_id: u64,
     waiting_list: Option<u64>,
-) -> Result<Vec<GroupMessage>, rusqlite::Error> {
+) -> Result<Vec<GroupMessage>, Error> {
     let mut stmt;
     let mut rows = if let Some(waiting_list) = waiting_list {
         stmt = conn.prepare(
@@ -1160,3 +2029,76 @@ pub fn get_group_messages(
     Ok(messages)
 }
 
+/// Returns the language of the user, remembering the given one on first contact.
+pub fn init_language(conn: &PooledConnection<SqliteConnectionManager>, user: u64, lang: &str) -> Result<String, Error> {
+    conn.execute(
+        "INSERT OR IGNORE INTO user_settings (user, language) VALUES (?1, ?2)",
+        params![user, lang],
+    )?;
+    Ok(conn.query_row(
+        "SELECT language FROM user_settings WHERE user = ?1",
+        params![user],
+        |row| row.get(0),
+    )?)
+}
+
+/// Token of the user's calendar feed. `token` is stored if the user doesn't have one yet.
+pub fn get_calendar_token(conn: &PooledConnection<SqliteConnectionManager>, user: u64, token: &str) -> Result<String, Error> {
+    conn.execute(
+        "INSERT OR IGNORE INTO calendar_tokens (user, token) VALUES (?1, ?2)",
+        params![user, token],
+    )?;
+    Ok(conn.query_row(
+        "SELECT token FROM calendar_tokens WHERE user = ?1",
+        params![user],
+        |row| row.get(0),
+    )?)
+}
+
+/// Secret of the given name. `value` is stored if there is none yet.
+pub fn get_secret(conn: &PooledConnection<SqliteConnectionManager>, name: &str, value: &str) -> Result<String, Error> {
+    conn.execute(
+        "INSERT OR IGNORE INTO secrets (name, value) VALUES (?1, ?2)",
+        params![name, value],
+    )?;
+    Ok(conn.query_row(
+        "SELECT value FROM secrets WHERE name = ?1",
+        params![name],
+        |row| row.get(0),
+    )?)
+}
+
+/// Turns the check-in mode of the admin on or off. In the mode their messages are tickets.
+pub fn set_checkin_mode(conn: &PooledConnection<SqliteConnectionManager>, user: u64, on: bool) -> Result<(), Error> {
+    conn.execute(
+        "UPDATE user_settings SET checkin = ?1 WHERE user = ?2",
+        params![on, user],
+    )?;
+    Ok(())
+}
+
+pub fn is_checkin_mode(conn: &PooledConnection<SqliteConnectionManager>, user: u64) -> Result<bool, Error> {
+    let mut stmt = conn.prepare("SELECT checkin FROM user_settings WHERE user = ?1")?;
+    let mut rows = stmt.query([user])?;
+    match rows.next()? {
+        Some(row) => Ok(row.get(0)?),
+        None => Ok(false),
+    }
+}
+
+pub fn get_calendar_user(conn: &PooledConnection<SqliteConnectionManager>, token: &str) -> Result<Option<u64>, Error> {
+    let mut stmt = conn.prepare("SELECT user FROM calendar_tokens WHERE token = ?1")?;
+    let mut rows = stmt.query([token])?;
+    match rows.next()? {
+        Some(row) => Ok(Some(row.get(0)?)),
+        None => Ok(None),
+    }
+}
+
+pub fn set_language(conn: &PooledConnection<SqliteConnectionManager>, user: u64, lang: &str) -> Result<(), Error> {
+    conn.execute(
+        "INSERT INTO user_settings (user, language) VALUES (?1, ?2) ON CONFLICT (user) DO UPDATE SET language = excluded.language",
+        params![user, lang],
+    )?;
+    Ok(())
+}
@@ -0,0 +1,36 @@
+use std::fmt;
+
+/// Database layer error.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    Sqlite(rusqlite::Error),
+    /// The requested event, series or refund doesn't exist.
+    NotFound(String),
+    /// The request was rejected before anything was written.
+    Invalid(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Sqlite(e) => write!(f, "{}", e),
+            Error::NotFound(what) => write!(f, "Failed to find {}", what),
+            Error::Invalid(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Sqlite(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Error::Sqlite(e)
+    }
+}
@@ -0,0 +1,203 @@
+//! Database schema migrations.
+//!
+//! Migrations are applied in order at startup, each one exactly once. The number of applied
+//! migrations is the schema version. Never edit a migration that has been released, append a
+//! new one instead.
+
+pub const MIGRATIONS: &[&str] = &[
+    // 1: initial schema, as created by db::create before versioning was introduced.
+    "CREATE TABLE events (
+        id              INTEGER PRIMARY KEY AUTOINCREMENT,
+        name            TEXT NOT NULL,
+        link            TEXT NOT NULL,
+        max_adults      INTEGER NOT NULL,
+        max_children    INTEGER NOT NULL,
+        max_adults_per_reservation   INTEGER NOT NULL,
+        max_children_per_reservation INTEGER NOT NULL,
+        ts              INTEGER NOT NULL,
+        remind          INTEGER NOT NULL,
+        state           INTEGER default 0,
+        adult_ticket_price INTEGER default 0,
+        child_ticket_price INTEGER default 0
+    );
+    CREATE TABLE reservations (
+        id              INTEGER PRIMARY KEY,
+        event           INTEGER NOT NULL,
+        user            INTEGER NOT NULL,
+        user_name1      TEXT NOT NULL,
+        user_name2      TEXT NOT NULL,
+        adults          INTEGER NOT NULL,
+        children        INTEGER NOT NULL,
+        waiting_list    INTEGER DEFAULT 0 NOT NULL,
+        ts              INTEGER NOT NULL,
+        payment         TEXT DEFAULT NULL,
+        state           INTEGER default 0
+    );
+    CREATE INDEX reservations_event_index ON reservations (event);
+    CREATE INDEX reservations_user_index ON reservations (user);
+    CREATE TABLE attachments (
+        event           INTEGER NOT NULL,
+        user            INTEGER NOT NULL,
+        attachment      TEXT NOT NULL
+    );
+    CREATE INDEX attachments_event_index ON attachments (event);
+    CREATE UNIQUE INDEX attachments_unique_event_user_idx ON attachments (event, user);
+    CREATE TABLE black_list (
+        user            INTEGER PRIMARY KEY,
+        user_name1      TEXT NOT NULL,
+        user_name2      TEXT NOT NULL,
+        ts              INTEGER NOT NULL,
+        reason          TEXT default ''
+    );
+    CREATE TABLE presence (
+        event           INTEGER NOT NULL,
+        user            INTEGER NOT NULL
+    );
+    CREATE INDEX presence_event_index ON presence (event);
+    CREATE UNIQUE INDEX presence_event_user_unique_idx ON presence (event, user);
+    CREATE TABLE group_leaders (
+        event           INTEGER NOT NULL,
+        user            INTEGER NOT NULL
+    );
+    CREATE UNIQUE INDEX group_leaders_event_user_unique_idx ON presence (event, user);
+    CREATE TABLE messages (
+        id              INTEGER PRIMARY KEY AUTOINCREMENT,
+        event           INTEGER NOT NULL,
+        type            INTEGER NOT NULL,
+        sender          text NOT NULL,
+        waiting_list    INTEGER NOT NULL,
+        text            text NOT NULL,
+        ts              INTEGER NOT NULL
+    );
+    CREATE INDEX messages_event_index ON messages (event);
+    CREATE TABLE message_outbox (
+        message         INTEGER NOT NULL,
+        send_at         INTEGER NOT NULL
+    );
+    CREATE TABLE message_sent (
+        message         INTEGER NOT NULL,
+        user            INTEGER NOT NULL,
+        ts              INTEGER NOT NULL
+    );
+    CREATE TABLE current_events (
+        user            INTEGER NOT NULL PRIMARY KEY,
+        event           INTEGER NOT NULL
+    );",
+    // 2: group_leaders_event_user_unique_idx was created on presence instead of group_leaders.
+    "DROP INDEX IF EXISTS group_leaders_event_user_unique_idx;
+    DELETE FROM group_leaders WHERE rowid NOT IN (SELECT min(rowid) FROM group_leaders GROUP BY event, user);
+    CREATE UNIQUE INDEX group_leaders_event_user_unique_idx ON group_leaders (event, user);",
+    // 3: recurring event series.
+    "CREATE TABLE event_series (
+        id              INTEGER PRIMARY KEY AUTOINCREMENT,
+        name            TEXT NOT NULL,
+        link            TEXT NOT NULL,
+        max_adults      INTEGER NOT NULL,
+        max_children    INTEGER NOT NULL,
+        max_adults_per_reservation   INTEGER NOT NULL,
+        max_children_per_reservation INTEGER NOT NULL,
+        ts              INTEGER NOT NULL,
+        remind          INTEGER NOT NULL,
+        adult_ticket_price INTEGER default 0,
+        child_ticket_price INTEGER default 0,
+        rule            INTEGER NOT NULL,
+        until           INTEGER default 0,
+        count           INTEGER default 0,
+        skip            TEXT default '',
+        generated_until INTEGER default 0
+    );
+    ALTER TABLE events ADD COLUMN series INTEGER default 0;
+    CREATE INDEX events_series_index ON events (series);",
+    // 4: per-user settings.
+    "CREATE TABLE user_settings (
+        user            INTEGER PRIMARY KEY,
+        language        TEXT NOT NULL
+    );",
+    // 5: event time zones. Empty for events shown in the server time zone.
+    "ALTER TABLE events ADD COLUMN tz TEXT NOT NULL default '';
+    ALTER TABLE event_series ADD COLUMN tz TEXT NOT NULL default '';",
+    // 6: ticket price currency.
+    "ALTER TABLE events ADD COLUMN currency TEXT NOT NULL default 'EUR';
+    ALTER TABLE event_series ADD COLUMN currency TEXT NOT NULL default 'EUR';",
+    // 7: refunds of paid reservations.
+    "CREATE TABLE refunds (
+        id              INTEGER PRIMARY KEY AUTOINCREMENT,
+        event           INTEGER NOT NULL,
+        user            INTEGER NOT NULL,
+        user_name1      TEXT NOT NULL,
+        user_name2      TEXT NOT NULL,
+        adults          INTEGER NOT NULL,
+        children        INTEGER NOT NULL,
+        payment         TEXT NOT NULL,
+        reason          TEXT default '',
+        state           INTEGER default 0,
+        ts              INTEGER NOT NULL,
+        refunded_ts     INTEGER default 0
+    );
+    CREATE INDEX refunds_state_index ON refunds (state);",
+    // 8: donations ledger.
+    "CREATE TABLE donations (
+        id              INTEGER PRIMARY KEY AUTOINCREMENT,
+        charge_id       TEXT NOT NULL,
+        user            INTEGER NOT NULL,
+        amount          INTEGER NOT NULL,
+        currency        TEXT NOT NULL,
+        ts              INTEGER NOT NULL
+    );
+    CREATE UNIQUE INDEX donations_charge_id_unique_idx ON donations (charge_id);",
+    // 9: secret tokens of personal calendar feeds.
+    "CREATE TABLE calendar_tokens (
+        user            INTEGER PRIMARY KEY,
+        token           TEXT NOT NULL
+    );
+    CREATE UNIQUE INDEX calendar_tokens_token_unique_idx ON calendar_tokens (token);",
+    // 10: .ics invitations. Messages with a recipient go to that user only, a document is sent
+    // as an .ics file with the text as caption.
+    "ALTER TABLE events ADD COLUMN calendar_invite INTEGER NOT NULL default 0;
+    ALTER TABLE event_series ADD COLUMN calendar_invite INTEGER NOT NULL default 0;
+    ALTER TABLE messages ADD COLUMN recipient INTEGER NOT NULL default 0;
+    ALTER TABLE messages ADD COLUMN document TEXT NOT NULL default '';",
+    // 11: reminder offsets in seconds before the start, comma separated.
+    "ALTER TABLE events ADD COLUMN reminders TEXT NOT NULL default '';
+    ALTER TABLE event_series ADD COLUMN reminders TEXT NOT NULL default '';",
+    // 12: attendance confirmation. When the reminder asked the user and whether they confirmed.
+    "ALTER TABLE reservations ADD COLUMN reminded INTEGER NOT NULL default 0;
+    ALTER TABLE reservations ADD COLUMN confirmed INTEGER NOT NULL default 0;",
+    // 13: automatic waiting list promotion. Hours to accept a promoted seat, 0 if disabled, and the
+    // deadline of the promoted reservation.
+    "ALTER TABLE events ADD COLUMN auto_promote_hours INTEGER NOT NULL default 0;
+    ALTER TABLE event_series ADD COLUMN auto_promote_hours INTEGER NOT NULL default 0;
+    ALTER TABLE reservations ADD COLUMN accept_until INTEGER NOT NULL default 0;",
+    // 14: price tiers of paid events as a JSON array, and the tier a reservation was sold at, 0 for
+    // the regular price.
+    "ALTER TABLE events ADD COLUMN price_tiers TEXT NOT NULL default '';
+    ALTER TABLE event_series ADD COLUMN price_tiers TEXT NOT NULL default '';
+    ALTER TABLE reservations ADD COLUMN tier INTEGER NOT NULL default 0;",
+    // 15: promo codes with the number of paid orders that used them, the code a user applied to an
+    // event, and the code and discount of a reservation.
+    "CREATE TABLE promo_codes (
+        code            TEXT PRIMARY KEY,
+        percent         INTEGER NOT NULL default 0,
+        amount          INTEGER NOT NULL default 0,
+        max_uses        INTEGER NOT NULL default 0,
+        valid_from      INTEGER NOT NULL default 0,
+        valid_until     INTEGER NOT NULL default 0,
+        event           INTEGER NOT NULL default 0,
+        uses            INTEGER NOT NULL default 0
+    );
+    CREATE TABLE applied_promo_codes (
+        user            INTEGER NOT NULL,
+        event           INTEGER NOT NULL,
+        code            TEXT NOT NULL,
+        PRIMARY KEY (user, event)
+    );
+    ALTER TABLE reservations ADD COLUMN promo_code TEXT NOT NULL default '';
+    ALTER TABLE reservations ADD COLUMN discount INTEGER NOT NULL default 0;",
+    // 16: secrets of the bot, e.g. the key tickets are signed with, and whether an admin is checking
+    // tickets in.
+    "CREATE TABLE secrets (
+        name            TEXT PRIMARY KEY,
+        value           TEXT NOT NULL
+    );
+    ALTER TABLE user_settings ADD COLUMN checkin INTEGER NOT NULL default 0;",
+];
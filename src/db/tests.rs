@@ -5,7 +5,7 @@ mod tests {
     use teloxide::types::UserId;
 
     #[test]
-    fn test_db() -> Result<(), rusqlite::Error> {
+    fn test_db() -> Result<(), Error> {
         let db_file = "./test.db3";
         let _ = std::fs::remove_file(db_file);
         let manager = SqliteConnectionManager::file(db_file);
@@ -27,6 +27,12 @@ mod tests {
             remind: ts - 10,
             adult_ticket_price: 0,
             child_ticket_price: 0,
+            tz: "Europe/Vienna".to_string(),
+            currency: "EUR".to_string(),
+            calendar_invite: false,
+            reminders: vec![],
+            auto_promote_hours: 0,
+            price_tiers: vec![],
         };
         let event_id = 1;
 
@@ -43,7 +49,8 @@ mod tests {
                         id: UserId(1000),
                         user_name1: "user_name1_1000".to_string(),
                         user_name2: "user_name2_1000".to_string(),
-                        is_admin: false
+                        is_admin: false,
+                        lang: "ru".to_string(),
                     },
                     0,
                     1,
@@ -53,6 +60,8 @@ mod tests {
                     },
                     e.ts - 20,
                     0,
+                    0,
+                    "",
                 )
                 .unwrap(),
                 (1, false)
@@ -72,13 +81,16 @@ mod tests {
                     id: UserId(2000),
                     user_name1: "user_name1_2000".to_string(),
                     user_name2: "user_name1_2000".to_string(),
-                    is_admin: false
+                    is_admin: false,
+                    lang: "ru".to_string(),
                 },
                 0,
                 1,
                 1,
                 e.ts - 20,
                 0,
+                0,
+                "",
             )
             .unwrap(),
             (1, false)
@@ -107,7 +119,7 @@ mod tests {
 
     #[test]
     #[ignore]
-    fn test_waiting_list() -> Result<(), rusqlite::Error> {
+    fn test_waiting_list() -> Result<(), Error> {
         let db_file = "./test1.db3";
         let _ = std::fs::remove_file(db_file);
         let manager = SqliteConnectionManager::file(db_file);
@@ -129,6 +141,12 @@ mod tests {
             remind: ts - 10,
             adult_ticket_price: 0,
             child_ticket_price: 0,
+            tz: "Europe/Vienna".to_string(),
+            currency: "EUR".to_string(),
+            calendar_invite: false,
+            reminders: vec![],
+            auto_promote_hours: 0,
+            price_tiers: vec![],
         };
         let event_id = 1;
 
@@ -144,13 +162,16 @@ mod tests {
                     id: UserId(10),
                     user_name1: "".to_string(),
                     user_name2: "".to_string(),
-                    is_admin: false
+                    is_admin: false,
+                    lang: "ru".to_string(),
                 },
                 1,
                 0,
                 0,
                 e.ts - 30,
                 0,
+                0,
+                "",
             )
             .unwrap(),
             (1, false)
@@ -165,13 +186,16 @@ mod tests {
                     id: UserId(20),
                     user_name1: "".to_string(),
                     user_name2: "".to_string(),
-                    is_admin: false
+                    is_admin: false,
+                    lang: "ru".to_string(),
                 },
                 1,
                 0,
                 1,
                 e.ts - 20,
                 0,
+                0,
+                "",
             )
             .unwrap(),
             (1, false)
@@ -184,13 +208,16 @@ mod tests {
                     id: UserId(30),
                     user_name1: "".to_string(),
                     user_name2: "".to_string(),
-                    is_admin: false
+                    is_admin: false,
+                    lang: "ru".to_string(),
                 },
                 1,
                 0,
                 1,
                 e.ts - 10,
                 0,
+                0,
+                "",
             )
             .unwrap(),
             (1, false)
@@ -245,4 +272,1308 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_migrations() -> Result<(), Error> {
+        let db_file = "./test2.db3";
+        let _ = std::fs::remove_file(db_file);
+        let manager = SqliteConnectionManager::file(db_file);
+        let pool = r2d2::Pool::new(manager).unwrap();
+        let conn = pool.get().unwrap();
+
+        // legacy database created before versioning
+        conn.execute_batch(crate::db::migrations::MIGRATIONS[0])?;
+        conn.execute_batch(
+            "INSERT INTO group_leaders (event, user) VALUES (1, 10); \
+            INSERT INTO group_leaders (event, user) VALUES (1, 10);",
+        )?;
+        create(&conn).expect("Failed to migrate db.");
+
+        let version: usize =
+            conn.query_row("SELECT max(version) FROM schema_version", [], |row| row.get(0))?;
+        assert_eq!(version, crate::db::migrations::MIGRATIONS.len());
+        let table: String = conn.query_row(
+            "SELECT tbl_name FROM sqlite_master WHERE type = 'index' AND name = 'group_leaders_event_user_unique_idx'",
+            [],
+            |row| row.get(0),
+        )?;
+        assert_eq!(table, "group_leaders");
+        assert!(is_group_leader(&conn, 1, 10)?);
+        assert!(set_group_leader(&conn, 1, 10).is_err());
+
+        // migrations are applied once
+        create(&conn).expect("Failed to reopen db.");
+
+        // database from a newer version is rejected
+        conn.execute(
+            "INSERT INTO schema_version (version, ts) VALUES (?1, 0)",
+            params![version + 1],
+        )?;
+        assert!(create(&conn).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_series() -> Result<(), Error> {
+        let db_file = "./test3.db3";
+        let _ = std::fs::remove_file(db_file);
+        let manager = SqliteConnectionManager::file(db_file);
+        let pool = r2d2::Pool::new(manager).unwrap();
+        let conn = pool.get().unwrap();
+        create(&conn).expect("Failed to create db.");
+
+        let ts = 1650445814;
+        let week = 7 * 24 * 60 * 60;
+        let mut series = EventSeries {
+            id: 0,
+            event: Event {
+                id: 0,
+                name: "weekly".to_string(),
+                link: "https://example.com/1".to_string(),
+                max_adults: 2,
+                max_children: 2,
+                max_adults_per_reservation: 1,
+                max_children_per_reservation: 1,
+                ts,
+                remind: ts - 10,
+                adult_ticket_price: 0,
+                child_ticket_price: 0,
+                tz: "Europe/Vienna".to_string(),
+                currency: "EUR".to_string(),
+                calendar_invite: false,
+                reminders: vec![],
+                auto_promote_hours: 0,
+                price_tiers: vec![],
+            },
+            rule: RepeatRule::Weekly,
+            until: 0,
+            count: 3,
+            skip: vec![chrono::NaiveDate::from_ymd_opt(2022, 4, 27).unwrap()],
+        };
+        series.id = add_series(&conn, &series, false, ts - 1)?;
+
+        // skipped dates don't count
+        let events = generate_series_events(&conn, ts - 1, ts + 10 * week)?;
+        assert_eq!(events.len(), 3);
+        let starts: Vec<u64> = events
+            .iter()
+            .map(|id| get_event(&conn, *id, 0).unwrap().event.ts)
+            .collect();
+        assert_eq!(starts, vec![ts, ts + 2 * week, ts + 3 * week]);
+
+        // already generated
+        assert_eq!(generate_series_events(&conn, ts - 1, ts + 10 * week)?.len(), 0);
+
+        // booked occurrences survive an update of the future ones
+        sign_up(
+            &conn,
+            events[0],
+            &User {
+                id: UserId(10),
+                user_name1: "".to_string(),
+                user_name2: "".to_string(),
+                is_admin: false,
+                lang: "ru".to_string(),
+            },
+            1,
+            0,
+            0,
+            ts - 20,
+            0,
+            0,
+            "",
+        )
+        .unwrap();
+        series.event.name = "renamed".to_string();
+        add_series(&conn, &series, true, ts - 1)?;
+        assert_eq!(get_event(&conn, events[0], 0)?.event.name, "weekly");
+        assert!(get_event(&conn, events[1], 0).is_err());
+        let regenerated = generate_series_events(&conn, ts - 1, ts + 10 * week)?;
+        assert_eq!(regenerated.len(), 2);
+        assert_eq!(get_event(&conn, regenerated[0], 0)?.event.name, "renamed");
+
+        delete_series(&conn, series.id, ts - 1)?;
+        assert!(get_event(&conn, events[0], 0).is_ok());
+        assert!(get_event(&conn, regenerated[0], 0).is_err());
+        assert_eq!(get_series_list(&conn)?.len(), 0);
+
+        // wall clock time is kept across the DST change
+        series.event.ts = 1647784800; // 20.03.2022 15:00 CET
+        series.skip.clear();
+        assert_eq!(
+            series.occurrences(0, 1647784800 + week),
+            vec![1647784800, 1647784800 + week - 60 * 60]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_checkout() -> anyhow::Result<()> {
+        let db_file = "./test4.db3";
+        let _ = std::fs::remove_file(db_file);
+        let manager = SqliteConnectionManager::file(db_file);
+        let pool = r2d2::Pool::new(manager).unwrap();
+        let conn = pool.get().unwrap();
+        create(&conn).expect("Failed to create db.");
+
+        let ts = 1650445814;
+        let e = Event {
+            id: 0,
+            name: "paid event".to_string(),
+            link: "https://example.com/1".to_string(),
+            max_adults: 2,
+            max_children: 2,
+            max_adults_per_reservation: 2,
+            max_children_per_reservation: 2,
+            ts,
+            remind: ts - 10,
+            adult_ticket_price: 1000,
+            child_ticket_price: 500,
+            tz: "Europe/Vienna".to_string(),
+            currency: "CHF".to_string(),
+            calendar_invite: false,
+            reminders: vec![],
+            auto_promote_hours: 0,
+            price_tiers: vec![],
+        };
+        let event_id = add_event(&conn, e.clone())?;
+        let user = User {
+            id: UserId(10),
+            user_name1: "".to_string(),
+            user_name2: "".to_string(),
+            is_admin: false,
+            lang: "ru".to_string(),
+        };
+        let booking = Booking {
+            event_id,
+            adults: 1,
+            children: 1,
+            user_id: 10,
+            tier: 0,
+            promo_code: String::new(),
+        };
+
+        // pre checkout
+        assert!(sign_up(&conn, event_id, &user, 1, 1, 0, ts - 20, 1000, 0, "").is_err());
+        sign_up(&conn, event_id, &user, 1, 1, 0, ts - 20, 1500, 0, "")?;
+
+        let order = |amount: u64, currency: &str| OrderInfo {
+            id: "charge".to_string(),
+            name: "name".to_string(),
+            amount,
+            currency: currency.to_string(),
+            ..Default::default()
+        };
+        assert!(checkout(&conn, &booking, order(1500, "EUR")).is_err());
+        assert!(checkout(&conn, &booking, order(1000, "CHF")).is_err());
+        checkout(&conn, &booking, order(1500, "CHF"))?;
+        assert_eq!(get_event(&conn, event_id, 10)?.adults.my_reservation, 1);
+
+        // refund releases the seats and keeps the payment
+        assert_eq!(request_refund(&conn, event_id, 20, "", ts)?, 0);
+        assert_eq!(request_refund(&conn, event_id, 10, "cancelled", ts)?, 1);
+        let s = get_event(&conn, event_id, 10)?;
+        assert_eq!(s.adults.reserved + s.children.reserved, 0);
+        let refunds = get_outstanding_refunds(&conn, 0, 10)?;
+        assert_eq!(refunds.len(), 1);
+        assert_eq!(refunds[0].order.amount, 1500);
+        assert_eq!(refunds[0].order.currency, "CHF");
+        assert_eq!(refunds[0].reason, "cancelled");
+        complete_refund(&conn, refunds[0].id, ts)?;
+        assert!(complete_refund(&conn, refunds[0].id, ts).is_err());
+
+        sign_up(&conn, event_id, &user, 1, 0, 0, ts - 20, 1000, 0, "")?;
+        let payments = get_payments(&conn, event_id)?;
+        assert_eq!(
+            payments.iter().map(|p| p.status).collect::<Vec<PaymentStatus>>(),
+            vec![PaymentStatus::Pending, PaymentStatus::Refunded]
+        );
+        assert_eq!(payments[1].order.as_ref().unwrap().id, "charge");
+        assert_eq!(get_outstanding_refunds(&conn, 0, 10)?.len(), 0);
+
+        // clearing the past event doesn't refund its payments
+        let other = User { id: UserId(20), ..user.clone() };
+        sign_up(&conn, event_id, &other, 1, 0, 0, ts - 20, 1000, 0, "")?;
+        let other_booking = Booking { user_id: 20, children: 0, ..booking.clone() };
+        checkout(&conn, &other_booking, OrderInfo { id: "charge3".to_string(), ..order(1000, "CHF") })?;
+        assert_eq!(get_payments(&conn, event_id)?[1].status, PaymentStatus::Completed);
+        let now = get_unix_time();
+        clear_old_events(&conn, now, false, false, &HashSet::new())?;
+        assert!(get_event(&conn, event_id, 0).is_err());
+        assert_eq!(get_outstanding_refunds(&conn, 0, 10)?.len(), 0);
+
+        // cancelling an event before the start keeps the completed payments for refund
+        let event_id = add_event(&conn, Event { name: "future paid event".to_string(), ts: now + 24 * 60 * 60, ..e })?;
+        sign_up(&conn, event_id, &other, 1, 0, 0, now, 1000, 0, "")?;
+        let other_booking = Booking { event_id, ..other_booking };
+        checkout(&conn, &other_booking, OrderInfo { id: "charge3".to_string(), ..order(1000, "CHF") })?;
+        delete_event(&conn, event_id, false, false, &HashSet::new())?;
+        let refunds = get_outstanding_refunds(&conn, 0, 10)?;
+        assert_eq!(refunds.len(), 1);
+        assert_eq!((refunds[0].user_id, refunds[0].order.amount), (20, 1000));
+        assert_eq!(refunds[0].order.id, "charge3");
+        assert_eq!(refunds[0].reason, "event deleted");
+
+        // donations
+        add_donation(&conn, 10, &order(500, "EUR"), ts)?;
+        assert!(add_donation(&conn, 10, &order(500, "EUR"), ts).is_err());
+        add_donation(&conn, 20, &OrderInfo { id: "charge2".to_string(), ..order(300, "EUR") }, ts)?;
+        assert_eq!(
+            get_donation_totals(&conn)?,
+            vec![("2022-04".to_string(), "EUR".to_string(), 2, 800)]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parallel_sign_up() -> anyhow::Result<()> {
+        let db_file = "./test5.db3";
+        let _ = std::fs::remove_file(db_file);
+        let manager = SqliteConnectionManager::file(db_file).with_init(|c| {
+            c.busy_timeout(std::time::Duration::from_secs(30))?;
+            c.execute_batch("PRAGMA journal_mode = WAL;")
+        });
+        let pool = r2d2::Pool::builder().max_size(16).build(manager).unwrap();
+        create(&pool.get()?)?;
+
+        let ts = 1650445814;
+        let mut events = Vec::new();
+        for i in 0..2 {
+            events.push(add_event(
+                &pool.get()?,
+                Event {
+                    id: 0,
+                    name: format!("event {}", i),
+                    link: "https://example.com/1".to_string(),
+                    max_adults: 5,
+                    max_children: 0,
+                    max_adults_per_reservation: 1,
+                    max_children_per_reservation: 0,
+                    ts,
+                    remind: ts - 10,
+                    adult_ticket_price: 0,
+                    child_ticket_price: 0,
+                    tz: "Europe/Vienna".to_string(),
+                    currency: "EUR".to_string(),
+                    calendar_invite: false,
+                    reminders: vec![],
+                    auto_promote_hours: 0,
+                    price_tiers: vec![],
+                },
+            )?);
+        }
+
+        // many users compete for the same seats of two events
+        std::thread::scope(|scope| {
+            for i in 0..40u64 {
+                let pool = &pool;
+                let event_id = events[i as usize % 2];
+                scope.spawn(move || {
+                    let user = User {
+                        id: UserId(100 + i),
+                        user_name1: "".to_string(),
+                        user_name2: "".to_string(),
+                        is_admin: false,
+                        lang: "ru".to_string(),
+                    };
+                    let _ = sign_up(&pool.get().unwrap(), event_id, &user, 1, 0, 0, ts - 20, 0, 0, "");
+                });
+            }
+        });
+
+        for event_id in events {
+            let s = get_event(&pool.get()?, event_id, 0)?;
+            assert_eq!(s.adults.reserved, 5);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rollback() -> Result<(), Error> {
+        let db_file = "./test6.db3";
+        let _ = std::fs::remove_file(db_file);
+        let manager = SqliteConnectionManager::file(db_file);
+        let pool = r2d2::Pool::new(manager).unwrap();
+        let conn = pool.get().unwrap();
+        create(&conn).expect("Failed to create db.");
+
+        // makes every statement of the given kind on the table fail
+        let inject_failure = |event: &str, table: &str| {
+            conn.execute_batch(&format!(
+                "CREATE TEMP TRIGGER fail_{1} BEFORE {0} ON {1} BEGIN SELECT RAISE(ABORT, 'injected'); END;",
+                event, table
+            ))
+        };
+        let clear_failure = |table: &str| {
+            conn.execute_batch(&format!("DROP TRIGGER temp.fail_{}", table))
+        };
+        let count = |query: &str| -> u64 { conn.query_row(query, [], |row| row.get(0)).unwrap() };
+
+        let ts = 1650445814;
+        let event_id = add_event(
+            &conn,
+            Event {
+                id: 0,
+                name: "test event".to_string(),
+                link: "https://example.com/1".to_string(),
+                max_adults: 10,
+                max_children: 0,
+                max_adults_per_reservation: 1,
+                max_children_per_reservation: 0,
+                ts,
+                remind: ts - 10,
+                adult_ticket_price: 0,
+                child_ticket_price: 0,
+                tz: "Europe/Vienna".to_string(),
+                currency: "EUR".to_string(),
+                calendar_invite: false,
+                reminders: vec![],
+                auto_promote_hours: 0,
+                price_tiers: vec![],
+            },
+        )?;
+        assert_eq!(get_event(&conn, 99, 0).err(), Some(Error::NotFound("event 99".to_string())));
+        for i in 1..=2 {
+            let user = User {
+                id: UserId(i),
+                user_name1: "".to_string(),
+                user_name2: "".to_string(),
+                is_admin: false,
+                lang: "ru".to_string(),
+            };
+            sign_up(&conn, event_id, &user, 1, 0, 0, ts - 20, 0, 0, "").unwrap();
+        }
+        add_attachment(&conn, event_id, 1, "note")?;
+        confirm_presence(&conn, event_id, 1)?;
+
+        // enqueue_message: no message without its outbox entry
+        let messages = count("SELECT count(*) FROM messages");
+        inject_failure("INSERT", "message_outbox")?;
+        assert!(enqueue_message(&conn, event_id, "admin", 0, MessageType::Direct, "hi", ts).is_err());
+        clear_failure("message_outbox")?;
+        assert_eq!(count("SELECT count(*) FROM messages"), messages);
+
+        // ban_user: not banned if the reservations can't be cancelled
+        inject_failure("DELETE", "reservations")?;
+        assert!(ban_user(&conn, 2, "", "", "test", true).is_err());
+        clear_failure("reservations")?;
+        assert!(!is_in_black_list(&conn, 2)?);
+
+        // delete_event: automatic blacklisting and the cleanup are undone together
+        inject_failure("DELETE", "presence")?;
+        assert!(delete_event(&conn, event_id, true, true, &HashSet::new()).is_err());
+        clear_failure("presence")?;
+        assert!(!is_in_black_list(&conn, 2)?);
+        assert_eq!(get_event(&conn, event_id, 0)?.adults.reserved, 2);
+        assert_eq!(count("SELECT count(*) FROM attachments"), 1);
+        assert_eq!(count("SELECT count(*) FROM presence"), 1);
+        assert_eq!(count("SELECT count(*) FROM message_outbox"), 1);
+
+        // get_pending_messages: a finished message keeps its outbox entry until the receipts are gone
+        enqueue_message(&conn, event_id, "admin", 1, MessageType::Direct, "hi", ts - 30)?;
+        save_receipt(&conn, count("SELECT max(id) FROM messages"), 1)?;
+        inject_failure("DELETE", "message_sent")?;
+        assert!(get_pending_messages(&conn, ts - 20, 10).is_err());
+        clear_failure("message_sent")?;
+        assert_eq!(count("SELECT count(*) FROM message_outbox"), 2);
+        assert_eq!(get_pending_messages(&conn, ts - 20, 10)?.len(), 1);
+        assert_eq!(count("SELECT count(*) FROM message_outbox"), 1);
+
+        delete_event(&conn, event_id, true, true, &HashSet::new())?;
+        assert!(is_in_black_list(&conn, 2)?);
+        assert_eq!(count("SELECT count(*) FROM attachments"), 0);
+        assert_eq!(count("SELECT count(*) FROM message_outbox"), 0);
+        assert!(get_event(&conn, event_id, 0).is_err());
+
+        // series: no half generated or half deleted occurrences
+        let week = 7 * 24 * 60 * 60;
+        let series = EventSeries {
+            id: 0,
+            event: Event {
+                id: 0,
+                name: "weekly".to_string(),
+                link: "https://example.com/2".to_string(),
+                max_adults: 10,
+                max_children: 0,
+                max_adults_per_reservation: 1,
+                max_children_per_reservation: 0,
+                ts: ts + week,
+                remind: 0,
+                adult_ticket_price: 0,
+                child_ticket_price: 0,
+                tz: "Europe/Vienna".to_string(),
+                currency: "EUR".to_string(),
+                calendar_invite: false,
+                reminders: vec![],
+                auto_promote_hours: 0,
+                price_tiers: vec![],
+            },
+            rule: RepeatRule::Weekly,
+            until: 0,
+            count: 2,
+            skip: vec![],
+        };
+        let series = EventSeries { id: add_series(&conn, &series, false, ts)?, ..series };
+        inject_failure("UPDATE", "event_series")?;
+        assert!(generate_series_events(&conn, ts, ts + 4 * week).is_err());
+        clear_failure("event_series")?;
+        assert_eq!(count("SELECT count(*) FROM events WHERE series != 0"), 0);
+        assert_eq!(generate_series_events(&conn, ts, ts + 4 * week)?.len(), 2);
+        inject_failure("DELETE", "event_series")?;
+        assert!(delete_series(&conn, series.id, ts).is_err());
+        clear_failure("event_series")?;
+        assert_eq!(count("SELECT count(*) FROM events WHERE series != 0"), 2);
+        delete_series(&conn, series.id, ts)?;
+        assert_eq!(count("SELECT count(*) FROM events WHERE series != 0"), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_event_change() -> Result<(), Error> {
+        let db_file = "./test9.db3";
+        let _ = std::fs::remove_file(db_file);
+        let manager = SqliteConnectionManager::file(db_file);
+        let pool = r2d2::Pool::new(manager).unwrap();
+        let conn = pool.get().unwrap();
+        create(&conn).expect("Failed to create db.");
+
+        let ts = 4000000000;
+        let mut e = Event {
+            id: 0,
+            name: "test event".to_string(),
+            link: "https://example.com/1".to_string(),
+            max_adults: 1,
+            max_children: 0,
+            max_adults_per_reservation: 1,
+            max_children_per_reservation: 0,
+            ts,
+            remind: ts - 3600,
+            adult_ticket_price: 0,
+            child_ticket_price: 0,
+            tz: "Europe/Vienna".to_string(),
+            currency: "EUR".to_string(),
+            calendar_invite: true,
+            reminders: vec![],
+            auto_promote_hours: 0,
+            price_tiers: vec![],
+        };
+        e.id = add_event(&conn, e.clone())?;
+        assert!(get_event(&conn, e.id, 0)?.event.calendar_invite);
+        for i in 1..=2 {
+            let user = User {
+                id: UserId(i),
+                user_name1: "".to_string(),
+                user_name2: "".to_string(),
+                is_admin: false,
+                lang: "en".to_string(),
+            };
+            sign_up(&conn, e.id, &user, 1, 0, i - 1, ts - 7200, 0, 0, "").unwrap();
+        }
+        let pending = || get_pending_messages(&conn, get_unix_time() + 1, 10).unwrap();
+        let drain = || loop {
+            let messages = pending();
+            if messages.is_empty() {
+                break;
+            }
+            for m in messages {
+                for r in m.recipients {
+                    save_receipt(&conn, m.message_id, r.user_id).unwrap();
+                }
+            }
+        };
+        let recipients = |m: &MessageBatch| m.recipients.iter().map(|r| r.user_id).collect::<Vec<u64>>();
+
+        // nothing to tell if only the limits change
+        e.max_adults = 2;
+        add_event(&conn, e.clone())?;
+        assert_eq!(pending().len(), 0);
+
+        // a new reminder only updates the invite
+        e.remind = ts - 7200;
+        add_event(&conn, e.clone())?;
+        let messages = pending();
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].message_type == MessageType::EventChange);
+        assert!(messages[0].document.contains("TRIGGER;VALUE=DATE-TIME:20961002T050640Z\r\n"));
+        assert_eq!(recipients(&messages[0]), vec![1]);
+        drain();
+
+        // the new time goes to both lists, with the invite for the confirmed participant
+        e.ts = ts + 3600;
+        add_event(&conn, e.clone())?;
+        let messages = pending();
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].document.contains("METHOD:PUBLISH\r\n"));
+        assert!(messages[0].document.contains("DTSTART:20961002T080640Z\r\n"));
+        assert_eq!(recipients(&messages[0]), vec![1]);
+        assert!(messages[1].document.is_empty());
+        assert_eq!(recipients(&messages[1]), vec![2]);
+        assert_eq!(
+            locale::render(&messages[1].text, "en"),
+            "\nPlease note: <a href=\"https://example.com/1\">test event</a> has been moved from Tue 02.10 09:06 CEST to Tue 02.10 10:06 CEST.\n"
+        );
+        drain();
+
+        // without invites participants get the text only
+        e.calendar_invite = false;
+        e.name = "renamed event".to_string();
+        add_event(&conn, e.clone())?;
+        let messages = pending();
+        assert_eq!(messages.len(), 2);
+        assert!(messages.iter().all(|m| m.document.is_empty() && m.event_id == e.id));
+        assert!(locale::render(&messages[0].text, "en").starts_with("\nPlease note: test event on Tue 02.10 10:06 CEST is now"));
+        drain();
+
+        // the cancellations outlive the event
+        e.calendar_invite = true;
+        add_event(&conn, e.clone())?;
+        assert_eq!(pending().len(), 0);
+        delete_event(&conn, e.id, false, false, &HashSet::new())?;
+        let messages = pending();
+        assert_eq!(messages.len(), 2);
+        assert!(messages.iter().all(|m| m.event_id == 0));
+        assert!(messages[0].document.contains("METHOD:CANCEL\r\n"));
+        assert!(!messages[0].document.contains("VALARM"));
+        assert_eq!(recipients(&messages[0]), vec![1]);
+        assert_eq!(messages[0].recipients[0].lang, locale::DEFAULT_LANGUAGE);
+        assert!(messages[1].document.is_empty());
+        assert_eq!(recipients(&messages[1]), vec![2]);
+        assert!(locale::render(&messages[1].text, "en").contains("renamed event</a> on Tue 02.10 10:06 CEST has been cancelled"));
+        save_receipt(&conn, messages[0].message_id, 1)?;
+        assert_eq!(pending()[0].recipients.len(), 0);
+        drain();
+        assert_eq!(pending().len(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reminders() -> Result<(), Error> {
+        let db_file = "./test10.db3";
+        let _ = std::fs::remove_file(db_file);
+        let manager = SqliteConnectionManager::file(db_file);
+        let pool = r2d2::Pool::new(manager).unwrap();
+        let conn = pool.get().unwrap();
+        create(&conn).expect("Failed to create db.");
+        let outbox = |event_id: u64| -> Vec<u64> {
+            let mut stmt = conn
+                .prepare("SELECT o.send_at FROM message_outbox as o JOIN messages as m ON o.message = m.id WHERE m.event = ?1 AND m.type = 1 ORDER BY o.send_at")
+                .unwrap();
+            let rows = stmt.query_map([event_id], |row| row.get(0)).unwrap();
+            rows.map(|r| r.unwrap()).collect()
+        };
+
+        let day = 24 * 60 * 60;
+        let ts = get_unix_time() + 3 * day;
+        let mut e = Event {
+            id: 0,
+            name: "test event".to_string(),
+            link: "https://example.com/1".to_string(),
+            max_adults: 10,
+            max_children: 0,
+            max_adults_per_reservation: 1,
+            max_children_per_reservation: 0,
+            ts,
+            remind: 0,
+            adult_ticket_price: 0,
+            child_ticket_price: 0,
+            tz: "Europe/Vienna".to_string(),
+            currency: "EUR".to_string(),
+            calendar_invite: false,
+            reminders: vec![7 * day, day, 2 * 60 * 60],
+            auto_promote_hours: 0,
+            price_tiers: vec![],
+        };
+        assert_eq!(e.reminder_times(), vec![ts - 7 * day, ts - day, ts - 2 * 60 * 60]);
+
+        // the reminder 7 days before has passed
+        e.id = add_event(&conn, e.clone())?;
+        assert_eq!(get_event(&conn, e.id, 0)?.event.reminders, e.reminders);
+        assert_eq!(outbox(e.id), vec![ts - day, ts - 2 * 60 * 60]);
+
+        // rescheduled with the event, the explicit reminder coincides with the 2 hours one
+        e.ts = ts + 60 * 60;
+        e.remind = ts - 60 * 60;
+        add_event(&conn, e.clone())?;
+        assert_eq!(outbox(e.id), vec![e.ts - day, ts - 60 * 60]);
+
+        // all reminders have passed, the last one goes out right away
+        e.id = 0;
+        e.ts = get_unix_time() + 60 * 60;
+        e.remind = 0;
+        e.reminders = vec![day, 2 * 60 * 60];
+        let event_id = add_event(&conn, e.clone())?;
+        assert_eq!(outbox(event_id), vec![e.ts - 2 * 60 * 60]);
+
+        // editing it later doesn't send the past reminder again
+        e.id = event_id;
+        e.name = "renamed event".to_string();
+        add_event(&conn, e.clone())?;
+        assert_eq!(outbox(event_id), Vec::<u64>::new());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_attendance() -> Result<(), Error> {
+        let db_file = "./test11.db3";
+        let _ = std::fs::remove_file(db_file);
+        let manager = SqliteConnectionManager::file(db_file);
+        let pool = r2d2::Pool::new(manager).unwrap();
+        let conn = pool.get().unwrap();
+        create(&conn).expect("Failed to create db.");
+        let count = |query: &str| -> u64 { conn.query_row(query, [], |row| row.get(0)).unwrap() };
+
+        let now = get_unix_time();
+        let hour = 60 * 60;
+        let event_id = add_event(
+            &conn,
+            Event {
+                id: 0,
+                name: "test event".to_string(),
+                link: "https://example.com/1".to_string(),
+                max_adults: 3,
+                max_children: 0,
+                max_adults_per_reservation: 1,
+                max_children_per_reservation: 0,
+                ts: now + 2 * hour,
+                remind: now - hour,
+                adult_ticket_price: 0,
+                child_ticket_price: 0,
+                tz: "Europe/Vienna".to_string(),
+                currency: "EUR".to_string(),
+                calendar_invite: false,
+                reminders: vec![],
+                auto_promote_hours: 0,
+                price_tiers: vec![],
+            },
+        )?;
+        for i in 1..=4 {
+            let user = User {
+                id: UserId(i),
+                user_name1: "".to_string(),
+                user_name2: "".to_string(),
+                is_admin: false,
+                lang: "en".to_string(),
+            };
+            sign_up(&conn, event_id, &user, 1, 0, (i == 4) as u64, now, 0, 0, "").unwrap();
+        }
+        assert!(confirm_attendance(&conn, event_id, 5).is_err());
+
+        // users 1 and 2 were asked before the deadline, user 1 confirmed
+        set_reminded(&conn, event_id, 1, now - 2 * hour)?;
+        set_reminded(&conn, event_id, 2, now - 2 * hour)?;
+        set_reminded(&conn, event_id, 2, now)?;
+        confirm_attendance(&conn, event_id, 1)?;
+        let participants = get_participants(&conn, event_id, 0, 0, 0, ReservationState::Free)?;
+        assert_eq!(
+            participants.iter().map(|p| (p.user_id, p.reminded, p.confirmed)).collect::<Vec<_>>(),
+            vec![(1, true, true), (2, true, false), (3, false, false)]
+        );
+
+        // the deadline hasn't come yet
+        assert_eq!(release_unconfirmed(&conn, now, 1)?, 0);
+
+        // user 3 was never asked
+        assert_eq!(release_unconfirmed(&conn, now, 3)?, 1);
+        assert_eq!(release_unconfirmed(&conn, now, 3)?, 0);
+        let s = get_event(&conn, event_id, 0)?;
+        assert_eq!(s.adults.reserved, 2);
+        assert_eq!(get_participants(&conn, event_id, 1, 0, 0, ReservationState::Free)?.len(), 1);
+        assert_eq!(count("SELECT count(*) FROM messages WHERE type = 2"), 1);
+        assert_eq!(count("SELECT recipient FROM messages WHERE type = 4"), 2);
+
+        // asked after the deadline
+        set_reminded(&conn, event_id, 3, now)?;
+        assert_eq!(release_unconfirmed(&conn, now, 3)?, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_auto_promote() -> Result<(), Error> {
+        let db_file = "./test12.db3";
+        let _ = std::fs::remove_file(db_file);
+        let manager = SqliteConnectionManager::file(db_file);
+        let pool = r2d2::Pool::new(manager).unwrap();
+        let conn = pool.get().unwrap();
+        create(&conn).expect("Failed to create db.");
+        let count = |query: &str| -> u64 { conn.query_row(query, [], |row| row.get(0)).unwrap() };
+
+        let now = get_unix_time();
+        let hour = 60 * 60;
+        let event_id = add_event(
+            &conn,
+            Event {
+                id: 0,
+                name: "test event".to_string(),
+                link: "https://example.com/1".to_string(),
+                max_adults: 2,
+                max_children: 0,
+                max_adults_per_reservation: 1,
+                max_children_per_reservation: 0,
+                ts: now + 10 * hour,
+                remind: 0,
+                adult_ticket_price: 0,
+                child_ticket_price: 0,
+                tz: "Europe/Vienna".to_string(),
+                currency: "EUR".to_string(),
+                calendar_invite: false,
+                reminders: vec![],
+                auto_promote_hours: 2,
+                price_tiers: vec![],
+            },
+        )?;
+        for i in 1..=4 {
+            let user = User {
+                id: UserId(i),
+                user_name1: "".to_string(),
+                user_name2: "".to_string(),
+                is_admin: false,
+                lang: "en".to_string(),
+            };
+            sign_up(&conn, event_id, &user, 1, 0, (i > 2) as u64, now + i, 0, 0, "").unwrap();
+        }
+        let reserved = |waiting_list: u64| -> Vec<u64> {
+            get_participants(&conn, event_id, waiting_list, 0, 0, ReservationState::Free)
+                .unwrap()
+                .iter()
+                .map(|p| p.user_id)
+                .collect()
+        };
+
+        // the first in the waiting list gets the seat instead of a prompt to everyone
+        wontgo(&conn, event_id, 1)?;
+        assert_eq!(reserved(0), vec![2, 3]);
+        assert_eq!(reserved(1), vec![4]);
+        assert_eq!(count("SELECT count(*) FROM messages WHERE type = 2"), 0);
+        assert_eq!(count("SELECT recipient FROM messages WHERE type = 5"), 3);
+
+        // not accepted in time, so it moves on
+        assert_eq!(expire_promotions(&conn, now)?, 0);
+        assert_eq!(expire_promotions(&conn, now + 3 * hour)?, 1);
+        assert_eq!(reserved(0), vec![2, 4]);
+        assert!(reserved(1).is_empty());
+        assert_eq!(count("SELECT recipient FROM messages WHERE type = 4"), 3);
+        assert_eq!(count("SELECT count(*) FROM messages WHERE type = 5 AND recipient = 4"), 1);
+
+        // accepted seats stay
+        confirm_attendance(&conn, event_id, 4)?;
+        assert_eq!(expire_promotions(&conn, now + 6 * hour)?, 0);
+        assert_eq!(reserved(0), vec![2, 4]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_price_tiers() -> anyhow::Result<()> {
+        let db_file = "./test13.db3";
+        let _ = std::fs::remove_file(db_file);
+        let manager = SqliteConnectionManager::file(db_file);
+        let pool = r2d2::Pool::new(manager).unwrap();
+        let conn = pool.get().unwrap();
+        create(&conn).expect("Failed to create db.");
+
+        let ts = 1650445814;
+        let hour = 60 * 60;
+        let event_id = add_event(
+            &conn,
+            Event {
+                id: 0,
+                name: "paid event".to_string(),
+                link: "https://example.com/1".to_string(),
+                max_adults: 3,
+                max_children: 0,
+                max_adults_per_reservation: 3,
+                max_children_per_reservation: 0,
+                ts,
+                remind: 0,
+                adult_ticket_price: 1000,
+                child_ticket_price: 0,
+                tz: "Europe/Vienna".to_string(),
+                currency: "EUR".to_string(),
+                calendar_invite: false,
+                reminders: vec![],
+                auto_promote_hours: 0,
+                price_tiers: vec![
+                    PriceTier {
+                        name: "early bird".to_string(),
+                        adult_ticket_price: 500,
+                        child_ticket_price: 0,
+                        until: ts - 2 * hour,
+                        quota: 1,
+                    },
+                    PriceTier {
+                        name: "concession".to_string(),
+                        adult_ticket_price: 700,
+                        child_ticket_price: 0,
+                        until: 0,
+                        quota: 0,
+                    },
+                ],
+            },
+        )?;
+        let s = get_event(&conn, event_id, 0)?;
+        assert_eq!(s.event.price_tiers.len(), 2);
+        assert_eq!(s.event.prices(2), Some((700, 0)));
+        assert_eq!(s.event.prices(3), None);
+        let user = |id: u64| User {
+            id: UserId(id),
+            user_name1: "".to_string(),
+            user_name2: "".to_string(),
+            is_admin: false,
+            lang: "en".to_string(),
+        };
+
+        // the amount has to match the tier
+        assert!(sign_up(&conn, event_id, &user(10), 1, 0, 0, ts - 3 * hour, 1000, 1, "").is_err());
+        assert!(sign_up(&conn, event_id, &user(10), 1, 0, 0, ts - 3 * hour, 500, 3, "").is_err());
+        sign_up(&conn, event_id, &user(10), 1, 0, 0, ts - 3 * hour, 500, 1, "")?;
+        assert_eq!(get_tier_sales(&conn, event_id, 1)?, 1);
+
+        // sold out and expired
+        assert!(!is_tier_available(&conn, &s.event, 1, 1, ts - 3 * hour)?);
+        assert!(sign_up(&conn, event_id, &user(20), 1, 0, 0, ts - 3 * hour, 500, 1, "").is_err());
+        sign_up(&conn, event_id, &user(20), 1, 0, 0, ts - hour, 700, 2, "")?;
+        assert!(is_tier_available(&conn, &s.event, 2, 1, ts - hour)?);
+        assert!(is_tier_available(&conn, &s.event, 0, 1, ts - hour)?);
+
+        let order = |amount: u64| OrderInfo {
+            id: "charge".to_string(),
+            name: "name".to_string(),
+            amount,
+            currency: "EUR".to_string(),
+            ..Default::default()
+        };
+        let booking = |user_id: u64, tier: u64| Booking {
+            event_id,
+            adults: 1,
+            children: 0,
+            user_id,
+            tier,
+            promo_code: String::new(),
+        };
+        assert!(checkout(&conn, &booking(10, 0), order(500)).is_err());
+        assert!(checkout(&conn, &booking(10, 1), order(1000)).is_err());
+        checkout(&conn, &booking(10, 1), order(500))?;
+        assert!(checkout(&conn, &booking(20, 1), order(500)).is_err());
+        checkout(&conn, &booking(20, 2), order(700))?;
+        let payments = get_payments(&conn, event_id)?;
+        assert_eq!(payments.iter().filter_map(|p| p.order.as_ref()).map(|o| o.amount).sum::<u64>(), 1200);
+
+        // new prices release the holds at the old ones
+        let count = |query: &str| -> u64 { conn.query_row(query, [], |row| row.get(0)).unwrap() };
+        sign_up(&conn, event_id, &user(30), 1, 0, 0, ts - hour, 1000, 0, "")?;
+        let mut e = get_event(&conn, event_id, 0)?.event;
+        e.price_tiers[1].adult_ticket_price = 800;
+        add_event(&conn, e.clone())?;
+        assert_eq!(get_event(&conn, event_id, 0)?.event.prices(2), Some((800, 0)));
+        assert_eq!(count("SELECT count(*) FROM reservations WHERE state = 1"), 1);
+        e.adult_ticket_price = 1200;
+        add_event(&conn, e.clone())?;
+        assert_eq!(get_event(&conn, event_id, 0)?.event.prices(0), Some((1200, 0)));
+        assert_eq!(count("SELECT count(*) FROM reservations WHERE state = 1"), 0);
+        assert_eq!(count("SELECT recipient FROM messages WHERE type = 4"), 30);
+
+        // paid events keep the currency and stay paid
+        e.currency = "CHF".to_string();
+        assert!(add_event(&conn, e.clone()).is_err());
+        e.currency = "EUR".to_string();
+        e.adult_ticket_price = 0;
+        e.price_tiers.clear();
+        assert!(add_event(&conn, e.clone()).is_err());
+        assert_eq!(get_event(&conn, event_id, 0)?.event.price_tiers.len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_promo_codes() -> anyhow::Result<()> {
+        let db_file = "./test14.db3";
+        let _ = std::fs::remove_file(db_file);
+        let manager = SqliteConnectionManager::file(db_file);
+        let pool = r2d2::Pool::new(manager).unwrap();
+        let conn = pool.get().unwrap();
+        create(&conn).expect("Failed to create db.");
+
+        let ts = 1650445814;
+        let hour = 60 * 60;
+        let event = |name: &str| Event {
+            id: 0,
+            name: name.to_string(),
+            link: "https://example.com/1".to_string(),
+            max_adults: 5,
+            max_children: 5,
+            max_adults_per_reservation: 5,
+            max_children_per_reservation: 5,
+            ts,
+            remind: 0,
+            adult_ticket_price: 1000,
+            child_ticket_price: 500,
+            tz: "Europe/Vienna".to_string(),
+            currency: "EUR".to_string(),
+            calendar_invite: false,
+            reminders: vec![],
+            auto_promote_hours: 0,
+            price_tiers: vec![],
+        };
+        let event_id = add_event(&conn, event("paid event"))?;
+        let other_event_id = add_event(&conn, event("other event"))?;
+        let promo_code = PromoCode {
+            code: "HALF".to_string(),
+            percent: 50,
+            amount: 0,
+            max_uses: 1,
+            valid_from: ts - 10 * hour,
+            valid_until: ts - 2 * hour,
+            event_id,
+        };
+        add_promo_code(&conn, &promo_code)?;
+        add_promo_code(&conn, &PromoCode { code: "FIXED".to_string(), percent: 0, amount: 300, max_uses: 0, ..promo_code.clone() })?;
+        add_promo_code(&conn, &PromoCode { code: "TENTH".to_string(), percent: 10, max_uses: 0, event_id: 0, ..promo_code.clone() })?;
+        assert_eq!(promo_code.discount(1500), 750);
+        assert_eq!(get_promo_codes(&conn)?.len(), 3);
+
+        // codes can't make a ticket free, and amounts are in the currency of an event
+        let free = PromoCode { code: "FREE".to_string(), percent: 0, ..promo_code.clone() };
+        assert!(add_promo_code(&conn, &PromoCode { percent: 100, ..free.clone() }).is_err());
+        assert!(add_promo_code(&conn, &PromoCode { amount: 500, ..free.clone() }).is_err());
+        assert!(add_promo_code(&conn, &PromoCode { percent: 50, amount: 250, ..free.clone() }).is_err());
+        assert!(add_promo_code(&conn, &PromoCode { amount: 300, event_id: 0, ..free.clone() }).is_err());
+        assert_eq!(get_promo_codes(&conn)?.len(), 3);
+
+        // scope and validity window
+        assert!(check_promo_code(&conn, "HALF", other_event_id, ts - 5 * hour)?.is_none());
+        assert!(check_promo_code(&conn, "HALF", event_id, ts - 11 * hour)?.is_none());
+        assert!(check_promo_code(&conn, "HALF", event_id, ts - hour)?.is_none());
+        assert!(check_promo_code(&conn, "FIXED", other_event_id, ts - 5 * hour)?.is_none());
+        assert!(check_promo_code(&conn, "TENTH", other_event_id, ts - 5 * hour)?.is_some());
+        assert!(have_promo_codes(&conn, other_event_id, ts - 5 * hour)?);
+        assert!(!have_promo_codes(&conn, other_event_id, ts)?);
+
+        apply_promo_code(&conn, event_id, 10, "HALF")?;
+        assert_eq!(get_applied_promo_code(&conn, event_id, 10, ts - 5 * hour)?, Some(promo_code.clone()));
+        assert_eq!(get_applied_promo_code(&conn, event_id, 10, ts)?, None);
+
+        let user = |id: u64| User {
+            id: UserId(id),
+            user_name1: "".to_string(),
+            user_name2: "".to_string(),
+            is_admin: false,
+            lang: "en".to_string(),
+        };
+        // the amount has to account for the discount
+        assert!(sign_up(&conn, event_id, &user(10), 1, 1, 0, ts - 5 * hour, 1500, 0, "HALF").is_err());
+        sign_up(&conn, event_id, &user(10), 1, 1, 0, ts - 5 * hour, 750, 0, "HALF")?;
+
+        // the pending order uses the code up
+        assert!(sign_up(&conn, event_id, &user(20), 1, 0, 0, ts - 5 * hour, 500, 0, "HALF").is_err());
+        sign_up(&conn, event_id, &user(20), 1, 0, 0, ts - 5 * hour, 700, 0, "FIXED")?;
+
+        let booking = |user_id: u64, children: u64, promo_code: &str| Booking {
+            event_id,
+            adults: 1,
+            children,
+            user_id,
+            tier: 0,
+            promo_code: promo_code.to_string(),
+        };
+        let order = |amount: u64| OrderInfo {
+            id: "charge".to_string(),
+            name: "name".to_string(),
+            amount,
+            currency: "EUR".to_string(),
+            ..Default::default()
+        };
+        assert!(checkout(&conn, &booking(10, 1, ""), order(1500)).is_err());
+        assert!(checkout(&conn, &booking(10, 1, "HALF"), order(1500)).is_err());
+        checkout(&conn, &booking(10, 1, "HALF"), order(750))?;
+        checkout(&conn, &booking(20, 0, "FIXED"), order(700))?;
+        let payments = get_payments(&conn, event_id)?;
+        let orders = payments.iter().filter_map(|p| p.order.as_ref()).collect::<Vec<_>>();
+        assert_eq!(
+            orders.iter().map(|o| (o.promo_code.as_str(), o.discount)).collect::<Vec<_>>(),
+            vec![("HALF", 750), ("FIXED", 300)]
+        );
+        assert_eq!(
+            get_promo_codes(&conn)?.iter().map(|(p, uses)| (p.code.as_str(), *uses)).collect::<Vec<_>>(),
+            vec![("FIXED", 1), ("HALF", 1), ("TENTH", 0)]
+        );
+
+        // nor can cheaper tickets later
+        let mut e = get_event(&conn, event_id, 0)?.event;
+        e.child_ticket_price = 300;
+        add_event(&conn, e)?;
+        assert!(sign_up(&conn, event_id, &user(30), 0, 1, 0, ts - 5 * hour, 0, 0, "FIXED").is_err());
+        sign_up(&conn, event_id, &user(30), 0, 1, 0, ts - 5 * hour, 300, 0, "")?;
+
+        delete_promo_code(&conn, "HALF")?;
+        assert!(delete_promo_code(&conn, "HALF").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_payment_hold() -> anyhow::Result<()> {
+        let db_file = "./test15.db3";
+        let _ = std::fs::remove_file(db_file);
+        let manager = SqliteConnectionManager::file(db_file);
+        let pool = r2d2::Pool::new(manager).unwrap();
+        let conn = pool.get().unwrap();
+        create(&conn).expect("Failed to create db.");
+        let count = |query: &str| -> u64 { conn.query_row(query, [], |row| row.get(0)).unwrap() };
+
+        let ts = get_unix_time();
+        let hold = 5 * 60;
+        let event_id = add_event(
+            &conn,
+            Event {
+                id: 0,
+                name: "paid event".to_string(),
+                link: "https://example.com/1".to_string(),
+                max_adults: 2,
+                max_children: 0,
+                max_adults_per_reservation: 2,
+                max_children_per_reservation: 0,
+                ts: ts + 24 * 60 * 60,
+                remind: 0,
+                adult_ticket_price: 1000,
+                child_ticket_price: 0,
+                tz: "Europe/Vienna".to_string(),
+                currency: "EUR".to_string(),
+                calendar_invite: false,
+                reminders: vec![],
+                auto_promote_hours: 0,
+                price_tiers: vec![],
+            },
+        )?;
+        let user = |id: u64| User {
+            id: UserId(id),
+            user_name1: "".to_string(),
+            user_name2: "".to_string(),
+            is_admin: false,
+            lang: "en".to_string(),
+        };
+        let booking = |user_id: u64| Booking {
+            event_id,
+            adults: 2,
+            children: 0,
+            user_id,
+            tier: 0,
+            promo_code: "".to_string(),
+        };
+
+        // the hold takes all seats
+        sign_up(&conn, event_id, &user(10), 2, 0, 0, ts - hold - 10, 2000, 0, "")?;
+        assert!(sign_up(&conn, event_id, &user(20), 1, 0, 0, ts, 1000, 0, "").is_err());
+        assert_eq!(renew_hold(&conn, &booking(20), ts)?, None);
+
+        // an active hold is kept
+        assert_eq!(clear_failed_payments(&conn, ts - 20, hold)?, 0);
+        assert_eq!(count("SELECT count(*) FROM messages WHERE type = 4"), 0);
+
+        // an expired one releases the seats, tells the user and prompts the waiting list
+        assert_eq!(clear_failed_payments(&conn, ts, hold)?, 1);
+        assert_eq!(count("SELECT count(*) FROM reservations WHERE state = 1"), 0);
+        assert_eq!(count("SELECT recipient FROM messages WHERE type = 4"), 10);
+        assert_eq!(count("SELECT count(*) FROM messages WHERE type = 2"), 1);
+
+        // confirming the payment renews the hold
+        sign_up(&conn, event_id, &user(10), 2, 0, 0, ts - hold - 10, 2000, 0, "")?;
+        assert_eq!(renew_hold(&conn, &booking(10), ts)?, Some(0));
+        assert_eq!(clear_failed_payments(&conn, ts, hold)?, 0);
+
+        // a new invoice replaces the hold
+        assert_eq!(release_hold(&conn, event_id, 10)?, 1);
+        assert_eq!(renew_hold(&conn, &booking(10), ts)?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_paid_waiting_list() -> anyhow::Result<()> {
+        let db_file = "./test16.db3";
+        let _ = std::fs::remove_file(db_file);
+        let manager = SqliteConnectionManager::file(db_file);
+        let pool = r2d2::Pool::new(manager).unwrap();
+        let conn = pool.get().unwrap();
+        create(&conn).expect("Failed to create db.");
+        let count = |query: &str| -> u64 { conn.query_row(query, [], |row| row.get(0)).unwrap() };
+
+        let ts = get_unix_time();
+        let hour = 60 * 60;
+        let event_id = add_event(
+            &conn,
+            Event {
+                id: 0,
+                name: "paid event".to_string(),
+                link: "https://example.com/1".to_string(),
+                max_adults: 2,
+                max_children: 0,
+                max_adults_per_reservation: 2,
+                max_children_per_reservation: 0,
+                ts: ts + 24 * hour,
+                remind: 0,
+                adult_ticket_price: 1000,
+                child_ticket_price: 0,
+                tz: "Europe/Vienna".to_string(),
+                currency: "EUR".to_string(),
+                calendar_invite: false,
+                reminders: vec![],
+                auto_promote_hours: 2,
+                price_tiers: vec![],
+            },
+        )?;
+        let user = |id: u64| User {
+            id: UserId(id),
+            user_name1: "".to_string(),
+            user_name2: "".to_string(),
+            is_admin: false,
+            lang: "en".to_string(),
+        };
+        let booking = |user_id: u64, adults: u64| Booking {
+            event_id,
+            adults,
+            children: 0,
+            user_id,
+            tier: 0,
+            promo_code: "".to_string(),
+        };
+        let order = |amount: u64| OrderInfo {
+            id: "charge".to_string(),
+            name: "name".to_string(),
+            amount,
+            currency: "EUR".to_string(),
+            ..Default::default()
+        };
+        sign_up(&conn, event_id, &user(10), 2, 0, 0, ts, 2000, 0, "")?;
+        checkout(&conn, &booking(10, 2), order(2000))?;
+
+        // sold out, but the waiting list takes orders without payment
+        assert!(sign_up(&conn, event_id, &user(20), 1, 0, 0, ts, 1000, 0, "").is_err());
+        assert_eq!(sign_up(&conn, event_id, &user(20), 1, 0, 1, ts, 0, 0, "")?, (1, false));
+        assert_eq!(sign_up(&conn, event_id, &user(20), 2, 0, 1, ts, 0, 0, "")?, (0, false));
+        assert_eq!(sign_up(&conn, event_id, &user(30), 2, 0, 1, ts + 1, 0, 0, "")?, (1, false));
+        sign_up(&conn, event_id, &user(40), 1, 0, 1, ts + 2, 0, 0, "")?;
+        assert_eq!(leave_waiting_list(&conn, event_id, 40)?, 1);
+        assert_eq!(get_event(&conn, event_id, 20)?.adults.my_waiting, 1);
+        assert_eq!(get_offer(&conn, event_id, 20)?.map(|(b, _, _)| b.adults), None);
+
+        // a refund offers the seats to the first order that fits
+        request_refund(&conn, event_id, 10, "", ts)?;
+        let (offer, discount, until) = get_offer(&conn, event_id, 20)?.expect("no offer");
+        assert_eq!((offer.adults, discount), (1, 0));
+        assert!(until > ts && until <= get_unix_time() + 2 * hour);
+        assert_eq!(count("SELECT recipient FROM messages WHERE type = 5"), 20);
+        assert!(get_offer(&conn, event_id, 30)?.is_none());
+
+        // the offer isn't an invoice hold and lasts until its deadline
+        assert_eq!(clear_failed_payments(&conn, ts + hour, 5 * 60)?, 0);
+        assert_eq!(release_hold(&conn, event_id, 20)?, 0);
+        assert_eq!(expire_promotions(&conn, until + 1)?, 1);
+        assert_eq!(count("SELECT recipient FROM messages WHERE type = 4"), 20);
+
+        // and moves on to the next order
+        assert!(get_offer(&conn, event_id, 20)?.is_none());
+        assert!(get_offer(&conn, event_id, 30)?.is_some());
+        assert_eq!(renew_hold(&conn, &booking(30, 2), ts)?, Some(0));
+        checkout(&conn, &booking(30, 2), order(2000))?;
+        assert!(get_offer(&conn, event_id, 30)?.is_none());
+        assert_eq!(count("SELECT count(*) FROM reservations WHERE waiting_list = 1"), 0);
+        assert_eq!(expire_promotions(&conn, until + 1)?, 0);
+
+        // a promo code worth the whole ticket after a price cut isn't applied to the offer
+        let promo_code = PromoCode {
+            code: "FIXED".to_string(),
+            percent: 0,
+            amount: 500,
+            max_uses: 0,
+            valid_from: 0,
+            valid_until: 0,
+            event_id,
+        };
+        add_promo_code(&conn, &promo_code)?;
+        apply_promo_code(&conn, event_id, 50, "FIXED")?;
+        sign_up(&conn, event_id, &user(50), 1, 0, 1, ts, 0, 0, "")?;
+        let mut e = get_event(&conn, event_id, 0)?.event;
+        e.adult_ticket_price = 500;
+        add_event(&conn, e)?;
+        request_refund(&conn, event_id, 30, "", ts)?;
+        let (offer, discount, _) = get_offer(&conn, event_id, 50)?.expect("no offer");
+        assert_eq!((offer.promo_code.as_str(), discount), ("", 0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_in() -> anyhow::Result<()> {
+        let db_file = "./test17.db3";
+        let _ = std::fs::remove_file(db_file);
+        let manager = SqliteConnectionManager::file(db_file);
+        let pool = r2d2::Pool::new(manager).unwrap();
+        let conn = pool.get().unwrap();
+        create(&conn).expect("Failed to create db.");
+
+        let ts = get_unix_time();
+        let event = |name: &str, price: u64| Event {
+            id: 0,
+            name: name.to_string(),
+            link: "https://example.com/1".to_string(),
+            max_adults: 5,
+            max_children: 5,
+            max_adults_per_reservation: 5,
+            max_children_per_reservation: 5,
+            ts: ts + 24 * 60 * 60,
+            remind: 0,
+            adult_ticket_price: price,
+            child_ticket_price: 0,
+            tz: "Europe/Vienna".to_string(),
+            currency: "EUR".to_string(),
+            calendar_invite: false,
+            reminders: vec![],
+            auto_promote_hours: 0,
+            price_tiers: vec![],
+        };
+        let event_id = add_event(&conn, event("free event", 0))?;
+        let paid_event_id = add_event(&conn, event("paid event", 1000))?;
+        let user = |id: u64| User {
+            id: UserId(id),
+            user_name1: format!("user {}", id),
+            user_name2: "".to_string(),
+            is_admin: false,
+            lang: "en".to_string(),
+        };
+
+        // the secret is created once
+        let secret = get_secret(&conn, "tickets", "first")?;
+        assert_eq!(get_secret(&conn, "tickets", "second")?, secret);
+
+        sign_up(&conn, event_id, &user(10), 1, 0, 0, ts, 0, 0, "")?;
+        sign_up(&conn, event_id, &user(10), 0, 1, 0, ts, 0, 0, "")?;
+        sign_up(&conn, event_id, &user(20), 1, 0, 1, ts, 0, 0, "")?;
+        assert_eq!(check_in(&conn, event_id, 10)?, Some(("user 10".to_string(), 2, false)));
+        assert_eq!(check_in(&conn, event_id, 10)?, Some(("user 10".to_string(), 2, true)));
+        assert_eq!(get_presence_list(&conn, event_id, 0, 10)?.len(), 0);
+        // the waiting list has no ticket
+        assert_eq!(check_in(&conn, event_id, 20)?, None);
+
+        // unpaid seats have no ticket
+        sign_up(&conn, paid_event_id, &user(10), 1, 0, 0, ts, 1000, 0, "")?;
+        assert_eq!(check_in(&conn, paid_event_id, 10)?, None);
+
+        // check-in mode of admins
+        init_language(&conn, 10, "en")?;
+        assert!(!is_checkin_mode(&conn, 10)?);
+        set_checkin_mode(&conn, 10, true)?;
+        assert!(is_checkin_mode(&conn, 10)?);
+        set_checkin_mode(&conn, 10, false)?;
+        assert!(!is_checkin_mode(&conn, 10)?);
+
+        Ok(())
+    }
 }
@@ -1,17 +1,162 @@
 use crate::types::Event;
 use crate::types::{EventState, Participant};
-use chrono::{DateTime, Local, NaiveDateTime, Utc};
+use chrono::{Datelike, LocalResult, TimeZone};
 
 use crate::db;
+use crate::locale;
+use crate::payments;
+use crate::util;
 use db::EventStats;
 use r2d2::PooledConnection;
 use r2d2_sqlite::SqliteConnectionManager;
 
-pub fn ts(ts: u64) -> String {
-    let naive = NaiveDateTime::from_timestamp(ts as i64, 0);
-    let datetime: DateTime<Utc> = DateTime::from_utc(naive, Utc);
-    let local: DateTime<Local> = datetime.into();
-    local.format("%d.%m %H:%M").to_string()
+/// Start time in the event time zone with a localized weekday, e.g. "Sun 29.05 15:00 CEST".
+pub fn ts(ts: u64, tz: &str, lang: &str) -> String {
+    match util::time_zone(tz).timestamp_opt(ts as i64, 0) {
+        LocalResult::Single(t) => {
+            let weekdays = t!(lang, "weekdays");
+            let weekday = weekdays
+                .split(' ')
+                .nth(t.weekday().num_days_from_monday() as usize)
+                .unwrap_or_default();
+            format!("{} {}", weekday, t.format("%d.%m %H:%M %Z"))
+        }
+        _ => String::new(),
+    }
+}
+
+/// Amount in minor units, e.g. "12.50 EUR" or "1200 JPY".
+pub fn amount(amount: u64, currency: &str) -> String {
+    match payments::currency_exponent(currency) {
+        Some(exp) if exp > 0 => {
+            let unit = 10u64.pow(exp);
+            format!(
+                "{}.{:0width$} {}",
+                amount / unit,
+                amount % unit,
+                currency,
+                width = exp as usize
+            )
+        }
+        _ => format!("{} {}", amount, currency),
+    }
+}
+
+/// CSV with a byte order mark, so that spreadsheets detect UTF-8.
+pub fn csv(rows: &[Vec<String>]) -> String {
+    let mut res = "\u{feff}".to_string();
+    for row in rows {
+        let fields: Vec<String> = row
+            .iter()
+            .map(|field| {
+                if field.contains(&[',', '"', '\n', '\r'][..]) {
+                    format!("\"{}\"", field.replace('"', "\"\""))
+                } else {
+                    field.to_string()
+                }
+            })
+            .collect();
+        res.push_str(&fields.join(","));
+        res.push_str("\r\n");
+    }
+    res
+}
+
+/// iCalendar feed of the events, times in UTC.
+pub fn ics(events: &[EventStats], lang: &str, ts: u64) -> String {
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//event-manager-telegram-bot//EN".to_string(),
+        "CALSCALE:GREGORIAN".to_string(),
+        "METHOD:PUBLISH".to_string(),
+    ];
+    for s in events {
+        lines.push("BEGIN:VEVENT".to_string());
+        lines.push(format!("UID:event-{}@event-manager-telegram-bot", s.event.id));
+        lines.push(format!("DTSTAMP:{}", ics_time(ts)));
+        lines.push(format!("DTSTART:{}", ics_time(s.event.ts)));
+        lines.push(format!("SUMMARY:{}", ics_text(&s.event.name)));
+        if !s.event.link.is_empty() {
+            lines.push(format!("URL:{}", s.event.link));
+        }
+        let max = s.event.max_adults + s.event.max_children;
+        if max > 0 {
+            let free = max.saturating_sub(s.adults.reserved + s.children.reserved);
+            lines.push(format!(
+                "DESCRIPTION:{}",
+                ics_text(&t!(lang, "calendar_seats", free = free, max = max))
+            ));
+        }
+        lines.push("END:VEVENT".to_string());
+    }
+    lines.push("END:VCALENDAR".to_string());
+    lines.iter().map(|line| ics_line(line)).collect()
+}
+
+/// Single event .ics file sent to a participant. `ts` is the time of the change and doubles as
+/// the sequence number, so calendars replace the previous version of the event.
+pub fn ics_invite(event: &Event, cancelled: bool, ts: u64) -> String {
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//event-manager-telegram-bot//EN".to_string(),
+        "CALSCALE:GREGORIAN".to_string(),
+        format!("METHOD:{}", if cancelled { "CANCEL" } else { "PUBLISH" }),
+        "BEGIN:VEVENT".to_string(),
+        format!("UID:event-{}@event-manager-telegram-bot", event.id),
+        format!("SEQUENCE:{}", ts),
+        format!("DTSTAMP:{}", ics_time(ts)),
+        format!("DTSTART:{}", ics_time(event.ts)),
+        format!("SUMMARY:{}", ics_text(&event.name)),
+        format!("STATUS:{}", if cancelled { "CANCELLED" } else { "CONFIRMED" }),
+    ];
+    if !event.link.is_empty() {
+        lines.push(format!("URL:{}", event.link));
+    }
+    for remind in event.reminder_times() {
+        if cancelled || remind >= event.ts {
+            break;
+        }
+        lines.push("BEGIN:VALARM".to_string());
+        lines.push("ACTION:DISPLAY".to_string());
+        lines.push(format!("DESCRIPTION:{}", ics_text(&event.name)));
+        lines.push(format!("TRIGGER;VALUE=DATE-TIME:{}", ics_time(remind)));
+        lines.push("END:VALARM".to_string());
+    }
+    lines.push("END:VEVENT".to_string());
+    lines.push("END:VCALENDAR".to_string());
+    lines.iter().map(|line| ics_line(line)).collect()
+}
+
+fn ics_time(ts: u64) -> String {
+    match chrono::Utc.timestamp_opt(ts as i64, 0) {
+        LocalResult::Single(t) => t.format("%Y%m%dT%H%M%SZ").to_string(),
+        _ => String::new(),
+    }
+}
+
+fn ics_text(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+/// Folds the line to 75 octets.
+fn ics_line(line: &str) -> String {
+    let mut res = String::with_capacity(line.len() + 2);
+    let mut len = 0;
+    for c in line.chars() {
+        if len + c.len_utf8() > 75 {
+            res.push_str("\r\n ");
+            len = 1;
+        }
+        res.push(c);
+        len += c.len_utf8();
+    }
+    res.push_str("\r\n");
+    res
 }
 
 pub fn event_title(event: &Event) -> String {
@@ -28,44 +173,90 @@ pub fn header(
     free_children: i64,
     is_admin: bool,
     no_age_distinction: bool,
+    lang: &str,
 ) -> String {
-    let mut header = format!(
-        "\n \n{}\nНачало: {}.",
-        event_title(&s.event),
-        ts(s.event.ts)
-    );
+    let mut header = t!(lang, "header", title = event_title(&s.event), start = ts(s.event.ts, &s.event.tz, lang));
     if is_admin {
-        header.push_str(&format!(
-            " Мероприятие {} / {}({})",
-            s.event.id, s.event.max_adults, s.event.max_children
+        header.push_str(&t!(
+            lang,
+            "header_admin",
+            id = s.event.id,
+            max_adults = s.event.max_adults,
+            max_children = s.event.max_children
         ));
     }
 
     if s.state == EventState::Open {
         if no_age_distinction {
-            header.push_str(&format!(
-                " Свободные места: {}",
-                free_adults + free_children,
-            ));
+            header.push_str(&t!(lang, "free_seats", count = free_adults + free_children));
         } else {
-            header.push_str(&format!("\nВзрослые свободные места: {}", free_adults));
-            header.push_str(&format!("\nДетские свободные места: {}", free_children));
+            header.push_str(&t!(lang, "free_adult_seats", count = free_adults));
+            header.push_str(&t!(lang, "free_child_seats", count = free_children));
         }
     } else {
-        header.push_str(" Запись остановлена.");
+        header.push_str(&t!(lang, "sign_up_stopped"));
     }
     header
 }
 
+/// Ticket prices that can be bought now, the selected tier in bold.
+pub fn price_tiers(
+    event: &Event,
+    tiers: &[(u64, Option<u64>)],
+    selected: u64,
+    no_age_distinction: bool,
+    lang: &str,
+) -> String {
+    let mut text = t!(lang, "price_tiers");
+    for (tier, left) in tiers {
+        let (adult_ticket_price, child_ticket_price) = event.prices(*tier).unwrap_or_default();
+        let (name, until) = match *tier {
+            0 => (t!(lang, "regular_price"), 0),
+            _ => {
+                let t = &event.price_tiers[*tier as usize - 1];
+                (t.name.clone(), t.until)
+            }
+        };
+        let mut line = if no_age_distinction {
+            format!("{}: {}", name, amount(adult_ticket_price + child_ticket_price, &event.currency))
+        } else {
+            format!(
+                "{}: {}, {}",
+                name,
+                t!(lang, "price_adult", amount = amount(adult_ticket_price, &event.currency)),
+                t!(lang, "price_child", amount = amount(child_ticket_price, &event.currency))
+            )
+        };
+        if until != 0 {
+            line.push_str(&t!(lang, "price_until", until = ts(until, &event.tz, lang)));
+        }
+        if let Some(left) = left {
+            line.push_str(&t!(lang, "price_left", count = left));
+        }
+        if *tier == selected {
+            text.push_str(&format!("\n<b>{}</b>", line));
+        } else {
+            text.push_str(&format!("\n{}", line));
+        }
+    }
+    text
+}
+
 pub fn participants(
     s: &EventStats,
     participants: &Vec<Participant>,
     is_admin: bool,
     no_age_distinction: bool,
+    lang: &str,
 ) -> String {
     let mut list = "".to_string();
     if participants.len() != 0 {
-        list.push_str(&format!("\n\nЗаписались {}({}):", s.adults.reserved, s.children.reserved));
+        list.push_str(&t!(
+            lang,
+            "participants_header",
+            adults = s.adults.reserved,
+            children = s.children.reserved
+        ));
     }
 
     list.push_str(
@@ -97,6 +288,11 @@ pub fn participants(
                 if let Some(a) = &p.attachment {
                     entry.push_str(&format!(" {}", a));
                 }
+                if is_admin && p.confirmed {
+                    entry.push_str(" ✅");
+                } else if is_admin && p.reminded {
+                    entry.push_str(" ❔");
+                }
                 entry
             })
             .collect::<String>(),
@@ -109,6 +305,7 @@ pub fn messages(
     s: &EventStats,
     event_id: u64,
     is_admin: bool,
+    lang: &str,
 ) -> Option<String> {
     let waiting_list = if is_admin {
         None
@@ -123,24 +320,24 @@ pub fn messages(
                     format!(
                         "\n{}, {}:\n{}\n",
                         msg.sender,
-                        ts(msg.ts),
-                        msg.text
+                        ts(msg.ts, &s.event.tz, lang),
+                        locale::render(&msg.text, lang)
                     )
                 } else {
                     format!(
                         "\n{}, {} ({}):\n{}\n",
                         msg.sender,
-                        ts(msg.ts),
+                        ts(msg.ts, &s.event.tz, lang),
                         if msg.waiting_list == 0 {
-                            "для забронировавших"
+                            t!(lang, "for_confirmed")
                         } else {
-                            "для списка ожидания"
+                            t!(lang, "for_waiting")
                         },
-                        msg.text
+                        locale::render(&msg.text, lang)
                     )
                 }                
             }).collect();
-            return Some(format!("\n\n<b>Cообщения по мероприятию</b>{}", formatted_list));
+            return Some(format!("{}{}", t!(lang, "event_messages"), formatted_list));
         }
     }
     None
@@ -148,5 +345,17 @@ pub fn messages(
 
 #[test]
 fn test_format() {
-    assert_eq!(ts(1650445814), "20.04 11:10");
+    assert_eq!(ts(1650445814, "Europe/Vienna", "en"), "Wed 20.04 11:10 CEST");
+    assert_eq!(ts(1648303200, "Europe/Vienna", "de"), "Sa 26.03 15:00 CET");
+    assert_eq!(ts(1648303200, "Europe/Moscow", "ru"), "Сб 26.03 17:00 MSK");
+    assert_eq!(amount(1250, "EUR"), "12.50 EUR");
+    assert_eq!(amount(1200, "JPY"), "1200 JPY");
+    assert_eq!(amount(1005, "KWD"), "1.005 KWD");
+    assert_eq!(
+        csv(&[vec!["a".to_string(), "b, \"c\"".to_string()]]),
+        "\u{feff}a,\"b, \"\"c\"\"\"\r\n"
+    );
+    assert_eq!(ics_text("a,b;c\\d\ne"), "a\\,b\\;c\\\\d\\ne");
+    let folded = ics_line(&"ä".repeat(40));
+    assert_eq!(folded.split("\r\n ").map(|l| l.len()).collect::<Vec<_>>(), vec![74, 8]);
 }
@@ -0,0 +1,168 @@
+//! Message catalogue.
+//!
+//! One TOML file per language in `locales/`, embedded into the binary. Templates use `{name}`
+//! placeholders. Keys missing in a language fall back to the default language.
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+pub const DEFAULT_LANGUAGE: &str = "ru";
+
+const CATALOGUES: &[(&str, &str)] = &[
+    ("ru", include_str!("../locales/ru.toml")),
+    ("en", include_str!("../locales/en.toml")),
+    ("de", include_str!("../locales/de.toml")),
+];
+
+type Catalogue = HashMap<String, HashMap<String, String>>;
+
+fn catalogue() -> &'static Catalogue {
+    static CATALOGUE: OnceLock<Catalogue> = OnceLock::new();
+    CATALOGUE.get_or_init(|| {
+        CATALOGUES
+            .iter()
+            .map(|(lang, text)| {
+                (
+                    lang.to_string(),
+                    toml::from_str(text)
+                        .unwrap_or_else(|e| panic!("Failed to parse catalogue {}: {}", lang, e)),
+                )
+            })
+            .collect()
+    })
+}
+
+/// Supported languages in display order.
+pub fn languages() -> Vec<&'static str> {
+    CATALOGUES.iter().map(|(lang, _)| *lang).collect()
+}
+
+/// Maps Telegram's IETF language tag to a supported language.
+pub fn from_language_code(code: Option<&str>) -> String {
+    code.and_then(|code| code.split('-').next())
+        .map(|code| code.to_lowercase())
+        .filter(|code| catalogue().contains_key(code))
+        .unwrap_or_else(|| DEFAULT_LANGUAGE.to_string())
+}
+
+/// Looks up the template and substitutes the arguments.
+pub fn translate(lang: &str, key: &str, args: &[(&str, String)]) -> String {
+    let template = catalogue()
+        .get(lang)
+        .and_then(|c| c.get(key))
+        .or_else(|| catalogue().get(DEFAULT_LANGUAGE).and_then(|c| c.get(key)));
+    match template {
+        Some(template) => substitute(template, args),
+        None => {
+            error!("Missing translation {} {}", lang, key);
+            key.to_string()
+        }
+    }
+}
+
+fn substitute(template: &str, args: &[(&str, String)]) -> String {
+    let mut res = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        res.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = rest
+            .find('}')
+            .and_then(|end| args.iter().find(|(name, _)| *name == &rest[1..end]));
+        match value {
+            Some((name, value)) => {
+                res.push_str(value);
+                rest = &rest[name.len() + 2..];
+            }
+            None => {
+                res.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    res.push_str(rest);
+    res
+}
+
+/// Bot message stored in the outbox and rendered in the language of each recipient.
+#[derive(Serialize, Deserialize)]
+pub struct Template {
+    pub key: String,
+    pub args: Vec<(String, String)>,
+    /// Timestamps with their time zones, formatted in the recipient's language.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub times: Vec<(String, u64, String)>,
+}
+
+impl Template {
+    pub fn new(key: &str, args: &[(&str, String)]) -> Template {
+        Template {
+            key: key.to_string(),
+            args: args
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.clone()))
+                .collect(),
+            times: Vec::new(),
+        }
+    }
+
+    pub fn time(mut self, name: &str, ts: u64, tz: &str) -> Template {
+        self.times.push((name.to_string(), ts, tz.to_string()));
+        self
+    }
+
+    pub fn to_text(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+/// Renders a stored message. Messages stored before localization are plain text.
+pub fn render(text: &str, lang: &str) -> String {
+    match serde_json::from_str::<Template>(text) {
+        Ok(t) => translate(
+            lang,
+            &t.key,
+            &t.args
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.clone()))
+                .chain(
+                    t.times
+                        .iter()
+                        .map(|(name, ts, tz)| (name.as_str(), crate::format::ts(*ts, tz, lang))),
+                )
+                .collect::<Vec<(&str, String)>>(),
+        ),
+        Err(_) => text.to_string(),
+    }
+}
+
+/// `t!(lang, "key", name = value, ...)`
+macro_rules! t {
+    ($lang:expr, $key:expr) => {
+        $crate::locale::translate($lang, $key, &[])
+    };
+    ($lang:expr, $key:expr, $($name:ident = $value:expr),+ $(,)?) => {
+        $crate::locale::translate($lang, $key, &[$((stringify!($name), $value.to_string())),+])
+    };
+}
+
+#[test]
+fn test_locale() {
+    assert_eq!(from_language_code(Some("de-AT")), "de");
+    assert_eq!(from_language_code(Some("xx")), DEFAULT_LANGUAGE);
+    assert_eq!(from_language_code(None), DEFAULT_LANGUAGE);
+    assert_eq!(
+        substitute("{a} {b} {c} {", &[("a", "{b}".to_string()), ("b", "2".to_string())]),
+        "{b} 2 {c} {"
+    );
+    for lang in languages() {
+        assert_eq!(
+            catalogue()[lang].keys().collect::<std::collections::BTreeSet<_>>(),
+            catalogue()[DEFAULT_LANGUAGE].keys().collect(),
+            "{} catalogue keys differ",
+            lang
+        );
+    }
+    let stored = Template::new("no_events", &[]).to_text();
+    assert_eq!(render(&stored, "en"), "No events.");
+    assert_eq!(render("plain", "en"), "plain");
+}
@@ -6,7 +6,6 @@ extern crate num;
 use std::collections::HashSet;
 use std::sync::Arc;
 use std::{fs::File, io::prelude::*, time::Duration};
-use tokio::sync::Mutex;
 #[macro_use]
 extern crate log;
 extern crate r2d2;
@@ -15,20 +14,26 @@ extern crate rusqlite;
 use teloxide::{
     prelude::*,
     types::{
-        InlineKeyboardButton, InlineKeyboardMarkup, LabeledPrice, MessageKind,
+        InlineKeyboardButton, InlineKeyboardMarkup, InputFile, LabeledPrice, MessageKind,
         MessageSuccessfulPayment, ParseMode, PreCheckoutQuery, Update, UserId,
     },
     RequestError,
 };
 
+#[macro_use]
+mod locale;
 mod admin_message_handler;
+mod api;
+mod calendar;
 mod db;
 mod format;
 mod message_handler;
 mod payments;
 mod reply;
+mod ticket;
 mod types;
 mod util;
+mod webhook;
 
 use crate::reply::*;
 use crate::types::MessageType;
@@ -50,10 +55,16 @@ async fn main() {
                 .takes_value(true)
                 .default_value(""),
         )
+        .arg(
+            clap::Arg::with_name("database")
+                .short("d")
+                .long("database")
+                .help("Database file, overrides database_path of the configuration")
+                .takes_value(true),
+        )
         .get_matches();
 
-    let config = matches.value_of("config").unwrap();
-    let mut f = File::open(config).unwrap();
+    let mut f = File::open(matches.value_of("config").unwrap()).unwrap();
     let mut contents = String::new();
     f.read_to_string(&mut contents).unwrap();
 
@@ -63,6 +74,10 @@ async fn main() {
 
     config.parse().unwrap();
 
+    if let Some(database) = matches.value_of("database") {
+        config.database_path = database.to_string();
+    }
+
     let admins: HashSet<u64> = config
         .admin_ids
         .split(',')
@@ -70,10 +85,18 @@ async fn main() {
         .filter_map(|id| id.parse::<u64>().ok())
         .collect();
 
-    let manager = SqliteConnectionManager::file("./events.db3");
+    info!("Using database {}", config.database_path);
+    let busy_timeout = Duration::from_millis(config.database_busy_timeout_ms);
+    let manager = SqliteConnectionManager::file(&config.database_path).with_init(move |c| {
+        c.busy_timeout(busy_timeout)?;
+        c.execute_batch("PRAGMA journal_mode = WAL;")
+    });
     let pool = r2d2::Pool::new(manager).unwrap();
     if let Ok(conn) = pool.get() {
-        db::create(&conn).expect("Failed to create db.");
+        if let Err(e) = db::create(&conn) {
+            error!("Failed to prepare db: {}", e);
+            std::process::exit(1);
+        }
     }
 
     let bot = Bot::new(&config.telegram_bot_token).auto_send();
@@ -82,28 +105,57 @@ async fn main() {
         config,
         pool,
         admins,
-        sign_up_mutex: Arc::new(Mutex::new(0u64)),
     });
 
     tokio::spawn(perform_bulk_tasks(bot.clone(), context.clone()));
 
+    if !context.config.api_address.is_empty() {
+        let ctx = context.clone();
+        tokio::spawn(async move {
+            if let Err(e) = api::serve(ctx).await {
+                error!("Admin API failed: {}", e);
+            }
+        });
+    }
+
+    if !context.config.calendar_address.is_empty() {
+        let ctx = context.clone();
+        tokio::spawn(async move {
+            if let Err(e) = calendar::serve(ctx).await {
+                error!("Calendar feed failed: {}", e);
+            }
+        });
+    }
+
     let handler = dptree::entry()
         .branch(Update::filter_pre_checkout_query().endpoint(pre_checkout_handler))
         .branch(Update::filter_message().endpoint(message_handler))
         .branch(Update::filter_callback_query().endpoint(callback_handler));
 
-    Dispatcher::builder(bot, handler)
-        .dependencies(dptree::deps![context])
+    let mut dispatcher = Dispatcher::builder(bot.clone(), handler)
+        .dependencies(dptree::deps![context.clone()])
         .default_handler(|upd| async move {
             log::warn!("Unhandled update: {:?}", upd);
         })
         .error_handler(LoggingErrorHandler::with_custom_text(
             "An error has occurred in the dispatcher",
         ))
-        .build()
-        .setup_ctrlc_handler()
-        .dispatch()
-        .await;
+        .build();
+    dispatcher.setup_ctrlc_handler();
+
+    if context.config.mode == "webhook" {
+        let listener = webhook::listener(bot, &context.config)
+            .await
+            .expect("Failed to set up webhook");
+        dispatcher
+            .dispatch_with_listener(
+                listener,
+                LoggingErrorHandler::with_custom_text("An error from the update listener"),
+            )
+            .await;
+    } else {
+        dispatcher.dispatch().await;
+    }
 }
 
 async fn message_handler(
@@ -115,15 +167,19 @@ async fn message_handler(
 
     match &msg.kind {
         MessageKind::Common(_) => {
-            if let Some(text) = msg.text() {
+            // A forwarded ticket has the token in the caption.
+            if let Some(text) = msg.text().or_else(|| msg.caption()) {
                 if let Some(user) = msg.from() {
                     if user.is_bot {
                         warn!("Bot ignored");
                         return Ok(());
                     }
                     trace!("received {:?}", msg);
-                    let u = crate::types::User::new(&user, &context.admins);
+                    let mut u = crate::types::User::new(user, &context.admins);
                     if let Ok(conn) = context.pool.get() {
+                        if let Ok(lang) = db::init_language(&conn, u.id.0, &u.lang) {
+                            u.lang = lang;
+                        }
                         let reply = if u.is_admin {
                             crate::admin_message_handler::handle_message(&conn, &u, text, &context)
                         } else {
@@ -134,6 +190,9 @@ async fn message_handler(
                                 Reply::Message(r) => {
                                     r.send(&msg, &bot).await?;
                                 }
+                                Reply::Document(d) => {
+                                    d.send(&msg, &bot).await?;
+                                }
                                 Reply::Invoice {
                                     title,
                                     description,
@@ -175,11 +234,20 @@ async fn message_handler(
         }
         MessageKind::SuccessfulPayment(MessageSuccessfulPayment { successful_payment }) => {
             trace!("successful_payment {:?}", &successful_payment);
-            if let Ok(conn) = context.pool.get() {
-                let res = crate::payments::checkout(&conn, successful_payment, &context);
-                if let Err(e) = res {
-                    error!("Failed to check out: {}", e);
-                    bot.send_message(msg.chat.id, e.to_string()).await?;
+            if let (Ok(conn), Some(user)) = (context.pool.get(), msg.from()) {
+                let mut u = crate::types::User::new(user, &context.admins);
+                if let Ok(lang) = db::init_language(&conn, u.id.0, &u.lang) {
+                    u.lang = lang;
+                }
+                match crate::payments::checkout(&conn, &u, successful_payment, &context) {
+                    Ok(Reply::Message(r)) => {
+                        r.send(&msg, &bot).await?;
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        error!("Failed to check out: {}", e);
+                        bot.send_message(msg.chat.id, e.to_string()).await?;
+                    }
                 }
             }
         }
@@ -198,14 +266,11 @@ async fn callback_handler(
     match (q.message, q.data) {
         (Some(msg), Some(data)) => {
             trace!("received {:?} {:?}", &msg, &data);
-            let u = crate::types::User::new(&q.from, &context.admins);
-            let mut lock;
-            if data.starts_with("sign_up ") {
-                lock = context.sign_up_mutex.lock().await;
-                *lock = *lock + 1;
-                // todo: use event based locking
-            }
+            let mut u = crate::types::User::new(&q.from, &context.admins);
             if let Ok(conn) = context.pool.get() {
+                if let Ok(lang) = db::init_language(&conn, u.id.0, &u.lang) {
+                    u.lang = lang;
+                }
                 let reply = if u.is_admin {
                     crate::admin_message_handler::handle_callback(&conn, &u, &data, &context)
                 } else {
@@ -217,6 +282,9 @@ async fn callback_handler(
                             trace!("reply {:?}", r);
                             r.edit(&msg, &bot).await?;
                         }
+                        Reply::Document(d) => {
+                            d.send(&msg, &bot).await?;
+                        }
                         Reply::Invoice {
                             title,
                             description,
@@ -299,11 +367,11 @@ async fn pre_checkout_handler(
     context: Arc<Context>,
 ) -> Result<(), RequestError> {
     trace!("pre_checkout_handler::received {:?}", pre_checkout);
-    let u = crate::types::User::new(&pre_checkout.from, &context.admins);
+    let mut u = crate::types::User::new(&pre_checkout.from, &context.admins);
     if let Ok(conn) = context.pool.get() {
-        let mut lock = context.sign_up_mutex.lock().await;
-        *lock = *lock + 1;
-
+        if let Ok(lang) = db::init_language(&conn, u.id.0, &u.lang) {
+            u.lang = lang;
+        }
         let reply = crate::payments::pre_checkout(&conn, &u, &pre_checkout, &context);
         match reply {
             Ok(_) => {
@@ -319,6 +387,20 @@ async fn pre_checkout_handler(
     Ok(())
 }
 
+fn attendance_buttons(event_id: u64, lang: &str) -> Vec<InlineKeyboardButton> {
+    vec![
+        InlineKeyboardButton::callback(
+            t!(lang, "attendance_yes"),
+            serde_json::to_string(&message_handler::CallbackQuery::ConfirmAttendance { event_id })
+                .unwrap(),
+        ),
+        InlineKeyboardButton::callback(
+            t!(lang, "attendance_no"),
+            serde_json::to_string(&message_handler::CallbackQuery::WontGo { event_id }).unwrap(),
+        ),
+    ]
+}
+
 /// Bulk mailing and houskeeping task
 async fn perform_bulk_tasks(bot: AutoSend<Bot>, ctx: Arc<Context>) -> Result<bool, RequestError> {
     let mut next_break = tokio::time::Instant::now() + Duration::from_millis(1000);
@@ -328,11 +410,8 @@ async fn perform_bulk_tasks(bot: AutoSend<Bot>, ctx: Arc<Context>) -> Result<boo
         let mut notifications = 0;
         let mut batch_contains_waiting_list_prompt = false;
         let ts = get_unix_time();
-        let num_seconds_from_midnight = ts % 86400;
 
-        if num_seconds_from_midnight >= ctx.config.mailing_hours_from.unwrap()
-            && num_seconds_from_midnight < ctx.config.mailing_hours_to.unwrap()
-        {
+        if ctx.config.is_mailing_time(ts) {
             let messages = if let Ok(conn) = ctx.pool.get() {
                 match db::get_pending_messages(
                     &conn,
@@ -348,35 +427,68 @@ async fn perform_bulk_tasks(bot: AutoSend<Bot>, ctx: Arc<Context>) -> Result<boo
 
             for m in messages {
                 notifications += m.recipients.len();
-                let keyboard: Vec<Vec<InlineKeyboardButton>> =
-                    vec![vec![InlineKeyboardButton::callback(
-                        "К мероприятию",
-                        if m.is_paid {
-                            serde_json::to_string(&message_handler::CallbackQuery::PaidEvent {
-                                event_id: m.event_id,
-                                adults: 0,
-                                children: 0,
-                                offset: 0,
-                            })
+                let callback = if m.is_paid {
+                    serde_json::to_string(&message_handler::CallbackQuery::PaidEvent {
+                        event_id: m.event_id,
+                        adults: 0,
+                        children: 0,
+                        tier: 0,
+                        offset: 0,
+                    })
+                } else {
+                    serde_json::to_string(&message_handler::CallbackQuery::Event {
+                        event_id: m.event_id,
+                        offset: 0,
+                    })
+                }
+                .unwrap();
+                for u in m.recipients {
+                    let text = locale::render(&m.text, &u.lang);
+                    debug!("Sending notification {} to {} {}", m.message_id, u.user_id, &text);
+                    if m.document.is_empty() && m.event_id == 0 {
+                        bot.send_message(UserId(u.user_id), text)
+                            .parse_mode(ParseMode::Html)
+                            .disable_web_page_preview(true)
+                            .await?;
+                    } else if m.document.is_empty() {
+                        let mut keyboard = vec![vec![InlineKeyboardButton::callback(
+                            t!(&u.lang, "to_event"),
+                            callback.clone(),
+                        )]];
+                        let ask = m.message_type == MessageType::Reminder && !m.is_paid;
+                        let text = if ask {
+                            // Still coming?
+                            keyboard.insert(0, attendance_buttons(m.event_id, &u.lang));
+                            text + &t!(&u.lang, "attendance_question")
                         } else {
-                            serde_json::to_string(&message_handler::CallbackQuery::Event {
-                                event_id: m.event_id,
-                                offset: 0,
-                            })
+                            if m.message_type == MessageType::Promotion && !m.is_paid {
+                                keyboard.insert(0, attendance_buttons(m.event_id, &u.lang));
+                            }
+                            text
+                        };
+                        bot.send_message(UserId(u.user_id), text)
+                            .parse_mode(ParseMode::Html)
+                            .disable_web_page_preview(true)
+                            .reply_markup(InlineKeyboardMarkup::new(keyboard))
+                            .await?;
+                        if ask {
+                            if let Ok(conn) = ctx.pool.get() {
+                                if let Err(e) = db::set_reminded(&conn, m.event_id, u.user_id, ts) {
+                                    error!("Failed to save reminder: {}", e);
+                                }
+                            }
                         }
-                        .unwrap(),
-                    )]];
-                let keyboard = InlineKeyboardMarkup::new(keyboard);
-                for u in m.recipients {
-                    debug!("Sending notification {} to {} {}", m.message_id, u, &m.text);
-                    bot.send_message(UserId(u), &m.text)
-                        .parse_mode(ParseMode::Html)
-                        .disable_web_page_preview(true)
-                        .reply_markup(keyboard.clone())
-                        .await?;
+                    } else {
+                        let document = InputFile::memory(m.document.clone().into_bytes())
+                            .file_name("event.ics");
+                        bot.send_document(UserId(u.user_id), document)
+                            .caption(text)
+                            .parse_mode(ParseMode::Html)
+                            .await?;
+                    }
 
                     if let Ok(conn) = ctx.pool.get() {
-                        if let Err(e) = db::save_receipt(&conn, m.message_id, u) {
+                        if let Err(e) = db::save_receipt(&conn, m.message_id, u.user_id) {
                             error!("Failed to save receipt: {}", e);
                         }
                     }
@@ -416,10 +528,37 @@ async fn perform_bulk_tasks(bot: AutoSend<Bot>, ctx: Arc<Context>) -> Result<boo
             }
         }
 
+        // Release seats of participants who didn't confirm the reminder.
+        if ctx.config.release_unconfirmed_hours > 0 {
+            if let Ok(conn) = ctx.pool.get() {
+                if let Err(e) = db::release_unconfirmed(&conn, ts, ctx.config.release_unconfirmed_hours) {
+                    error!("Failed to release unconfirmed seats at {}: {}", ts, e);
+                }
+            }
+        }
+
+        // Return promoted seats that weren't accepted in time.
+        if let Ok(conn) = ctx.pool.get() {
+            if let Err(e) = db::expire_promotions(&conn, ts) {
+                error!("Failed to expire promotions at {}: {}", ts, e);
+            }
+        }
+
         // Clear failed payments.
         if let Ok(conn) = ctx.pool.get() {
-            if db::clear_failed_payments(&conn, ts - 5 * 60).is_ok() == false {
-                error!("Failed to clear failed payments at {}", ts);
+            if let Err(e) = db::clear_failed_payments(&conn, ts, ctx.config.payment_hold_minutes * 60) {
+                error!("Failed to clear failed payments at {}: {}", ts, e);
+            }
+        }
+
+        // Materialise recurring events.
+        if let Ok(conn) = ctx.pool.get() {
+            if let Err(e) = db::generate_series_events(
+                &conn,
+                ts,
+                ts + ctx.config.series_horizon_days * 24 * 60 * 60,
+            ) {
+                error!("Failed to generate series events at {}: {}", ts, e);
             }
         }
 
@@ -1,5 +1,5 @@
 use crate::get_unix_time;
-use crate::payments::{prepare_invoice, show_paid_event, donate};
+use crate::payments::{apply_promo_code, join_waiting_list, leave_waiting_list, prepare_invoice, show_paid_event, donate, TicketSelection};
 use crate::types::{Context, EventState, EventType, ReservationState, User};
 use crate::reply::*;
 use anyhow::anyhow;
@@ -9,8 +9,11 @@ use teloxide::{
 };
 use url::Url;
 
+use crate::calendar;
 use crate::db;
 use crate::format;
+use crate::locale;
+use crate::ticket;
 use db::EventStats;
 use r2d2::PooledConnection;
 use r2d2_sqlite::SqliteConnectionManager;
@@ -42,22 +45,32 @@ pub fn handle_message(
                     }
                 }
             } else {
-                return show_event_list(conn, user.id.0, ctx, 0);
+                return show_event_list(conn, user, ctx, 0);
             }
         }
         "/donate" => {
                 return donate(user, 500, ctx);
         }
         "/help" => {
-            return Ok(ReplyMessage::new(format!(
-                "Здесь вы можете бронировать места на мероприятия.\n \
-                            \n /start - показать список мероприятий \
-                            \n /help - эта подсказка \
-                            \n <a href=\"{}\">Подробная инструкция</a> \
-                            \n /donate - поддержать канал.",
-                ctx.config.help
-            ))
-            .into());
+            return Ok(ReplyMessage::new(t!(&user.lang, "help", help = ctx.config.help)).into());
+        }
+        "/lang" => {
+            if pars.len() == 2 {
+                return set_language(conn, user, pars[1]);
+            }
+            return show_languages(user);
+        }
+        "/calendar" => {
+            return show_calendar(conn, user, ctx);
+        }
+        "/ticket" => {
+            return ticket::show_ticket(conn, user, ctx);
+        }
+        "/promo" => {
+            if pars.len() == 2 {
+                return apply_promo_code(conn, user, pars[1], ctx);
+            }
+            return Ok(ReplyMessage::new(t!(&user.lang, "promo_code_hint")).into());
         }
         _ => {
             // Message from user - try to add as attachment to the last reservation.
@@ -106,12 +119,26 @@ pub enum CallbackQuery {
         event_id: u64,
         adults: u64,
         children: u64,
+        tier: u64,
         offset: u64,
     },
     SendInvoice {
         event_id: u64,
         adults: u64,
         children: u64,
+        tier: u64,
+    },
+    WaitForTickets {
+        event_id: u64,
+        adults: u64,
+        children: u64,
+        tier: u64,
+    },
+    LeaveWaitingList {
+        event_id: u64,
+    },
+    SetLanguage {
+        lang: String,
     },
 
     // admin callbacks
@@ -128,6 +155,13 @@ pub enum CallbackQuery {
     ConfirmRemoveFromBlackList {
         user_id: u64,
     },
+    ExportParticipants {
+        event_id: u64,
+    },
+
+    ConfirmAttendance {
+        event_id: u64,
+    },
 }
 
 /// Callback query processor.
@@ -140,7 +174,7 @@ pub fn handle_callback(
     if let Ok(q) = serde_json::from_str::<CallbackQuery>(&data) {
         use CallbackQuery::*;
         match q {
-            EventList { offset } => show_event_list(conn, user.id.0, ctx, offset),
+            EventList { offset } => show_event_list(conn, user, ctx, offset),
             Event { event_id, offset } => show_event(conn, user, event_id, ctx, None, offset),
             SignUp {
                 event_id,
@@ -156,20 +190,36 @@ pub fn handle_callback(
                     wait as u64,
                     get_unix_time(),
                     0,
+                    0,
+                    "",
                 ) {
-                    Ok((_, black_listed)) => show_event(
-                        conn,
-                        user,
-                        event_id,
-                        ctx,
-                        if black_listed {
-                            Some(format!("\n\nИзвините, но бронирование невозможно, поскольку ранее Вы не использовали и не отменили бронь. \
-                                    Если это ошибка, пожалуйста, свяжитесь с <a href=\"tg://user?id={}\">поддержкой</a> и сообщите код {}. <a href=\"{}\">Инструкция</a>.", ctx.config.support, user.id, ctx.config.help))
-                        } else {
-                            None
-                        },
-                        0,
-                    ),
+                    Ok((res, black_listed)) => {
+                        let reply = show_event(
+                            conn,
+                            user,
+                            event_id,
+                            ctx,
+                            if black_listed {
+                                Some(t!(
+                                    &user.lang,
+                                    "black_listed",
+                                    support = ctx.config.support,
+                                    user = user.id,
+                                    help = ctx.config.help
+                                ))
+                            } else {
+                                None
+                            },
+                            0,
+                        )?;
+                        match reply {
+                            Reply::Message(m) if res > 0 && !wait => Ok(m
+                                .document(calendar_invite(conn, user, event_id, 1))
+                                .document(ticket::ticket(conn, &ctx.config, user, event_id))
+                                .into()),
+                            reply => Ok(reply),
+                        }
+                    }
                     Err(e) => Err(anyhow!("{}", e)),
                 }
             }
@@ -189,7 +239,7 @@ pub fn handle_callback(
                                         &user.user_name2,
                                         &format!(
                                             "late cancel {} {}",
-                                            format::ts(s.event.ts),
+                                            format::ts(s.event.ts, &s.event.tz, locale::DEFAULT_LANGUAGE),
                                             s.event.name
                                         ),
                                         ctx.config.cancel_future_reservations_on_ban,
@@ -202,7 +252,7 @@ pub fn handle_callback(
                                             user.id
                                         ));
                                     }
-                                    ps = Some(format!("\n\nВНИМАНИЕ!\nК сожалению, вы отказались от билетов слишком поздно и не сможете больше бронировать бесплатные билеты."));
+                                    ps = Some(t!(&user.lang, "late_cancel_banned"));
                                 }
                             }
                         }
@@ -215,14 +265,20 @@ pub fn handle_callback(
                 match db::wontgo(conn, event_id, user.id.0) {
                     Ok(_) => {
                         if is_too_late_to_cancel(conn, event_id, user, ctx) {
-                            Ok(ReplyMessage::new("К сожалению, вы отказываетесь от билетов слишком поздно и не сможете больше бронировать бесплатные билеты.".to_string()).into())
+                            Ok(ReplyMessage::new(t!(&user.lang, "wontgo_too_late")).into())
                         } else {
-                            Ok(ReplyMessage::new("Мы сожалеем, что вы не сможете пойти. Увидимся в другой раз. Спасибо!".to_string()).into())
+                            Ok(ReplyMessage::new(t!(&user.lang, "wontgo")).into())
                         }
                     }
                     Err(e) => Err(anyhow!("Failed to add event: {}.", e)),
                 }
             }
+            ConfirmAttendance { event_id } => match db::confirm_attendance(conn, event_id, user.id.0) {
+                Ok(_) => Ok(ReplyMessage::new(t!(&user.lang, "attendance_confirmed"))
+                    .document(ticket::ticket(conn, &ctx.config, user, event_id))
+                    .into()),
+                Err(e) => Err(anyhow!("Failed to confirm attendance: {}.", e)),
+            },
             ShowWaitingList { event_id, offset } => {
                 if ctx.config.public_lists || user.is_admin != false {
                     show_waiting_list(conn, user, event_id, ctx, offset)
@@ -259,13 +315,23 @@ pub fn handle_callback(
                 event_id,
                 adults,
                 children,
+                tier,
                 offset,
-            } => show_paid_event(event_id, adults, children, offset, conn, user, ctx),
+            } => show_paid_event(event_id, TicketSelection { adults, children, tier }, offset, conn, user, ctx),
             SendInvoice {
                 event_id,
                 adults,
                 children,
-            } => prepare_invoice(event_id, adults, children, conn, user, ctx),
+                tier,
+            } => prepare_invoice(event_id, adults, children, tier, conn, user, ctx),
+            WaitForTickets {
+                event_id,
+                adults,
+                children,
+                tier,
+            } => join_waiting_list(event_id, adults, children, tier, conn, user, ctx),
+            LeaveWaitingList { event_id } => leave_waiting_list(event_id, conn, user, ctx),
+            SetLanguage { lang } => set_language(conn, user, &lang),
             _ => Err(anyhow!("Not allowed.")),
         }
     } else {
@@ -290,7 +356,7 @@ pub fn add_attachment(
                         event_id,
                         ctx,
                         if data.chars().any(char::is_numeric) {
-                            Some("\n\nВНИМАНИЕ!\nВаше примечание содержит цифры. Они никак не влияют на количество забронированных мест. Количество мест можно менять только кнопками \"Записать/Отписать\".".to_string())
+                            Some(t!(&user.lang, "attachment_has_digits"))
                         } else {
                             None
                         },
@@ -306,19 +372,19 @@ pub fn add_attachment(
 
 pub fn show_event_list(
     conn: &PooledConnection<SqliteConnectionManager>,
-    user_id: u64,
+    user: &User,
     ctx: &Context,
     offset: u64,
 ) -> anyhow::Result<Reply> {
-    match db::get_events(conn, user_id, offset, ctx.config.event_list_page_size) {
+    match db::get_events(conn, user.id.0, offset, ctx.config.event_list_page_size) {
         Ok(events) => {
             Ok(
                 // header
                 ReplyMessage::new(
                     if offset != 0 || events.len() != 0 {
-                        format!("Программа\nвремя / взросл.(детск.) места  / мероприятие\n<a href=\"{}\">инструкция</a> /donate", ctx.config.help)
+                        t!(&user.lang, "event_list_header", help = ctx.config.help)
                     } else {
-                        "Нет мероприятий.".to_string()
+                        t!(&user.lang, "no_events")
                     }                      
                 )
                 .keyboard(
@@ -328,7 +394,7 @@ pub fn show_event_list(
                         let event_type = s.event.get_type();
                         if event_type == EventType::Announcement {
                             if let Ok(url) = Url::parse(&s.event.link) {
-                                vec![InlineKeyboardButton::url(format!("ℹ️ {} {}", format::ts(s.event.ts), s.event.name), url)]
+                                vec![InlineKeyboardButton::url(format!("ℹ️ {} {}", format::ts(s.event.ts, &s.event.tz, &user.lang), s.event.name), url)]
                             } else {
                                 vec![]
                             }
@@ -345,7 +411,7 @@ pub fn show_event_list(
                                     } else {
                                         ""
                                     },
-                                    format::ts(s.event.ts),
+                                    format::ts(s.event.ts, &s.event.tz, &user.lang),
                                     if s.state == EventState::Open {
                                         if s.event.max_adults == 0 || s.event.max_children == 0 {
                                             (s.event.max_adults - s.adults.reserved + s.event.max_children
@@ -368,6 +434,7 @@ pub fn show_event_list(
                                         event_id: s.event.id,
                                         adults: 0,
                                         children: 0,
+                                        tier: 0,
                                         offset: 0,
                                     })
                                 } else {
@@ -435,6 +502,7 @@ pub fn show_event(
                         free_children,
                         is_admin,
                         no_age_distinction,
+                        &user.lang,
                     )                    
                 )
                 // participants
@@ -444,10 +512,11 @@ pub fn show_event(
                         &participants,
                         is_admin,
                         no_age_distinction,
+                        &user.lang,
                     ))
                 }))
                 // messages
-                .text(format::messages(conn, &s, event_id, is_admin))
+                .text(format::messages(conn, &s, event_id, is_admin, &user.lang))
                 // attachment
                 .text({
                     if is_admin
@@ -461,25 +530,27 @@ pub fn show_event(
                             match db::get_attachment(conn, event_id, user.id.0) {
                                 Ok(v) => {
                                     if let Some(attachment) = v {
-                                        text.push_str(&format!("\nПримечание: {}.", attachment));
+                                        text.push_str(&t!(&user.lang, "attachment", attachment = attachment));
                                     }
                                 }
                                 Err(e) => error!("Failed to get attachment: {}", e),
                             }
                         }
                         if is_admin == false {
-                            text.push_str("\nКоличество мест можно менять кнопками \"Записаться/Отписаться\". Примечание к брони можно добавить, послав сообщение боту.\n");
+                            text.push_str(&t!(&user.lang, "seats_hint"));
                         }
                         if s.adults.my_reservation + s.children.my_reservation > 0 {
-                            text.push_str(&format!(
-                                "\n<b>У вас забронировано: {}</b>",
-                                s.adults.my_reservation + s.children.my_reservation
+                            text.push_str(&t!(
+                                &user.lang,
+                                "my_reservation",
+                                count = s.adults.my_reservation + s.children.my_reservation
                             ));
                         }
                         if s.adults.my_waiting + s.children.my_waiting > 0 {
-                            text.push_str(&format!(
-                                "\n<b>У вас в списке ожидания: {}</b>",
-                                s.adults.my_waiting + s.children.my_waiting
+                            text.push_str(&t!(
+                                &user.lang,
+                                "my_waiting",
+                                count = s.adults.my_waiting + s.children.my_waiting
                             ));
                         }
                         Some(text)
@@ -494,7 +565,7 @@ pub fn show_event(
                     free_children,
                     no_age_distinction,
                     is_admin,
-                    user.id.0,
+                    user,
                     conn,
                 )?)
                 // pagination
@@ -525,18 +596,19 @@ fn get_signup_controls(
     free_children: i64,
     no_age_distinction: bool,
     is_admin: bool,
-    user_id: u64,
+    user: &User,
     conn: &PooledConnection<SqliteConnectionManager>,
 ) -> anyhow::Result<Vec<Vec<InlineKeyboardButton>>> {
+    let lang = &user.lang;
     let mut keyboard: Vec<Vec<InlineKeyboardButton>> = Vec::new();
     let mut row: Vec<InlineKeyboardButton> = Vec::new();
     if s.state == EventState::Open && s.adults.my_reservation < s.event.max_adults_per_reservation {
         if free_adults > 0 {
             row.push(InlineKeyboardButton::callback(
                 if no_age_distinction {
-                    "Записаться +1"
+                    t!(lang, "sign_up")
                 } else {
-                    "Записать взрослого +1"
+                    t!(lang, "sign_up_adult")
                 },
                 &serde_json::to_string(&CallbackQuery::SignUp {
                     event_id: s.event.id,
@@ -548,9 +620,9 @@ fn get_signup_controls(
         {
             row.push(InlineKeyboardButton::callback(
                 if no_age_distinction {
-                    "В лист ожидания +1"
+                    t!(lang, "wait")
                 } else {
-                    "В лист ожидания взрослого +1"
+                    t!(lang, "wait_adult")
                 },
                 &serde_json::to_string(&CallbackQuery::SignUp {
                     event_id: s.event.id,
@@ -563,9 +635,9 @@ fn get_signup_controls(
     if s.adults.my_reservation > 0 || s.adults.my_waiting > 0 {
         row.push(InlineKeyboardButton::callback(
             if no_age_distinction {
-                "Отписаться -1"
+                t!(lang, "cancel")
             } else {
-                "Отписать взрослого -1"
+                t!(lang, "cancel_adult")
             },
             &serde_json::to_string(&CallbackQuery::Cancel {
                 event_id: s.event.id,
@@ -579,9 +651,9 @@ fn get_signup_controls(
         if free_children > 0 {
             row.push(InlineKeyboardButton::callback(
                 if no_age_distinction {
-                    "Записаться +1"
+                    t!(lang, "sign_up")
                 } else {
-                    "Записать ребёнка +1"
+                    t!(lang, "sign_up_child")
                 },
                 &serde_json::to_string(&CallbackQuery::SignUp {
                     event_id: s.event.id,
@@ -594,9 +666,9 @@ fn get_signup_controls(
         {
             row.push(InlineKeyboardButton::callback(
                 if no_age_distinction {
-                    "В лист ожидания +1"
+                    t!(lang, "wait")
                 } else {
-                    "В лист ожидания ребёнка +1"
+                    t!(lang, "wait_child")
                 },
                 &serde_json::to_string(&CallbackQuery::SignUp {
                     event_id: s.event.id,
@@ -609,9 +681,9 @@ fn get_signup_controls(
     if s.children.my_reservation > 0 || s.children.my_waiting > 0 {
         row.push(InlineKeyboardButton::callback(
             if no_age_distinction {
-                "Отписаться -1"
+                t!(lang, "cancel")
             } else {
-                "Отписать ребёнка -1"
+                t!(lang, "cancel_child")
             },
             &serde_json::to_string(&CallbackQuery::Cancel {
                 event_id: s.event.id,
@@ -623,14 +695,14 @@ fn get_signup_controls(
 
     row = Vec::new();
     row.push(InlineKeyboardButton::callback(
-        "Список мероприятий",
+        t!(lang, "event_list"),
         serde_json::to_string(&CallbackQuery::EventList { offset: 0 })?,
     ));
 
     let event_id = s.event.id;
     if s.adults.reserved > 0 || s.children.reserved > 0 {
         row.push(InlineKeyboardButton::callback(
-            "Список ожидания",
+            t!(lang, "waiting_list"),
             &serde_json::to_string(&CallbackQuery::ShowWaitingList {
                 event_id,
                 offset: 0,
@@ -641,30 +713,34 @@ fn get_signup_controls(
     if is_admin {
         if s.adults.reserved > 0 || s.children.reserved > 0 {
             row.push(InlineKeyboardButton::callback(
-                "Присутствие",
+                t!(lang, "presence"),
                 &serde_json::to_string(&CallbackQuery::ShowPresenceList {
                     event_id,
                     offset: 0,
                 })?,
             ));
+            row.push(InlineKeyboardButton::callback(
+                t!(lang, "export"),
+                &serde_json::to_string(&CallbackQuery::ExportParticipants { event_id })?,
+            ));
         }
         if s.state == EventState::Open {
             row.push(InlineKeyboardButton::callback(
-                "Остановить запись",
+                t!(lang, "close_event"),
                 serde_json::to_string(&CallbackQuery::ChangeEventState { event_id, state: 1 })?,
             ));
         } else {
             row.push(InlineKeyboardButton::callback(
-                "Разрешить запись",
+                t!(lang, "open_event"),
                 serde_json::to_string(&CallbackQuery::ChangeEventState { event_id, state: 0 })?,
             ));
         }
     } else {
         if s.adults.reserved > 0 || s.children.reserved > 0 {
-            if let Ok(check) = db::is_group_leader(conn, event_id, user_id) {
+            if let Ok(check) = db::is_group_leader(conn, event_id, user.id.0) {
                 if check {
                     row.push(InlineKeyboardButton::callback(
-                        "Присутствие",
+                        t!(lang, "presence"),
                         &serde_json::to_string(&CallbackQuery::ShowPresenceList {
                             event_id,
                             offset: 0,
@@ -691,10 +767,11 @@ fn show_waiting_list(
     match db::get_event(conn, event_id, user.id.0) {
         Ok(s) => {
             no_age_distinction = s.event.max_adults == 0 || s.event.max_children == 0;
-            list.push_str(&format!(
-                "\n \n{}\nНачало: {}\n",
-                format::event_title(&s.event),
-                format::ts(s.event.ts)
+            list.push_str(&t!(
+                &user.lang,
+                "list_header",
+                title = format::event_title(&s.event),
+                start = format::ts(s.event.ts, &s.event.tz, &user.lang)
             ));
         }
         Err(e) => {
@@ -713,9 +790,9 @@ fn show_waiting_list(
             Ok(
                 ReplyMessage::new(                    
                     if participants.len() == 0 {
-                        "Пустой список ожидания.".to_string()
+                        t!(&user.lang, "empty_waiting_list")
                     } else {
-                        "Список ожидания:\n".to_string() +
+                        t!(&user.lang, "waiting_list_header") +
                         &participants
                             .iter()
                             .map(|p| {
@@ -752,7 +829,7 @@ fn show_waiting_list(
                 // controls
                 .keyboard(
                     vec![vec![InlineKeyboardButton::callback(
-                        "Назад",
+                        t!(&user.lang, "back"),
                         &serde_json::to_string(&CallbackQuery::Event {
                             event_id,
                             offset: 0,
@@ -803,10 +880,11 @@ fn show_presence_list(
     let mut header = "".to_string();
     match db::get_event(conn, event_id, user.id.0) {
         Ok(s) => {
-            header.push_str(&format!(
-                "\n \n{}\nНачало: {}\n",
-                format::event_title(&s.event),
-                format::ts(s.event.ts)
+            header.push_str(&t!(
+                &user.lang,
+                "list_header",
+                title = format::event_title(&s.event),
+                start = format::ts(s.event.ts, &s.event.tz, &user.lang)
             ));
         }
         Err(e) => {
@@ -819,9 +897,9 @@ fn show_presence_list(
                 // header
                 ReplyMessage::new(              
                     if participants.len() == 0 {
-                        "Пустой список ожидания."
+                        t!(&user.lang, "empty_presence_list")
                     } else {
-                        "Пожалуйста, выберите присутствующих:\n"
+                        t!(&user.lang, "select_present")
                     }
                 )
                 .keyboard(
@@ -855,7 +933,7 @@ fn show_presence_list(
                 // controls
                 .keyboard(
                     vec![vec![InlineKeyboardButton::callback(
-                        "Назад",
+                        t!(&user.lang, "back"),
                         &serde_json::to_string(&CallbackQuery::Event {
                             event_id,
                             offset: 0,
@@ -881,3 +959,78 @@ fn show_presence_list(
         Err(e) => Err(anyhow!("Failed to get precense list: {}", e)),
     }
 }
+
+fn show_languages(user: &User) -> anyhow::Result<Reply> {
+    let mut keyboard: Vec<Vec<InlineKeyboardButton>> = Vec::new();
+    for lang in locale::languages() {
+        keyboard.push(vec![InlineKeyboardButton::callback(
+            t!(lang, "language_name"),
+            serde_json::to_string(&CallbackQuery::SetLanguage {
+                lang: lang.to_string(),
+            })?,
+        )]);
+    }
+    Ok(ReplyMessage::new(t!(&user.lang, "choose_language"))
+        .keyboard(keyboard)
+        .into())
+}
+
+/// Personal feed url, or the feed itself if the feed server is not configured.
+fn show_calendar(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    user: &User,
+    ctx: &Context,
+) -> anyhow::Result<Reply> {
+    if ctx.config.calendar_address.is_empty() {
+        let ics = calendar::user_feed(conn, user.id.0, &user.lang, get_unix_time())?;
+        return Ok(ReplyDocument::new("events.ics", ics.into_bytes())
+            .caption(t!(&user.lang, "calendar_file"))
+            .into());
+    }
+    let token = db::get_calendar_token(conn, user.id.0, &crate::util::random_token())?;
+    let (personal, public) = calendar::urls(&ctx.config, &token);
+    Ok(ReplyMessage::new(t!(&user.lang, "calendar_links", personal = personal, public = public)).into())
+}
+
+/// .ics file of the event for a user who has just booked their first `seats`, if the event sends
+/// calendar invites.
+pub fn calendar_invite(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    user: &User,
+    event_id: u64,
+    seats: u64,
+) -> Option<ReplyDocument> {
+    match db::get_event(conn, event_id, user.id.0) {
+        Ok(s) if s.event.calendar_invite
+            && s.adults.my_reservation + s.children.my_reservation == seats =>
+        {
+            let ics = format::ics_invite(&s.event, false, get_unix_time());
+            Some(ReplyDocument::new("event.ics", ics.into_bytes())
+                .caption(t!(&user.lang, "calendar_invite")))
+        }
+        Ok(_) => None,
+        Err(e) => {
+            error!("Failed to get event {}: {}", event_id, e);
+            None
+        }
+    }
+}
+
+fn set_language(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    user: &User,
+    lang: &str,
+) -> anyhow::Result<Reply> {
+    if !locale::languages().contains(&lang) {
+        return show_languages(user);
+    }
+    match db::set_language(conn, user.id.0, lang) {
+        Ok(_) => Ok(ReplyMessage::new(t!(
+            lang,
+            "language_set",
+            language = t!(lang, "language_name")
+        ))
+        .into()),
+        Err(e) => Err(anyhow!("Failed to set language: {}", e)),
+    }
+}
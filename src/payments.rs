@@ -1,6 +1,6 @@
-use crate::message_handler::CallbackQuery;
+use crate::message_handler::{self, CallbackQuery};
 use crate::types::{
-    Booking, Context, EventState, OrderInfo, ReservationState, User,
+    Booking, Context, Event, EventState, EventType, OrderInfo, ReservationState, User,
 };
 use crate::reply::*;
 use crate::util::{get_unix_time};
@@ -11,6 +11,7 @@ use teloxide::types::{
 
 use crate::db;
 use crate::format;
+use crate::ticket;
 use db::EventStats;
 use r2d2::PooledConnection;
 use r2d2_sqlite::SqliteConnectionManager;
@@ -20,17 +21,35 @@ pub fn pre_checkout(
     conn: &PooledConnection<SqliteConnectionManager>,
     user: &User,
     pre_checkout: &PreCheckoutQuery,
-    _ctx: &Context,
+    ctx: &Context,
 ) -> anyhow::Result<()> {
-    if pre_checkout.currency != Currency::EUR {
-        return Err(anyhow!("Only EUR is currently accepted"));
-    }
+    let currency = currency_code(&pre_checkout.currency);
     if let Some(_) = &pre_checkout.order_info.name {
         let booking: Booking = serde_json::from_str(&pre_checkout.invoice_payload)?;
         if booking.event_id == 0 {
             // Donation
+            if currency != ctx.config.currency {
+                return Err(anyhow!("Only {} is accepted", ctx.config.currency));
+            }
             Ok(())
         } else {
+            let s = db::get_event(conn, booking.event_id, user.id.0)?;
+            if currency != s.event.currency {
+                return Err(anyhow!("Only {} is accepted", s.event.currency));
+            }
+            // The invoice holds the seats; book them again only if the hold has expired.
+            if let Some(discount) = db::renew_hold(conn, &booking, get_unix_time())? {
+                let (adult_ticket_price, child_ticket_price) = s
+                    .event
+                    .prices(booking.tier)
+                    .ok_or_else(|| anyhow!("Unknown price tier {}", booking.tier))?;
+                let price = adult_ticket_price * booking.adults + child_ticket_price * booking.children;
+                return if price - discount == pre_checkout.total_amount as u64 {
+                    Ok(())
+                } else {
+                    Err(anyhow!("Wrong tranaction amount"))
+                };
+            }
             match db::sign_up(
                 conn,
                 booking.event_id,
@@ -40,6 +59,8 @@ pub fn pre_checkout(
                 0,
                 get_unix_time(),
                 pre_checkout.total_amount as u64,
+                booking.tier,
+                &booking.promo_code,
             ) {
                 Ok(_) => Ok(()),
                 Err(e) => Err(anyhow!("{}", e)),
@@ -53,47 +74,81 @@ pub fn pre_checkout(
 /// Payment successful.
 pub fn checkout(
     conn: &PooledConnection<SqliteConnectionManager>,
+    user: &User,
     payment: &SuccessfulPayment,
-    _ctx: &Context,
-) -> anyhow::Result<()> {
-    if let Some(name) = &payment.order_info.name {
-        let booking: Booking = serde_json::from_str(&payment.invoice_payload)?;
-        if booking.event_id == 0 {
-            // Donation
-            // todo: save to donations table
-            Ok(())
-        } else {
-            match db::checkout(
-                conn,
-                &booking,
-                OrderInfo {
-                    id: payment.telegram_payment_charge_id.to_owned(),
-                    name: name.to_owned(),
-                    amount: payment.total_amount as u64,
-                },
-            ) {
-                Ok(_) => Ok(()),
-                Err(e) => Err(anyhow!("{}", e)),
-            }
+    ctx: &Context,
+) -> anyhow::Result<Reply> {
+    let booking: Booking = serde_json::from_str(&payment.invoice_payload)?;
+    let order_info = OrderInfo {
+        id: payment.telegram_payment_charge_id.to_owned(),
+        name: payment.order_info.name.to_owned().unwrap_or_default(),
+        amount: payment.total_amount as u64,
+        currency: currency_code(&payment.currency),
+        ..Default::default()
+    };
+    if booking.event_id == 0 {
+        // Donation
+        if order_info.currency != ctx.config.currency {
+            return Err(anyhow!("Only {} is accepted", ctx.config.currency));
         }
-    } else {
+        match db::add_donation(conn, user.id.0, &order_info, get_unix_time()) {
+            Ok(_) => Ok(ReplyMessage::new(t!(&user.lang, "donation_thanks")).into()),
+            Err(e) => Err(anyhow!("Failed to save donation: {}", e)),
+        }
+    } else if order_info.name.is_empty() {
         Err(anyhow!("Name not found"))
+    } else {
+        match db::checkout(conn, &booking, order_info) {
+            Ok(_) => Ok(ReplyMessage::new(t!(&user.lang, "payment_thanks"))
+                .document(message_handler::calendar_invite(
+                    conn,
+                    user,
+                    booking.event_id,
+                    booking.adults + booking.children,
+                ))
+                .document(ticket::ticket(conn, &ctx.config, user, booking.event_id))
+                .into()),
+            Err(e) => Err(anyhow!("{}", e)),
+        }
     }
 }
 
+/// Tickets selected on the paid event page.
+#[derive(Clone, Copy, Default)]
+pub struct TicketSelection {
+    pub adults: u64,
+    pub children: u64,
+    pub tier: u64,
+}
+
 pub fn show_paid_event(
     event_id: u64,
-    adults: u64,
-    children: u64,
+    selection: TicketSelection,
     offset: u64,
     conn: &PooledConnection<SqliteConnectionManager>,
     user: &User,
     ctx: &Context,
 ) -> anyhow::Result<Reply> {
+    let TicketSelection { adults, children, tier } = selection;
     match db::get_event(conn, event_id, user.id.0) {
-        Ok(s) => {
-            let free_adults = s.event.max_adults as i64 - s.adults.reserved as i64 - adults as i64;
-            let free_children = s.event.max_children as i64 - s.children.reserved as i64 - children as i64;
+        Ok(mut s) => {
+            let ts = get_unix_time();
+            let tiers = available_tiers(conn, &s.event, ts)?;
+            // The tier may have sold out since the button was sent.
+            let tier = if tiers.iter().any(|(t, _)| *t == tier) { tier } else { 0 };
+            // Seats offered from the waiting list are free for the user and preselected.
+            let offer = db::get_offer(conn, event_id, user.id.0)?;
+            let (held_adults, held_children) = offer.as_ref().map(|(b, _, _)| (b.adults, b.children)).unwrap_or_default();
+            s.adults.my_reservation -= held_adults;
+            s.children.my_reservation -= held_children;
+            let (adults, children, tier) = match &offer {
+                Some((b, _, _)) if adults + children == 0 => (b.adults, b.children, b.tier),
+                _ => (adults, children, tier),
+            };
+            let promo = db::get_applied_promo_code(conn, event_id, user.id.0, ts)?;
+            let free_adults = s.event.max_adults as i64 - s.adults.reserved as i64 + held_adults as i64 - adults as i64;
+            let free_children =
+                s.event.max_children as i64 - s.children.reserved as i64 + held_children as i64 - children as i64;
             let no_age_distinction = s.event.max_adults == 0 || s.event.max_children == 0;
             let is_admin = ctx.admins.contains(&user.id.0);
 
@@ -120,7 +175,14 @@ pub fn show_paid_event(
                     free_children,
                     is_admin,
                     no_age_distinction,
+                    &user.lang,
                 ))
+                // prices
+                .text(if tiers.len() > 1 {
+                    Some(format::price_tiers(&s.event, &tiers, tier, no_age_distinction, &user.lang))
+                } else {
+                    None
+                })
                 // participants
                 .text(participants.and_then(|participants| {
                     Some(format::participants(
@@ -128,10 +190,11 @@ pub fn show_paid_event(
                         &participants,
                         is_admin,
                         no_age_distinction,
+                        &user.lang,
                     ))
                 }))
                 // messages
-                .text(format::messages(conn, &s, event_id, is_admin))
+                .text(format::messages(conn, &s, event_id, is_admin, &user.lang))
                 // attachment
                 .text({
                     if is_admin
@@ -145,25 +208,34 @@ pub fn show_paid_event(
                             match db::get_attachment(conn, event_id, user.id.0) {
                                 Ok(v) => {
                                     if let Some(attachment) = v {
-                                        text.push_str(&format!("\nПримечание: {}.", attachment));
+                                        text.push_str(&t!(&user.lang, "attachment", attachment = attachment));
                                     }
                                 }
                                 Err(e) => error!("Failed to get attachment: {}", e),
                             }
                         }
                         if is_admin == false {
-                            text.push_str("\nКоличество мест можно менять кнопками \"Записаться/Отписаться\". Примечание к брони можно добавить, послав сообщение боту.\n");
+                            text.push_str(&t!(&user.lang, "seats_hint"));
                         }
                         if s.adults.my_reservation + s.children.my_reservation > 0 {
-                            text.push_str(&format!(
-                                "\n<b>У вас забронировано: {}</b>",
-                                s.adults.my_reservation + s.children.my_reservation
+                            text.push_str(&t!(
+                                &user.lang,
+                                "my_reservation",
+                                count = s.adults.my_reservation + s.children.my_reservation
                             ));
                         }
                         if s.adults.my_waiting + s.children.my_waiting > 0 {
-                            text.push_str(&format!(
-                                "\n<b>У вас в списке ожидания: {}</b>",
-                                s.adults.my_waiting + s.children.my_waiting
+                            text.push_str(&t!(
+                                &user.lang,
+                                "my_waiting",
+                                count = s.adults.my_waiting + s.children.my_waiting
+                            ));
+                        }
+                        if let Some((_, _, until)) = &offer {
+                            text.push_str(&t!(
+                                &user.lang,
+                                "offer_until",
+                                until = format::ts(*until, &s.event.tz, &user.lang)
                             ));
                         }
                         Some(text)
@@ -174,7 +246,11 @@ pub fn show_paid_event(
                 // footer
                 .text(
                     if s.adults.my_reservation + s.children.my_reservation > 0 {
-                        Some(format!("\n<b>Вы ранее купили: {}</b>", s.adults.my_reservation + s.children.my_reservation))
+                        Some(t!(
+                            &user.lang,
+                            "previously_bought",
+                            count = s.adults.my_reservation + s.children.my_reservation
+                        ))
                     } else {
                         None
                     }        
@@ -182,42 +258,68 @@ pub fn show_paid_event(
                 // order
                 .text(
                     if adults + children > 0 {
-                        let mut order = "Сбор за бронирование: ".to_string();
+                        let mut order = t!(&user.lang, "booking_fee");
                         if no_age_distinction {
                             order.push_str(&format!("{}", adults));
                         } else {
                             if adults > 0 {
-                                order.push_str(&format!("{} взросл.", adults));
+                                order.push_str(&t!(&user.lang, "adults_short", count = adults));
                             }
                             if children > 0 {
-                                order.push_str(&format!(", {} детск.", children));
+                                order.push_str(", ");
+                                order.push_str(&t!(&user.lang, "children_short", count = children));
                             }
                         }
+                        order.push_str(&tier_name(&s.event, tier));
         
-                        let total_amount = (adults * s.event.adult_ticket_price
-                            + children * s.event.child_ticket_price)
-                            as f32
-                            / 100f32;
-                        Some(format!(
-                            "\n<b>{}, всего {:.2} EUR</b>",
-                            order, total_amount
-                        ))
+                        let (adult_ticket_price, child_ticket_price) = s.event.prices(tier).unwrap_or_default();
+                        let total_amount = adults * adult_ticket_price
+                            + children * child_ticket_price;
+                        let mut text = String::new();
+                        let discount = match &promo {
+                            Some(p) => {
+                                let discount = p.discount(total_amount);
+                                text.push_str(&t!(
+                                    &user.lang,
+                                    "promo_code_discount",
+                                    code = p.code,
+                                    amount = format::amount(discount, &s.event.currency)
+                                ));
+                                discount
+                            }
+                            None => 0,
+                        };
+                        text.push_str(&t!(
+                            &user.lang,
+                            "order_total",
+                            order = order,
+                            amount = format::amount(total_amount - discount, &s.event.currency)
+                        ));
+                        Some(text)
                     } else {
-                        Some("\nВыберите необходимое количество билетов и нажмите \"К оплате\". Введённое имя будет на билете.".to_string())
+                        let mut text = t!(&user.lang, "select_tickets");
+                        match &promo {
+                            Some(p) => text.push_str(&t!(&user.lang, "promo_code_applied", code = p.code)),
+                            None => {
+                                if s.state == EventState::Open && db::have_promo_codes(conn, event_id, ts)? {
+                                    text.push_str(&t!(&user.lang, "promo_code_hint"));
+                                }
+                            }
+                        }
+                        Some(text)
                     }                    
                 )
                 // controls
                 .keyboard(get_controls(
                     &s,
-                    adults,
-                    children,
+                    TicketSelection { adults, children, tier },
+                    &tiers,
                     offset,
                     free_adults,
                     free_children,
                     no_age_distinction,
                     is_admin,
-                    user.id.0,
-                    conn,
+                    user,
                 )?)
                 // pagination
                 .pagination(
@@ -225,12 +327,14 @@ pub fn show_paid_event(
                         event_id,
                         adults,
                         children,
+                        tier,
                         offset: offset.saturating_sub(1),
                     },
                     &CallbackQuery::PaidEvent {
                         event_id,
                         adults,
                         children,
+                        tier,
                         offset: offset + 1,
                     },
                     participants_len,
@@ -244,86 +348,188 @@ pub fn show_paid_event(
     }
 }
 
+/// Price tiers that can be bought now, with the seats left at each if limited. The regular price
+/// comes first.
+fn available_tiers(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    event: &Event,
+    ts: u64,
+) -> anyhow::Result<Vec<(u64, Option<u64>)>> {
+    let mut tiers = vec![(0, None)];
+    for (i, t) in event.price_tiers.iter().enumerate() {
+        let tier = i as u64 + 1;
+        if db::is_tier_available(conn, event, tier, 1, ts)? {
+            let left = if t.quota > 0 {
+                Some(t.quota - db::get_tier_sales(conn, event.id, tier)?)
+            } else {
+                None
+            };
+            tiers.push((tier, left));
+        }
+    }
+    Ok(tiers)
+}
+
+/// Name of the tier to append to the order, empty for the regular price.
+fn tier_name(event: &Event, tier: u64) -> String {
+    match tier {
+        0 => String::new(),
+        _ => event
+            .price_tiers
+            .get(tier as usize - 1)
+            .map(|t| format!(" ({})", t.name))
+            .unwrap_or_default(),
+    }
+}
+
 fn get_controls(
     s: &EventStats,
-    adults: u64,
-    children: u64,
+    selection: TicketSelection,
+    tiers: &[(u64, Option<u64>)],
     offset: u64,
     free_adults: i64,
     free_children: i64,
     no_age_distinction: bool,
     is_admin: bool,
-    _user_id: u64,
-    _conn: &PooledConnection<SqliteConnectionManager>,
+    user: &User,
 ) -> anyhow::Result<Vec<Vec<InlineKeyboardButton>>> {
+    let TicketSelection { adults, children, tier } = selection;
+    let lang = &user.lang;
     let mut keyboard: Vec<Vec<InlineKeyboardButton>> = Vec::new();
     let mut row: Vec<InlineKeyboardButton> = Vec::new();
     let event_id = s.event.id;
+    let tier_left = match tiers.iter().find(|(t, _)| *t == tier) {
+        Some((_, Some(left))) => *left as i64 - (adults + children) as i64,
+        _ => i64::MAX,
+    };
+    // Age groups without free seats are waited for, and so is an order with any of them.
+    let adults_sold_out = free_adults + (adults as i64) <= 0;
+    let children_sold_out = free_children + (children as i64) <= 0;
+    let waiting = (adults > 0 && adults_sold_out) || (children > 0 && children_sold_out);
 
     if s.state == EventState::Open {
-        if s.adults.my_reservation + adults < s.event.max_adults_per_reservation {
-            if free_adults > 0 {
+        if tiers.len() > 1 {
+            for (t, _) in tiers {
+                let name = match *t {
+                    0 => t!(lang, "regular_price"),
+                    _ => s.event.price_tiers[*t as usize - 1].name.clone(),
+                };
+                row.push(InlineKeyboardButton::callback(
+                    if *t == tier { format!("✅ {}", name) } else { name },
+                    &serde_json::to_string(&CallbackQuery::PaidEvent {
+                        event_id,
+                        adults,
+                        children,
+                        tier: *t,
+                        offset,
+                    })?,
+                ));
+            }
+            keyboard.push(row);
+            row = Vec::new();
+        }
+        if adults_sold_out || waiting {
+            if s.adults.my_reservation + s.adults.my_waiting + adults < s.event.max_adults_per_reservation {
                 row.push(InlineKeyboardButton::callback(
                     if no_age_distinction {
-                        "Забронировать +1"
+                        t!(lang, "wait")
                     } else {
-                        "Забронировать взрослый +1"
+                        t!(lang, "wait_adult")
                     },
                     &serde_json::to_string(&CallbackQuery::PaidEvent {
                         event_id,
                         adults: adults + 1,
                         children,
+                        tier,
                         offset,
                     })?,
                 ));
             }
+        } else if s.adults.my_reservation + adults < s.event.max_adults_per_reservation
+            && free_adults > 0
+            && tier_left > 0
+        {
+            row.push(InlineKeyboardButton::callback(
+                if no_age_distinction {
+                    t!(lang, "book")
+                } else {
+                    t!(lang, "book_adult")
+                },
+                &serde_json::to_string(&CallbackQuery::PaidEvent {
+                    event_id,
+                    adults: adults + 1,
+                    children,
+                    tier,
+                    offset,
+                })?,
+            ));
         }
         if adults > 0 {
             row.push(InlineKeyboardButton::callback(
                 if no_age_distinction {
-                    "Отменить -1"
+                    t!(lang, "unbook")
                 } else {
-                    "Отменить взрослый -1"
+                    t!(lang, "unbook_adult")
                 },
                 &serde_json::to_string(&CallbackQuery::PaidEvent {
                     event_id,
                     adults: adults - 1,
                     children,
+                    tier,
                     offset,
                 })?,
             ));
         }
         keyboard.push(row);
         row = Vec::new();
-        if s.children.my_reservation + children < s.event.max_children_per_reservation
-        {
-            if free_children > 0 {
+        if children_sold_out || waiting {
+            if s.children.my_reservation + s.children.my_waiting + children < s.event.max_children_per_reservation {
                 row.push(InlineKeyboardButton::callback(
                     if no_age_distinction {
-                        "Забронировать +1"
+                        t!(lang, "wait")
                     } else {
-                        "Забронировать детский +1"
+                        t!(lang, "wait_child")
                     },
                     &serde_json::to_string(&CallbackQuery::PaidEvent {
                         event_id,
                         adults,
                         children: children + 1,
+                        tier,
                         offset,
                     })?,
                 ));
             }
+        } else if s.children.my_reservation + children < s.event.max_children_per_reservation
+            && free_children > 0
+            && tier_left > 0
+        {
+            row.push(InlineKeyboardButton::callback(
+                if no_age_distinction {
+                    t!(lang, "book")
+                } else {
+                    t!(lang, "book_child")
+                },
+                &serde_json::to_string(&CallbackQuery::PaidEvent {
+                    event_id,
+                    adults,
+                    children: children + 1,
+                    tier,
+                    offset,
+                })?,
+            ));
         }
         if children > 0 {
             row.push(InlineKeyboardButton::callback(
                 if no_age_distinction {
-                    "Отменить -1"
+                    t!(lang, "unbook")
                 } else {
-                    "Отменить детский -1"
+                    t!(lang, "unbook_child")
                 },
                 &serde_json::to_string(&CallbackQuery::PaidEvent {
                     event_id,
                     adults,
                     children: children - 1,
+                    tier,
                     offset,
                 })?,
             ));
@@ -332,31 +538,48 @@ fn get_controls(
     }
     row = Vec::new();
     row.push(InlineKeyboardButton::callback(
-        "Список мероприятий",
+        t!(lang, "event_list"),
         serde_json::to_string(&CallbackQuery::EventList { offset: 0 })?,
     ));
 
     if is_admin {
         if s.state == EventState::Open {
             row.push(InlineKeyboardButton::callback(
-                "Остановить запись",
+                t!(lang, "close_event"),
                 serde_json::to_string(&CallbackQuery::ChangeEventState { event_id, state: 1 })?,
             ));
         } else {
             row.push(InlineKeyboardButton::callback(
-                "Разрешить запись",
+                t!(lang, "open_event"),
                 serde_json::to_string(&CallbackQuery::ChangeEventState { event_id, state: 0 })?,
             ));
         }
     }
 
-    if adults + children > 0 {
+    if s.adults.my_waiting + s.children.my_waiting > 0 {
+        row.push(InlineKeyboardButton::callback(
+            t!(lang, "leave_waiting_list"),
+            serde_json::to_string(&CallbackQuery::LeaveWaitingList { event_id })?,
+        ));
+    }
+    if adults + children > 0 && waiting {
+        row.push(InlineKeyboardButton::callback(
+            t!(lang, "join_waiting_list"),
+            serde_json::to_string(&CallbackQuery::WaitForTickets {
+                event_id,
+                adults,
+                children,
+                tier,
+            })?,
+        ));
+    } else if adults + children > 0 {
         row.push(InlineKeyboardButton::callback(
-            "К оплате",
+            t!(lang, "to_payment"),
             serde_json::to_string(&CallbackQuery::SendInvoice {
                 event_id,
                 adults,
                 children,
+                tier,
             })?,
         ));
     }
@@ -369,43 +592,90 @@ pub fn prepare_invoice(
     event_id: u64,
     adults: u64,
     children: u64,
+    tier: u64,
     conn: &PooledConnection<SqliteConnectionManager>,
     user: &User,
-    _ctx: &Context,
+    ctx: &Context,
 ) -> anyhow::Result<Reply> {
+    let ts = get_unix_time();
+    // Seats offered from the waiting list are already held until their deadline.
+    let offer = db::get_offer(conn, event_id, user.id.0)?
+        .filter(|(b, _, _)| b.adults == adults && b.children == children && b.tier == tier);
+    if offer.is_none() {
+        // A new invoice replaces the hold of the previous one.
+        db::release_hold(conn, event_id, user.id.0)?;
+    }
     match db::get_event(conn, event_id, user.id.0) {
         Ok(s) => {
             let no_age_distinction = s.event.max_adults == 0 || s.event.max_children == 0;
+            let (held_adults, held_children) = offer.as_ref().map(|(b, _, _)| (b.adults, b.children)).unwrap_or_default();
             if s.state != EventState::Open {
                 Err(anyhow!("Event has been closed"))
-            } else if s.adults.my_reservation + adults > s.event.max_adults_per_reservation
-                || s.children.my_reservation + children > s.event.max_children_per_reservation
+            } else if s.adults.my_reservation - held_adults + adults > s.event.max_adults_per_reservation
+                || s.children.my_reservation - held_children + children > s.event.max_children_per_reservation
             {
                 Err(anyhow!("Limits error"))
+            } else if offer.is_none() && !db::is_tier_available(conn, &s.event, tier, adults + children, ts)? {
+                Err(anyhow!(t!(&user.lang, "price_tier_unavailable")))
             } else {
-                let mut title = "Билеты: ".to_string();
+                let (adult_ticket_price, child_ticket_price) = s
+                    .event
+                    .prices(tier)
+                    .ok_or_else(|| anyhow!("Unknown price tier {}", tier))?;
+                let price = adults * adult_ticket_price + children * child_ticket_price;
+                let (promo_code, discount) = match &offer {
+                    Some((b, discount, _)) => (b.promo_code.clone(), *discount),
+                    None => match db::get_applied_promo_code(conn, event_id, user.id.0, ts)? {
+                        Some(p) => (p.code.clone(), p.discount(price)),
+                        None => (String::new(), 0),
+                    },
+                };
+                // Telegram doesn't take invoices without an amount.
+                if discount > 0 && discount >= price {
+                    return Err(anyhow!(t!(&user.lang, "promo_code_invalid")));
+                }
+                let hold_until = match offer {
+                    Some((_, _, until)) => until,
+                    None => {
+                        let (res, _) =
+                            db::sign_up(conn, event_id, user, adults, children, 0, ts, price - discount, tier, &promo_code)?;
+                        if res == 0 {
+                            return Err(anyhow!("Limits error"));
+                        }
+                        ts + ctx.config.payment_hold_minutes * 60
+                    }
+                };
+                let mut title = t!(&user.lang, "tickets");
                 if no_age_distinction {
                     title.push_str(&format!("{}", adults + children));
                 } else {
                     if adults > 0 {
-                        title.push_str(&format!("{} взросл. ", adults));
+                        title.push_str(&t!(&user.lang, "adults_short", count = adults));
                     }
                     if children > 0 {
-                        title.push_str(&format!(", {} детск.", children));
+                        title.push_str(", ");
+                        title.push_str(&t!(&user.lang, "children_short", count = children));
                     }
                 }
+                title.push_str(&tier_name(&s.event, tier));
 
                 Ok(Reply::Invoice {
                     title,
-                    description: format!("{} - {}", s.event.name, format::ts(s.event.ts)),
-                    currency: "EUR".to_string(),
-                    amount: adults * s.event.adult_ticket_price
-                        + children * s.event.child_ticket_price,
+                    description: format!(
+                        "{} - {}\n{}",
+                        s.event.name,
+                        format::ts(s.event.ts, &s.event.tz, &user.lang),
+                        t!(&user.lang, "hold_until", until = format::ts(hold_until, &s.event.tz, &user.lang))
+                    ),
+                    currency: s.event.currency.clone(),
+                    amount: price - discount,
                     payload: serde_json::to_string(&Booking {
                         event_id,
                         adults,
                         children,
                         user_id: user.id.0,
+                        tier,
+                        promo_code,
                     })?,
                 })
             }
@@ -414,21 +684,97 @@ pub fn prepare_invoice(
     }
 }
 
+/// Puts the order on the waiting list of the sold out event.
+pub fn join_waiting_list(
+    event_id: u64,
+    adults: u64,
+    children: u64,
+    tier: u64,
+    conn: &PooledConnection<SqliteConnectionManager>,
+    user: &User,
+    ctx: &Context,
+) -> anyhow::Result<Reply> {
+    match db::sign_up(conn, event_id, user, adults, children, 1, get_unix_time(), 0, tier, "") {
+        Ok((0, _)) => Err(anyhow!("Limits error")),
+        Ok(_) => show_paid_event(event_id, TicketSelection { tier, ..Default::default() }, 0, conn, user, ctx),
+        Err(e) => Err(anyhow!("{}", e)),
+    }
+}
+
+pub fn leave_waiting_list(
+    event_id: u64,
+    conn: &PooledConnection<SqliteConnectionManager>,
+    user: &User,
+    ctx: &Context,
+) -> anyhow::Result<Reply> {
+    match db::leave_waiting_list(conn, event_id, user.id.0) {
+        Ok(_) => show_paid_event(event_id, TicketSelection::default(), 0, conn, user, ctx),
+        Err(e) => Err(anyhow!("Failed to leave the waiting list: {}.", e)),
+    }
+}
+
+/// Applies the promo code to the paid event the user is looking at.
+pub fn apply_promo_code(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    user: &User,
+    code: &str,
+    ctx: &Context,
+) -> anyhow::Result<Reply> {
+    let code = code.trim().to_uppercase();
+    let event_id = db::get_current_event(conn, user.id.0)?;
+    let is_paid = event_id != 0 && db::get_event(conn, event_id, user.id.0)?.event.get_type() == EventType::Paid;
+    if !is_paid {
+        return Ok(ReplyMessage::new(t!(&user.lang, "promo_code_no_event")).into());
+    }
+    match db::check_promo_code(conn, &code, event_id, get_unix_time())? {
+        Some(_) => {
+            db::apply_promo_code(conn, event_id, user.id.0, &code)?;
+            show_paid_event(event_id, TicketSelection::default(), 0, conn, user, ctx)
+        }
+        None => Ok(ReplyMessage::new(t!(&user.lang, "promo_code_invalid")).into()),
+    }
+}
+
 pub fn donate(
     user: &User,
     amount: u64,
-    _ctx: &Context,
+    ctx: &Context,
 ) -> anyhow::Result<Reply> {
         Ok(Reply::Invoice {
-            title: "Донат".to_string(),
-            description: "Поддержать работу канала \"Венские Истории\"".to_string(),
-            currency: "EUR".to_string(),
+            title: t!(&user.lang, "donation"),
+            description: t!(&user.lang, "donation_description"),
+            currency: ctx.config.currency.clone(),
             amount,
             payload: serde_json::to_string(&Booking {
                 event_id: 0,
                 adults: 0,
                 children: 0,
                 user_id: user.id.0,
+                tier: 0,
+                promo_code: String::new(),
             })?,
         })
-}
\ No newline at end of file
+}
+/// Digits after the decimal point in amounts of the currency. Telegram expects amounts in minor
+/// units as defined by ISO 4217. None for unknown codes and codes that are not currencies.
+pub fn currency_exponent(currency: &str) -> Option<u32> {
+    if serde_json::from_value::<Currency>(serde_json::Value::String(currency.to_string())).is_err() {
+        return None;
+    }
+    match currency {
+        "XAG" | "XAU" | "XBA" | "XBB" | "XBC" | "XBD" | "XDR" | "XPD" | "XPT" | "XSU" | "XTS"
+        | "XUA" | "XXX" => None,
+        "BIF" | "CLP" | "DJF" | "GNF" | "ISK" | "JPY" | "KMF" | "KRW" | "PYG" | "RWF" | "UGX"
+        | "UYI" | "VND" | "VUV" | "XAF" | "XOF" | "XPF" => Some(0),
+        "BHD" | "IQD" | "JOD" | "KWD" | "LYD" | "OMR" | "TND" => Some(3),
+        "CLF" | "UYW" => Some(4),
+        _ => Some(2),
+    }
+}
+
+fn currency_code(currency: &Currency) -> String {
+    match serde_json::to_value(currency) {
+        Ok(serde_json::Value::String(code)) => code,
+        _ => format!("{:?}", currency),
+    }
+}
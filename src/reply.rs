@@ -1,6 +1,6 @@
 use teloxide::{
     prelude::*,
-    types::{InlineKeyboardButton, InlineKeyboardMarkup, ParseMode},
+    types::{InlineKeyboardButton, InlineKeyboardMarkup, InputFile, ParseMode},
     RequestError,
 };
 
@@ -14,6 +14,7 @@ pub enum Reply {
         currency: String,
         amount: u64,
     },
+    Document(ReplyDocument),
 }
 #[derive(Debug)]
 pub struct ReplyMessage {
@@ -21,6 +22,8 @@ pub struct ReplyMessage {
     pub parse_mode: ParseMode,
     pub disable_preview: bool,
     pub keyboard: Option<Vec<Vec<InlineKeyboardButton>>>,
+    /// Sent after the message, e.g. an .ics invite or a ticket.
+    pub documents: Vec<ReplyDocument>,
 }
 impl ReplyMessage {
     pub fn new<T>(message: T) -> Self
@@ -32,6 +35,7 @@ impl ReplyMessage {
             parse_mode: ParseMode::Html,
             disable_preview: true,
             keyboard: None,
+            documents: Vec::new(),
         }
     }
 
@@ -97,6 +101,11 @@ impl ReplyMessage {
         self
     }
 
+    pub fn document(mut self, document: Option<ReplyDocument>) -> Self {
+        self.documents.extend(document);
+        self
+    }
+
     pub async fn send(self, msg: &Message, bot: &AutoSend<Bot>) -> Result<(), RequestError> {
         let fut = if let Some(keyboard) = self.keyboard {
             bot.send_message(msg.chat.id, self.message)
@@ -112,6 +121,9 @@ impl ReplyMessage {
             error!("Failed to send message to Telegram: {}", e);
             Err(e)
         })?;
+        for document in self.documents {
+            document.send(msg, bot).await?;
+        }
         Ok(())
     }
 
@@ -130,6 +142,9 @@ impl ReplyMessage {
             error!("Failed to send message to Telegram: {}", e);
             Err(e)
         })?;
+        for document in self.documents {
+            document.send(msg, bot).await?;
+        }
         Ok(())
     }
 }
@@ -139,3 +154,71 @@ impl Into<Reply> for ReplyMessage {
         Reply::Message(self)
     }
 }
+
+/// File sent as a document, e.g. a CSV export, or as a photo.
+#[derive(Debug)]
+pub struct ReplyDocument {
+    pub file_name: String,
+    pub content: Vec<u8>,
+    pub caption: Option<String>,
+    pub is_photo: bool,
+}
+impl ReplyDocument {
+    pub fn new<T>(file_name: T, content: Vec<u8>) -> Self
+    where
+        T: Into<String>,
+    {
+        Self {
+            file_name: file_name.into(),
+            content,
+            caption: None,
+            is_photo: false,
+        }
+    }
+
+    pub fn caption<T>(mut self, caption: T) -> Self
+    where
+        T: Into<String>,
+    {
+        self.caption = Some(caption.into());
+        self
+    }
+
+    /// Shows the image in the chat instead of attaching it.
+    pub fn photo(mut self) -> Self {
+        self.is_photo = true;
+        self
+    }
+
+    pub async fn send(self, msg: &Message, bot: &AutoSend<Bot>) -> Result<(), RequestError> {
+        let document = InputFile::memory(self.content).file_name(self.file_name);
+        if self.is_photo {
+            let fut = if let Some(caption) = self.caption {
+                bot.send_photo(msg.chat.id, document).caption(caption)
+            } else {
+                bot.send_photo(msg.chat.id, document)
+            };
+            fut.await.map_err(|e| {
+                error!("Failed to send photo to Telegram: {}", e);
+                e
+            })?;
+            return Ok(());
+        }
+        let fut = if let Some(caption) = self.caption {
+            bot.send_document(msg.chat.id, document).caption(caption)
+        } else {
+            bot.send_document(msg.chat.id, document)
+        };
+        fut.await.map_err(|e| {
+            error!("Failed to send document to Telegram: {}", e);
+            e
+        })?;
+        Ok(())
+    }
+}
+
+impl From<ReplyDocument> for Reply {
+    fn from(document: ReplyDocument) -> Reply {
+        Reply::Document(document)
+    }
+}
@@ -0,0 +1,220 @@
+//! QR code tickets of reservations, checked in at the door.
+//!
+//! A ticket token is `<event>-<user>-<signature>`, signed with HMAC-SHA256 and a secret created on
+//! first use. The QR code holds the direct link `https://t.me/<bot_name>?start=checkin_<token>`, so
+//! an admin checks the ticket in by scanning it with the phone camera. In check-in mode the token
+//! can also be sent as text, e.g. by a scanner app, or the ticket forwarded to the bot.
+use crate::db;
+use crate::format;
+use crate::reply::*;
+use crate::types::{Configuration, Context, User};
+use crate::util;
+use hmac::{Hmac, Mac};
+use qrcode::{Color, QrCode};
+use r2d2::PooledConnection;
+use r2d2_sqlite::SqliteConnectionManager;
+use sha2::Sha256;
+use teloxide::utils::html;
+
+/// Prefix of the token in direct links.
+pub const CHECKIN_PREFIX: &str = "checkin_";
+/// Bytes of the signature kept in the token.
+const SIGNATURE_LEN: usize = 8;
+/// Pixels per QR code module.
+const SCALE: usize = 8;
+/// Quiet zone around the QR code in modules.
+const BORDER: usize = 4;
+
+fn mac(secret: &str, event_id: u64, user: u64) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(format!("{}-{}", event_id, user).as_bytes());
+    mac
+}
+
+fn secret(conn: &PooledConnection<SqliteConnectionManager>) -> Result<String, db::Error> {
+    db::get_secret(conn, "tickets", &util::random_token())
+}
+
+pub fn token(secret: &str, event_id: u64, user: u64) -> String {
+    let signature = mac(secret, event_id, user).finalize().into_bytes();
+    let hex: String = signature[..SIGNATURE_LEN].iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}-{}-{}", event_id, user, hex)
+}
+
+/// Event and user of a genuine ticket token.
+pub fn verify(secret: &str, token: &str) -> Option<(u64, u64)> {
+    let mut parts = token.splitn(3, '-');
+    let event_id = parts.next()?.parse().ok()?;
+    let user = parts.next()?.parse().ok()?;
+    let hex = parts.next()?;
+    if hex.len() != SIGNATURE_LEN * 2 {
+        return None;
+    }
+    let signature = (0..SIGNATURE_LEN)
+        .map(|i| u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    mac(secret, event_id, user).verify_truncated_left(&signature).ok()?;
+    Some((event_id, user))
+}
+
+/// Whether the word looks like a token, without checking the signature.
+fn is_token(word: &str) -> bool {
+    let parts: Vec<&str> = word.split('-').collect();
+    parts.len() == 3
+        && parts[..2].iter().all(|p| !p.is_empty() && p.bytes().all(|b| b.is_ascii_digit()))
+        && parts[2].len() == SIGNATURE_LEN * 2
+        && parts[2].bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Token in a direct link, on its own or in the caption of a forwarded ticket.
+fn find_token(text: &str) -> Option<&str> {
+    match text.find(CHECKIN_PREFIX) {
+        Some(i) => text[i + CHECKIN_PREFIX.len()..].split_whitespace().next(),
+        None => text.split_whitespace().find(|word| is_token(word)),
+    }
+}
+
+/// Whether the text carries a ticket, so that check-in mode leaves other texts alone.
+pub fn is_ticket(text: &str) -> bool {
+    find_token(text).is_some()
+}
+
+/// PNG image of the QR code with the text.
+pub fn qr_code(text: &str) -> anyhow::Result<Vec<u8>> {
+    let code = QrCode::new(text.as_bytes())?;
+    let width = code.width();
+    let size = (width + 2 * BORDER) * SCALE;
+    let mut pixels = vec![255u8; size * size];
+    for (i, color) in code.to_colors().iter().enumerate() {
+        if *color == Color::Dark {
+            let (x, y) = (i % width + BORDER, i / width + BORDER);
+            for row in y * SCALE..(y + 1) * SCALE {
+                pixels[row * size + x * SCALE..row * size + (x + 1) * SCALE].fill(0);
+            }
+        }
+    }
+    let mut png = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut png, size as u32, size as u32);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.write_header()?.write_image_data(&pixels)?;
+    }
+    Ok(png)
+}
+
+/// Ticket of the user's reservation, if tickets are enabled and the user has seats.
+pub fn ticket(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    config: &Configuration,
+    user: &User,
+    event_id: u64,
+) -> Option<ReplyDocument> {
+    if !config.tickets {
+        return None;
+    }
+    match create_ticket(conn, config, user, event_id) {
+        Ok(ticket) => ticket,
+        Err(e) => {
+            error!("Failed to create ticket for event {}, user {}: {}", event_id, user.id, e);
+            None
+        }
+    }
+}
+
+fn create_ticket(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    config: &Configuration,
+    user: &User,
+    event_id: u64,
+) -> anyhow::Result<Option<ReplyDocument>> {
+    let s = db::get_event(conn, event_id, user.id.0)?;
+    let seats = s.adults.my_reservation + s.children.my_reservation;
+    if seats == 0 {
+        return Ok(None);
+    }
+    let token = token(&secret(conn)?, event_id, user.id.0);
+    let link = format!("https://t.me/{}?start={}{}", config.bot_name, CHECKIN_PREFIX, token);
+    Ok(Some(
+        ReplyDocument::new("ticket.png", qr_code(&link)?)
+            .caption(t!(
+                &user.lang,
+                "ticket",
+                name = s.event.name,
+                start = format::ts(s.event.ts, &s.event.tz, &user.lang),
+                seats = seats,
+                token = token
+            ))
+            .photo(),
+    ))
+}
+
+/// Ticket of the event the user looked at last, for /ticket.
+pub fn show_ticket(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    user: &User,
+    ctx: &Context,
+) -> anyhow::Result<Reply> {
+    let event_id = db::get_current_event(conn, user.id.0)?;
+    match ticket(conn, &ctx.config, user, event_id) {
+        Some(ticket) => Ok(ticket.into()),
+        None => Ok(ReplyMessage::new(t!(&user.lang, "no_ticket")).into()),
+    }
+}
+
+/// Checks in the ticket in the text, a token or a direct link, and tells the admin the result.
+pub fn check_in(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    user: &User,
+    text: &str,
+) -> anyhow::Result<Reply> {
+    let token = find_token(text).unwrap_or_default();
+    let lang = &user.lang;
+    let reply = match verify(&secret(conn)?, token) {
+        Some((event_id, user_id)) => match db::get_event_name(conn, event_id) {
+            Ok((event, _, _)) => match db::check_in(conn, event_id, user_id)? {
+                Some((name, seats, false)) => t!(
+                    lang,
+                    "ticket_checked_in",
+                    name = html::escape(&name),
+                    seats = seats,
+                    event = html::escape(&event)
+                ),
+                Some((name, seats, true)) => t!(
+                    lang,
+                    "ticket_duplicate",
+                    name = html::escape(&name),
+                    seats = seats,
+                    event = html::escape(&event)
+                ),
+                None => t!(lang, "ticket_cancelled", event = html::escape(&event)),
+            },
+            Err(db::Error::NotFound(_)) => t!(lang, "ticket_invalid"),
+            Err(e) => return Err(e.into()),
+        },
+        None => t!(lang, "ticket_invalid"),
+    };
+    Ok(ReplyMessage::new(reply).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token() {
+        let token = token("secret", 12, 3456);
+        assert_eq!(verify("secret", &token), Some((12, 3456)));
+        assert_eq!(verify("other secret", &token), None);
+        assert_eq!(verify("secret", &token.replacen("12-", "13-", 1)), None);
+        assert_eq!(verify("secret", &token[..token.len() - 1]), None);
+        assert_eq!(verify("secret", "12-3456"), None);
+        assert!(qr_code(&token).unwrap().starts_with(b"\x89PNG"));
+
+        let caption = format!("Ticket: test event, Sun 29.05 15:00 CEST\nSeats: 2\n{}", token);
+        assert_eq!(find_token(&caption), Some(token.as_str()));
+        assert_eq!(find_token(&format!("https://t.me/bot?start={}{}", CHECKIN_PREFIX, token)), Some(token.as_str()));
+        assert!(!is_ticket(r#"{"name":"test event", "start":"2022-05-29 15:00"}"#));
+        assert!(!is_ticket("12-3456-not a token"));
+    }
+}
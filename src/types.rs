@@ -1,9 +1,7 @@
-use chrono::DateTime;
+use chrono::{DateTime, Datelike, Months, NaiveDate, NaiveTime, TimeZone, Timelike};
 use r2d2_sqlite::SqliteConnectionManager;
 use serde_compact::compact;
 use std::collections::HashSet;
-use std::sync::Arc;
-use tokio::sync::Mutex;
 
 use teloxide::{types::{UserId}};
 
@@ -31,26 +29,195 @@ pub struct Configuration {
     pub mailing_hours: String,
     pub mailing_hours_from: Option<u64>,
     pub mailing_hours_to: Option<u64>,
+    #[serde(default = "default_series_horizon_days")]
+    pub series_horizon_days: u64,
+    #[serde(default = "default_database_path")]
+    pub database_path: String,
+    #[serde(default = "default_database_busy_timeout_ms")]
+    pub database_busy_timeout_ms: u64,
+    #[serde(default = "default_time_zone")]
+    pub time_zone: String,
+    #[serde(default = "default_currency")]
+    pub currency: String,
+    #[serde(skip)]
+    pub mailing_hours_offset: Option<i64>,
+    /// "polling" or "webhook".
+    #[serde(default = "default_mode")]
+    pub mode: String,
+    #[serde(default = "default_webhook_address")]
+    pub webhook_address: String,
+    #[serde(default)]
+    pub webhook_url: String,
+    #[serde(default)]
+    pub webhook_secret_path: String,
+    #[serde(default)]
+    pub webhook_certificate: String,
+    #[serde(default)]
+    pub webhook_private_key: String,
+    /// Admin API listen address, the API is disabled if empty.
+    #[serde(default)]
+    pub api_address: String,
+    #[serde(default)]
+    pub api_tokens: String,
+    /// Calendar feed listen address, the feed is disabled if empty.
+    #[serde(default)]
+    pub calendar_address: String,
+    /// Public url of the calendar feed server.
+    #[serde(default)]
+    pub calendar_url: String,
+    /// Reminders of new events before the start, e.g. "1d, 2h". Events can override them.
+    #[serde(default)]
+    pub reminders: String,
+    /// Hours before the start of free events when seats of participants who didn't confirm the
+    /// reminder are released. 0 keeps them.
+    #[serde(default)]
+    pub release_unconfirmed_hours: u64,
+    /// Default of new events, see `Event::auto_promote_hours`.
+    #[serde(default)]
+    pub auto_promote_hours: u64,
+    /// Default of new paid events, so that freed seats are offered with an invoice.
+    #[serde(default = "default_paid_auto_promote_hours")]
+    pub paid_auto_promote_hours: u64,
+    /// Minutes paid seats are held for the user after the invoice is sent.
+    #[serde(default = "default_payment_hold_minutes")]
+    pub payment_hold_minutes: u64,
+    /// Username of the bot for direct links.
+    #[serde(default = "default_bot_name")]
+    pub bot_name: String,
+    /// Send QR code tickets of reservations to be checked in at the door.
+    #[serde(default)]
+    pub tickets: bool,
+}
+
+fn default_database_path() -> String {
+    "./events.db3".to_string()
+}
+
+fn default_database_busy_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_series_horizon_days() -> u64 {
+    28
+}
+
+fn default_currency() -> String {
+    "EUR".to_string()
+}
+
+fn default_mode() -> String {
+    "polling".to_string()
+}
+
+fn default_webhook_address() -> String {
+    "127.0.0.1:8443".to_string()
+}
+
+fn default_bot_name() -> String {
+    "sign_up_for_event_bot".to_string()
+}
+
+fn default_payment_hold_minutes() -> u64 {
+    5
+}
+
+fn default_paid_auto_promote_hours() -> u64 {
+    24
+}
+
+fn default_time_zone() -> String {
+    crate::util::local_time_zone().name().to_string()
 }
 
 impl Configuration {
     pub fn parse(&mut self) -> Result<(), String> {
+        if crate::util::parse_time_zone(&self.time_zone).is_none() {
+            return Err(format!("Unknown time zone {}.", self.time_zone));
+        }
+        if crate::payments::currency_exponent(&self.currency).is_none() {
+            return Err(format!("Unsupported currency {}.", self.currency));
+        }
+        match self.mode.as_str() {
+            "polling" => {}
+            "webhook" => {
+                if url::Url::parse(&self.webhook_url).is_err() {
+                    return Err(format!("Wrong webhook url {}.", self.webhook_url));
+                }
+                if self.webhook_address.parse::<std::net::SocketAddr>().is_err() {
+                    return Err(format!("Wrong webhook address {}.", self.webhook_address));
+                }
+                if self.webhook_certificate.is_empty() != self.webhook_private_key.is_empty() {
+                    return Err("Webhook certificate and private key must be set together.".to_string());
+                }
+            }
+            _ => return Err(format!("Unknown mode {}.", self.mode)),
+        }
+        if !self.api_address.is_empty() {
+            if self.api_address.parse::<std::net::SocketAddr>().is_err() {
+                return Err(format!("Wrong api address {}.", self.api_address));
+            }
+            if self.api_tokens.split(',').all(|t| t.trim().is_empty()) {
+                return Err("Api tokens are not set.".to_string());
+            }
+        }
+        if !self.calendar_address.is_empty() {
+            if self.calendar_address.parse::<std::net::SocketAddr>().is_err() {
+                return Err(format!("Wrong calendar address {}.", self.calendar_address));
+            }
+            if url::Url::parse(&self.calendar_url).is_err() {
+                return Err(format!("Wrong calendar url {}.", self.calendar_url));
+            }
+        }
+        if crate::util::parse_reminders(&self.reminders).is_none() {
+            return Err(format!("Wrong reminders {}.", self.reminders));
+        }
+        if self.payment_hold_minutes == 0 {
+            return Err("Payment hold must be at least a minute.".to_string());
+        }
         let parts: Vec<&str> = self.mailing_hours.split('.').collect();
         if parts.len() != 3 {
             return Err("Wrong mailing hours format.".to_string());
         }
+        // Hours with an explicit offset, e.g. "08:00 +02:00..21:00 +02:00", are kept for older
+        // configurations. Otherwise hours are wall clock time in time_zone.
         match (
             DateTime::parse_from_str(&format!("2022-07-06 {}", parts[0]), "%Y-%m-%d %H:%M  %z"),
             DateTime::parse_from_str(&format!("2022-07-06 {}", parts[2]), "%Y-%m-%d %H:%M  %z"),
         ) {
             (Ok(from), Ok(to)) => {
-                self.mailing_hours_from = Some((from.timestamp() % 86400) as u64);
-                self.mailing_hours_to = Some((to.timestamp() % 86400) as u64);
+                self.mailing_hours_from = Some(from.time().num_seconds_from_midnight() as u64);
+                self.mailing_hours_to = Some(to.time().num_seconds_from_midnight() as u64);
+                self.mailing_hours_offset = Some(from.offset().local_minus_utc() as i64);
+                return Ok(());
+            }
+            _ => {}
+        }
+        match (
+            NaiveTime::parse_from_str(parts[0].trim(), "%H:%M"),
+            NaiveTime::parse_from_str(parts[2].trim(), "%H:%M"),
+        ) {
+            (Ok(from), Ok(to)) => {
+                self.mailing_hours_from = Some(from.num_seconds_from_midnight() as u64);
+                self.mailing_hours_to = Some(to.num_seconds_from_midnight() as u64);
+                self.mailing_hours_offset = None;
                 Ok(())
             }
             _ => Err("Failed to farse mailing hours.".to_string()),
         }
     }
+
+    /// Whether bulk messages may be sent at the given time.
+    pub fn is_mailing_time(&self, ts: u64) -> bool {
+        let seconds_from_midnight = match self.mailing_hours_offset {
+            Some(offset) => (ts as i64 + offset).rem_euclid(86400) as u64,
+            None => match crate::util::time_zone(&self.time_zone).timestamp_opt(ts as i64, 0) {
+                chrono::LocalResult::Single(t) => t.num_seconds_from_midnight() as u64,
+                _ => return false,
+            },
+        };
+        seconds_from_midnight >= self.mailing_hours_from.unwrap_or(0)
+            && seconds_from_midnight < self.mailing_hours_to.unwrap_or(86400)
+    }
 }
 
 #[derive(PartialEq)]
@@ -73,9 +240,62 @@ pub struct Event {
     pub remind: u64,
     pub adult_ticket_price: u64,
     pub child_ticket_price: u64,
+    /// IANA time zone. Empty for events created before time zones were stored, those are shown in
+    /// the server time zone.
+    pub tz: String,
+    /// ISO 4217 code of the ticket prices, which are in minor units of the currency.
+    pub currency: String,
+    /// Participants get an .ics file on sign-up and when the event is moved or deleted.
+    pub calendar_invite: bool,
+    /// Reminders in seconds before the start, in addition to `remind`.
+    pub reminders: Vec<u64>,
+    /// Free seats go to the waiting list in order, to be accepted (or paid for) within these hours.
+    /// 0 prompts the whole waiting list instead.
+    pub auto_promote_hours: u64,
+    /// Named prices besides the regular one, e.g. early-bird or concession. Reservations refer to
+    /// them by position starting from 1, 0 is the regular price.
+    pub price_tiers: Vec<PriceTier>,
+}
+
+/// Ticket prices that can be chosen instead of the regular ones.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct PriceTier {
+    pub name: String,
+    pub adult_ticket_price: u64,
+    pub child_ticket_price: u64,
+    /// Sold until this time, 0 until the start.
+    #[serde(default)]
+    pub until: u64,
+    /// Seats sold at this price, 0 for no limit.
+    #[serde(default)]
+    pub quota: u64,
 }
 
 impl Event {
+    /// Reminder times in ascending order. `remind` is 0 if only offsets are set.
+    pub fn reminder_times(&self) -> Vec<u64> {
+        let mut times: Vec<u64> = self
+            .reminders
+            .iter()
+            .map(|offset| self.ts.saturating_sub(*offset))
+            .chain(Some(self.remind).filter(|remind| *remind != 0))
+            .collect();
+        times.sort_unstable();
+        times.dedup();
+        times
+    }
+
+    /// Adult and child ticket prices of the tier, None if there is no such tier.
+    pub fn prices(&self, tier: u64) -> Option<(u64, u64)> {
+        match tier {
+            0 => Some((self.adult_ticket_price, self.child_ticket_price)),
+            _ => self
+                .price_tiers
+                .get(tier as usize - 1)
+                .map(|t| (t.adult_ticket_price, t.child_ticket_price)),
+        }
+    }
+
     pub fn get_type(&self) -> EventType {
         // todo: move to constructor
         if self.adult_ticket_price != 0 || self.child_ticket_price != 0 {
@@ -100,6 +320,7 @@ pub struct User {
     pub user_name1: String,
     pub user_name2: String,
     pub is_admin: bool,
+    pub lang: String,
 }
 
 impl User {
@@ -119,10 +340,12 @@ impl User {
             user_name1,
             user_name2: user_name2.clone(),
             is_admin: admins.contains(&u.id.0),
+            lang: crate::locale::from_language_code(u.language_code.as_deref()),
         }
     }
 }
 
+#[derive(Serialize)]
 pub struct Participant {
     pub user_id: u64,
     pub user_name1: String,
@@ -130,6 +353,9 @@ pub struct Participant {
     pub adults: u64,
     pub children: u64,
     pub attachment: Option<String>,
+    /// Asked by the reminder whether they are still coming.
+    pub reminded: bool,
+    pub confirmed: bool,
 }
 
 pub struct Presence {
@@ -142,13 +368,21 @@ pub struct Presence {
 
 pub struct MessageBatch {
     pub message_id: u64,
+    /// 0 if the event has been deleted since.
     pub event_id: u64,
     pub sender: String,
     pub message_type: MessageType,
     pub waiting_list: u64,
     pub text: String,
+    /// .ics file sent with the text as caption, if not empty.
+    pub document: String,
     pub is_paid: bool,
-    pub recipients: Vec<u64>,
+    pub recipients: Vec<Recipient>,
+}
+
+pub struct Recipient {
+    pub user_id: u64,
+    pub lang: String,
 }
 
 #[derive(FromPrimitive, ToPrimitive, PartialEq)]
@@ -156,13 +390,18 @@ pub enum MessageType {
     Direct = 0,
     Reminder = 1,
     WaitingListPrompt = 2,
+    /// New time, name or link, or cancellation of the event.
+    EventChange = 3,
+    /// Unconfirmed or unpaid seats have been released.
+    Release = 4,
+    /// Seats moved from the waiting list, to be accepted.
+    Promotion = 5,
 }
 
 //#[derive(Clone)]
 pub struct Context {
     pub config: Configuration,
     pub pool: DbPool,
-    pub sign_up_mutex: Arc<Mutex<u64>>,
     pub admins: HashSet<u64>,
 }
 
@@ -173,13 +412,48 @@ pub struct Booking {
     pub adults: u64,
     pub children: u64,
     pub user_id: u64,
+    #[serde(default)]
+    pub tier: u64,
+    #[serde(default)]
+    pub promo_code: String,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Default)]
 pub struct OrderInfo {
     pub id: String,
     pub name: String,
     pub amount: u64,
+    #[serde(default)]
+    pub currency: String,
+    #[serde(default)]
+    pub promo_code: String,
+    /// Amount off the ticket prices.
+    #[serde(default)]
+    pub discount: u64,
+}
+
+/// Discount on paid events.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PromoCode {
+    pub code: String,
+    /// Percent off the order.
+    pub percent: u64,
+    /// Amount off the order in minor units of the event currency.
+    pub amount: u64,
+    /// Orders that can use the code, 0 for no limit.
+    pub max_uses: u64,
+    pub valid_from: u64,
+    /// 0 for no end.
+    pub valid_until: u64,
+    /// Event the code is for, 0 for all paid events.
+    pub event_id: u64,
+}
+
+impl PromoCode {
+    /// Amount off the price, at most the price.
+    pub fn discount(&self, price: u64) -> u64 {
+        std::cmp::min(price * self.percent / 100 + self.amount, price)
+    }
 }
 
 pub enum ReservationState {
@@ -187,3 +461,120 @@ pub enum ReservationState {
     PaymentPending = 1,
     PaymentCompleted = 2,
 }
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum PaymentStatus {
+    Pending,
+    Completed,
+    RefundRequested,
+    Refunded,
+}
+
+impl PaymentStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PaymentStatus::Pending => "pending",
+            PaymentStatus::Completed => "completed",
+            PaymentStatus::RefundRequested => "refund requested",
+            PaymentStatus::Refunded => "refunded",
+        }
+    }
+}
+
+/// Paid reservation or refund of an event, for reconciliation with the payment provider.
+pub struct PaymentRecord {
+    pub user_id: u64,
+    pub user_name1: String,
+    pub user_name2: String,
+    pub adults: u64,
+    pub children: u64,
+    pub status: PaymentStatus,
+    /// None while the payment is pending.
+    pub order: Option<OrderInfo>,
+}
+
+pub enum RefundState {
+    Requested = 0,
+    Completed = 1,
+}
+
+/// Paid reservation cancelled by an admin. The seats are released, the payment has to be
+/// returned through the payment provider.
+pub struct Refund {
+    pub id: u64,
+    pub event_id: u64,
+    pub event_name: String,
+    pub user_id: u64,
+    pub user_name1: String,
+    pub user_name2: String,
+    pub adults: u64,
+    pub children: u64,
+    pub order: OrderInfo,
+    pub reason: String,
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum RepeatRule {
+    Weekly = 0,
+    Biweekly = 1,
+    Monthly = 2,
+}
+
+/// Template for a series of recurring events.
+#[derive(Clone)]
+pub struct EventSeries {
+    pub id: u64,
+    pub event: Event,
+    pub rule: RepeatRule,
+    pub until: u64,
+    pub count: u64,
+    pub skip: Vec<NaiveDate>,
+}
+
+impl EventSeries {
+    /// Start times of the occurrences within (from, to]. Occurrences keep the wall clock time of
+    /// the first one in the event time zone across DST changes.
+    pub fn occurrences(&self, from: u64, to: u64) -> Vec<u64> {
+        let tz = crate::util::time_zone(&self.event.tz);
+        let start = match tz.timestamp_opt(self.event.ts as i64, 0).single() {
+            Some(v) => v.naive_local(),
+            None => return Vec::new(),
+        };
+        let mut res = Vec::new();
+        let mut generated = 0;
+        for i in 0u32.. {
+            let next = match self.rule {
+                RepeatRule::Weekly => start.checked_add_signed(chrono::Duration::weeks(i as i64)),
+                RepeatRule::Biweekly => {
+                    start.checked_add_signed(chrono::Duration::weeks(2 * i as i64))
+                }
+                RepeatRule::Monthly => start.checked_add_months(Months::new(i)),
+            };
+            let next = match next {
+                Some(v) => v,
+                None => break,
+            };
+            let ts = match tz.from_local_datetime(&next).earliest() {
+                Some(v) => v.timestamp() as u64,
+                None => continue, // skipped by a DST change
+            };
+            if ts > to || (self.until != 0 && ts > self.until) {
+                break;
+            }
+            if self.rule == RepeatRule::Monthly && next.day() != start.day() {
+                continue; // no such day in this month
+            }
+            if self.skip.contains(&next.date()) {
+                continue;
+            }
+            generated += 1;
+            if ts > from {
+                res.push(ts);
+            }
+            if self.count != 0 && generated >= self.count {
+                break;
+            }
+        }
+        res
+    }
+}
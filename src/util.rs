@@ -1,3 +1,5 @@
+use chrono::{NaiveDateTime, TimeZone};
+use chrono_tz::Tz;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 pub fn get_unix_time() -> u64 {
@@ -5,12 +7,80 @@ pub fn get_unix_time() -> u64 {
     t.duration_since(UNIX_EPOCH).unwrap().as_secs() as u64
 }
 
+/// Random hex string for links that must not be guessed.
+pub fn random_token() -> String {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes).expect("Failed to get random bytes");
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
 pub fn get_seconds_before_midnight(ts: u64) -> u64 {
     86400 - ts % 86400
 }
 
+/// Time zone of the server, used for events created before time zones were stored.
+pub fn local_time_zone() -> Tz {
+    iana_time_zone::get_timezone()
+        .ok()
+        .and_then(|tz| tz.parse().ok())
+        .unwrap_or(Tz::UTC)
+}
+
+/// Parses an IANA time zone name. An empty name means the server time zone.
+pub fn parse_time_zone(tz: &str) -> Option<Tz> {
+    if tz.is_empty() {
+        Some(local_time_zone())
+    } else {
+        tz.parse().ok()
+    }
+}
+
+pub fn time_zone(tz: &str) -> Tz {
+    parse_time_zone(tz).unwrap_or_else(local_time_zone)
+}
+
+/// Converts wall clock time in the given zone to a timestamp. Times skipped by a DST change
+/// don't exist, ambiguous ones resolve to the earlier instant.
+pub fn to_unix_time(local: &NaiveDateTime, tz: &str) -> Option<u64> {
+    time_zone(tz)
+        .from_local_datetime(local)
+        .earliest()
+        .map(|t| t.timestamp() as u64)
+}
+
+/// Parses reminder offsets before the start, e.g. "7d, 1d, 2h, 30m", to seconds.
+pub fn parse_reminders(reminders: &str) -> Option<Vec<u64>> {
+    reminders
+        .split(',')
+        .map(str::trim)
+        .filter(|r| !r.is_empty())
+        .map(|r| {
+            let unit = match r.chars().last()? {
+                'd' => 24 * 60 * 60,
+                'h' => 60 * 60,
+                'm' => 60,
+                _ => return None,
+            };
+            r[..r.len() - 1].trim().parse::<u64>().ok().map(|n| n * unit)
+        })
+        .collect()
+}
 
 #[test]
 fn test_util() {
     assert_eq!(get_seconds_before_midnight(1651503600), 9 * 60 * 60);
+
+    let local = |s| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap();
+    // CET and CEST
+    assert_eq!(to_unix_time(&local("2022-03-26 15:00"), "Europe/Vienna"), Some(1648303200));
+    assert_eq!(to_unix_time(&local("2022-03-27 15:00"), "Europe/Vienna"), Some(1648386000));
+    // skipped by the DST change
+    assert_eq!(to_unix_time(&local("2022-03-27 02:30"), "Europe/Vienna"), None);
+    assert!(parse_time_zone("Europe/Nowhere").is_none());
+
+    assert_eq!(parse_reminders("7d, 1d,2h ,30m"), Some(vec![604800, 86400, 7200, 1800]));
+    assert_eq!(parse_reminders(""), Some(vec![]));
+    assert_eq!(parse_reminders("2 h"), Some(vec![7200]));
+    assert_eq!(parse_reminders("2"), None);
+    assert_eq!(parse_reminders("xh"), None);
 }
@@ -0,0 +1,207 @@
+//! Webhook update listener, an alternative to long polling.
+//!
+//! Telegram posts updates to `webhook_url` extended with `webhook_secret_path`, so that only
+//! Telegram knows the endpoint. The server listens on `webhook_address`, usually behind a reverse
+//! proxy terminating TLS. With `webhook_certificate` and `webhook_private_key` it serves HTTPS
+//! itself and uploads the (self-signed) certificate to Telegram.
+use crate::types::Configuration;
+use anyhow::{anyhow, Context as _};
+use std::convert::Infallible;
+use std::fs::File;
+use std::future::Future;
+use std::io::BufReader;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::Arc;
+use teloxide::{
+    dispatching::{
+        stop_token::StopToken,
+        update_listeners::{webhooks, UpdateListener},
+    },
+    prelude::*,
+    types::InputFile,
+};
+use tokio_rustls::{rustls, TlsAcceptor};
+use url::Url;
+
+/// Registers the webhook and starts the server. The webhook is deleted when the listener stops.
+pub async fn listener(
+    bot: AutoSend<Bot>,
+    config: &Configuration,
+) -> anyhow::Result<impl UpdateListener<Infallible>> {
+    let options = options(config)?;
+    let tls = tls_acceptor(config)?;
+    let listener = TcpListener::bind(options.address)
+        .with_context(|| format!("Failed to listen on {}", options.address))?;
+    info!("Listening for webhook updates on {}", options.address);
+
+    let (mut updates, stop, router) = webhooks::axum_to_router(bot, options).await?;
+    let stop_token = updates.stop_token();
+    tokio::spawn(async move {
+        if let Err(e) = serve(listener, router, tls, stop).await {
+            error!("Webhook server failed: {}", e);
+            stop_token.stop();
+        }
+    });
+    Ok(updates)
+}
+
+fn options(config: &Configuration) -> anyhow::Result<webhooks::Options> {
+    let address: SocketAddr = config.webhook_address.parse()?;
+    let mut url = Url::parse(&config.webhook_url)?;
+    if !config.webhook_secret_path.is_empty() {
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("Wrong webhook url {}", config.webhook_url))?
+            .pop_if_empty()
+            .push(&config.webhook_secret_path);
+    }
+    let options = webhooks::Options::new(address, url);
+    if config.webhook_certificate.is_empty() {
+        Ok(options)
+    } else {
+        Ok(options.certificate(InputFile::file(&config.webhook_certificate)))
+    }
+}
+
+fn tls_acceptor(config: &Configuration) -> anyhow::Result<Option<TlsAcceptor>> {
+    if config.webhook_certificate.is_empty() {
+        return Ok(None);
+    }
+    let certs = rustls_pemfile::certs(&mut BufReader::new(
+        File::open(&config.webhook_certificate)
+            .with_context(|| format!("Failed to open {}", config.webhook_certificate))?,
+    ))?
+    .into_iter()
+    .map(rustls::Certificate)
+    .collect();
+
+    let mut reader = BufReader::new(
+        File::open(&config.webhook_private_key)
+            .with_context(|| format!("Failed to open {}", config.webhook_private_key))?,
+    );
+    let key = loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => break rustls::PrivateKey(key),
+            Some(_) => continue,
+            None => return Err(anyhow!("No private key in {}", config.webhook_private_key)),
+        }
+    };
+
+    let tls_config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(Some(TlsAcceptor::from(Arc::new(tls_config))))
+}
+
+/// Serves the webhook router until `stop` resolves.
+async fn serve(
+    listener: TcpListener,
+    router: axum::Router,
+    tls: Option<TlsAcceptor>,
+    stop: impl Future<Output = ()>,
+) -> anyhow::Result<()> {
+    listener.set_nonblocking(true)?;
+    match tls {
+        None => {
+            axum::Server::from_tcp(listener)?
+                .serve(router.into_make_service())
+                .with_graceful_shutdown(stop)
+                .await?
+        }
+        Some(acceptor) => {
+            let listener = tokio::net::TcpListener::from_std(listener)?;
+            let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+            tokio::spawn(async move {
+                // Handshakes run in their own tasks, so a slow client doesn't block the others.
+                while !tx.is_closed() {
+                    let (stream, address) = match listener.accept().await {
+                        Ok(res) => res,
+                        Err(e) => {
+                            warn!("Failed to accept connection: {}", e);
+                            continue;
+                        }
+                    };
+                    let acceptor = acceptor.clone();
+                    let tx = tx.clone();
+                    tokio::spawn(async move {
+                        match acceptor.accept(stream).await {
+                            Ok(stream) => {
+                                let _ = tx.send(Ok::<_, std::io::Error>(stream)).await;
+                            }
+                            Err(e) => debug!("TLS handshake with {} failed: {}", address, e),
+                        }
+                    });
+                }
+            });
+            axum::Server::builder(hyper::server::accept::poll_fn(move |cx| rx.poll_recv(cx)))
+                .serve(router.into_make_service())
+                .with_graceful_shutdown(stop)
+                .await?
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use teloxide::{dispatching::update_listeners::AsUpdateStream, types::UpdateKind};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const UPDATE: &str = r#"{"update_id":10000,"message":{"message_id":1,"date":1650445814,
+        "chat":{"id":1111,"type":"private","first_name":"Test"},
+        "from":{"id":1111,"is_bot":false,"first_name":"Test","language_code":"en"},
+        "text":"/start"}}"#;
+
+    /// Returns the status line of the response.
+    async fn post(address: SocketAddr, path: &str, body: &str) -> String {
+        let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        stream
+            .write_all(
+                format!(
+                    "POST {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+                    Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    path,
+                    body.len(),
+                    body
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).await.unwrap();
+        res.lines().next().unwrap_or_default().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_webhook() {
+        let config = Configuration {
+            webhook_address: "127.0.0.1:0".to_string(),
+            webhook_url: "https://example.com/telegram/".to_string(),
+            webhook_secret_path: "secret".to_string(),
+            ..Default::default()
+        };
+        let options = options(&config).unwrap();
+        assert_eq!(options.url.as_str(), "https://example.com/telegram/secret");
+        assert!(options.certificate.is_none());
+
+        let (mut updates, stop, router) = webhooks::axum_no_setup(options);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, router, None, stop));
+
+        assert_eq!(post(address, "/telegram", UPDATE).await, "HTTP/1.1 404 Not Found");
+        assert_eq!(post(address, "/telegram/secret", UPDATE).await, "HTTP/1.1 200 OK");
+
+        let update = Box::pin(updates.as_stream()).next().await.unwrap().unwrap();
+        assert_eq!(update.id, 10000);
+        match update.kind {
+            UpdateKind::Message(msg) => assert_eq!(msg.text(), Some("/start")),
+            kind => panic!("Unexpected update {:?}", kind),
+        }
+    }
+}